@@ -42,13 +42,16 @@ async fn main() -> anyhow::Result<()> {
     let max_peers = opts.sentry_opts.max_peers;
     std::fs::create_dir_all(&opts.data_dir.0)?;
 
-    let network_params = if let Some(path) = opts.chain_spec_file {
-        ChainSpec::load_from_file(path)?.p2p
+    let chain_spec = if let Some(path) = opts.chain_spec_file {
+        ChainSpec::load_from_file(path)?
     } else {
-        ChainSpec::load_builtin(&opts.chain)?.p2p
+        ChainSpec::load_builtin(&opts.chain)?
     };
 
-    let swarm = akula::sentry::run(opts.sentry_opts, opts.data_dir, network_params).await?;
+    let mut sentry_opts = opts.sentry_opts;
+    sentry_opts.no_new_block |= !akula::sentry::eth::accepts_new_block(&chain_spec);
+
+    let swarm = akula::sentry::run(sentry_opts, opts.data_dir, chain_spec.p2p).await?;
 
     loop {
         info!(
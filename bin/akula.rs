@@ -380,8 +380,11 @@ fn main() -> anyhow::Result<()> {
                 } else {
                     let max_peers = opt.sentry_opts.max_peers;
                     let sentry_api_addr = opt.sentry_opts.sentry_addr;
+                    let mut sentry_opts = opt.sentry_opts;
+                    sentry_opts.no_new_block |=
+                        !akula::sentry::eth::accepts_new_block(&chain_config.chain_spec);
                     let swarm = akula::sentry::run(
-                        opt.sentry_opts,
+                        sentry_opts,
                         opt.datadir,
                         chain_config.chain_spec.p2p.clone(),
                     )
@@ -1,12 +1,78 @@
+//! Reads of accounts, storage and code, current and historical, and walks over their changesets.
+//!
+//! The walks are plain iterators borrowing an [`MdbxTransaction`], and are not `Send`: MDBX pins
+//! transactions, and the cursors opened from them, to the thread that began them. A
+//! `Send` walk would thus need a different transaction model, opening a transaction per step or
+//! handing it off to a dedicated thread, at a cost on every lookup. Async callers on a
+//! multithreaded runtime should instead begin the transaction in a blocking task, e.g.
+//! [`tokio::task::spawn_blocking`], and walk it there.
+
 use crate::{
     kv::{mdbx::*, tables, traits::*},
     models::*,
 };
+use bytes::Bytes;
+use tracing::{debug_span, Span};
+
+/// Changeset entry recorded for `subkey` under `key`, with the subkey prefix stripped.
+fn seek_change<K, T>(
+    cursor: &mut MdbxCursor<'_, K, T>,
+    key: T::Key,
+    subkey: T::SeekBothKey,
+) -> anyhow::Result<Option<Bytes>>
+where
+    K: TransactionKind,
+    T: DupSort,
+    T::SeekBothKey: Clone,
+{
+    let prefix = subkey.clone().encode();
+    let prefix: &[u8] = prefix.as_ref();
+
+    Ok(cursor
+        .seek_both_range_raw(key, subkey)?
+        .filter(|v| v.starts_with(prefix))
+        .map(|v| v.slice(prefix.len()..)))
+}
+
+/// Advances the walk inside the span on every step, so that the cursor work is attributed to it.
+fn in_span<I: Iterator>(span: Span, mut iter: I) -> impl Iterator<Item = I::Item> {
+    std::iter::from_fn(move || span.in_scope(|| iter.next()))
+}
+
+/// Walks changeset entries from `start` on, splitting each into the `N` byte subkey it is
+/// recorded under and the undecoded remainder.
+///
+/// Serves 20 byte account and 32 byte storage location subkeys alike.
+///
+/// The walk owns the cursor, so dropping it partway just closes the cursor: the transaction is
+/// left as it was for any further reads.
+pub fn walk_change_entries<'tx, K, T, const N: usize>(
+    cursor: MdbxCursor<'tx, K, T>,
+    start: T::SeekKey,
+) -> impl Iterator<Item = anyhow::Result<(T::Key, [u8; N], Bytes)>> + 'tx
+where
+    K: TransactionKind,
+    T: DupSort + 'tx,
+    T::Key: TableDecode + 'tx,
+    T::SeekKey: 'tx,
+{
+    cursor.walk_raw(Some(start)).map(|entry| {
+        let (k, v) = entry?;
+        if v.len() < N {
+            return Err(tables::TooShort::<N> { got: v.len() }.into());
+        }
+
+        let mut subkey = [0; N];
+        subkey.copy_from_slice(&v[..N]);
+        Ok((k, subkey, v.slice(N..)))
+    })
+}
 
 pub mod account {
     use super::*;
     use crate::kv::tables::BitmapKey;
     use anyhow::format_err;
+    use std::{cmp::Ordering, collections::BTreeSet};
 
     pub fn read<K: TransactionKind, E: EnvironmentKind>(
         tx: &MdbxTransaction<'_, K, E>,
@@ -42,22 +108,271 @@ pub mod account {
         }
     }
 
+    /// Raw account encoding recorded for the address in the block's changeset, empty if the
+    /// account did not exist before the block.
+    pub fn seek_account_change<K: TransactionKind>(
+        cursor: &mut MdbxCursor<'_, K, tables::AccountChangeSet>,
+        block_number: BlockNumber,
+        address: Address,
+    ) -> anyhow::Result<Option<Bytes>> {
+        super::seek_change(cursor, block_number, address)
+    }
+
     impl<'tx, K: TransactionKind> MdbxCursor<'tx, K, tables::AccountChangeSet> {
         pub fn find_account(
             &mut self,
             block_number: BlockNumber,
             address_to_find: Address,
         ) -> anyhow::Result<Option<Option<Account>>> {
-            if let Some(tables::AccountChange { address, account }) =
-                self.seek_both_range(block_number, address_to_find)?
-            {
-                if address == address_to_find {
-                    return Ok(Some(account));
+            seek_account_change(self, block_number, address_to_find)?
+                .map(|v| {
+                    if v.is_empty() {
+                        Ok(None)
+                    } else {
+                        Account::decode(&v).map(Some)
+                    }
+                })
+                .transpose()
+        }
+
+        /// All recorded changes of the account in blocks `from..=to`, sorted by block.
+        pub fn find_account_range(
+            &mut self,
+            from: BlockNumber,
+            to: BlockNumber,
+            address_to_find: Address,
+        ) -> anyhow::Result<Vec<(BlockNumber, Option<Account>)>> {
+            let mut changes = vec![];
+
+            let mut entry = self.seek(from)?;
+            while let Some((block_number, _)) = entry {
+                if block_number > to {
+                    break;
+                }
+
+                if let Some(account) = self.find_account(block_number, address_to_find)? {
+                    changes.push((block_number, account));
+                }
+
+                entry = match block_number.0.checked_add(1) {
+                    Some(next) => self.seek(BlockNumber(next))?,
+                    None => None,
+                };
+            }
+
+            Ok(changes)
+        }
+
+        /// Same as [`seek_account_change`] for each of the addresses, in the same order, but
+        /// walks the block's changeset once instead of seeking every address.
+        pub fn find_many(
+            &mut self,
+            block_number: BlockNumber,
+            keys: &[Address],
+        ) -> anyhow::Result<Vec<Option<Bytes>>> {
+            let mut out = vec![None; keys.len()];
+
+            let mut order = (0..keys.len()).collect::<Vec<_>>();
+            order.sort_unstable_by_key(|&i| keys[i]);
+            let mut order = order.into_iter().peekable();
+
+            let mut entry = match order.peek() {
+                Some(&i) => self.seek_both_range(block_number, keys[i])?,
+                None => None,
+            };
+            while let (Some(change), Some(&i)) = (&entry, order.peek()) {
+                match change.address.cmp(&keys[i]) {
+                    Ordering::Less => {
+                        entry = self.next_dup()?.map(|(_, change)| change);
+                    }
+                    Ordering::Equal => {
+                        out[i] = Some(
+                            change
+                                .account
+                                .map(|account| Bytes::copy_from_slice(account.encode().as_ref()))
+                                .unwrap_or_default(),
+                        );
+                        order.next();
+                    }
+                    Ordering::Greater => {
+                        order.next();
+                    }
+                }
+            }
+
+            Ok(out)
+        }
+    }
+
+    /// Walks account changes recorded in blocks `from..=to`, lowest block first.
+    pub fn walk_changes<'db, 'tx, K: TransactionKind, E: EnvironmentKind>(
+        tx: &'tx MdbxTransaction<'db, K, E>,
+        from: BlockNumber,
+        to: BlockNumber,
+    ) -> impl Iterator<Item = anyhow::Result<(BlockNumber, tables::AccountChange)>> + 'tx
+    where
+        'db: 'tx,
+    {
+        let span = debug_span!("walk_account_changes", %from, %to, yielded = 0_u64);
+        let walk = TryGenIter::from(move || {
+            let mut walker = tx.cursor(tables::AccountChangeSet)?.walk(Some(from));
+
+            let mut yielded = 0_u64;
+            while let Some((block_number, change)) = walker.next().transpose()? {
+                if block_number > to {
+                    break;
                 }
+
+                yielded += 1;
+                Span::current().record("yielded", yielded);
+                yield (block_number, change);
             }
 
-            Ok(None)
+            Ok(())
+        });
+
+        in_span(span, walk)
+    }
+
+    /// Account changes recorded in a single block, in address order.
+    ///
+    /// Same as [`walk_changes`] with `from == to`, but only visits the block's duplicates.
+    pub fn changes_at<'db, 'tx, K: TransactionKind, E: EnvironmentKind>(
+        tx: &'tx MdbxTransaction<'db, K, E>,
+        block_number: BlockNumber,
+    ) -> impl Iterator<Item = anyhow::Result<tables::AccountChange>> + 'tx
+    where
+        'db: 'tx,
+    {
+        let span = debug_span!("account_changes_at", %block_number, yielded = 0_u64);
+        let walk = TryGenIter::from(move || {
+            let mut cursor = tx.cursor(tables::AccountChangeSet)?;
+
+            let mut yielded = 0_u64;
+            let mut entry = cursor.seek_exact(block_number)?;
+            while let Some((_, change)) = entry {
+                yielded += 1;
+                Span::current().record("yielded", yielded);
+                yield change;
+
+                entry = cursor.next_dup()?;
+            }
+
+            Ok(())
+        });
+
+        in_span(span, walk)
+    }
+
+    /// Same as [`walk_changes`], but only yields changes of the given addresses.
+    pub fn walk_changes_filtered<'db, 'tx, K: TransactionKind, E: EnvironmentKind>(
+        tx: &'tx MdbxTransaction<'db, K, E>,
+        from: BlockNumber,
+        to: BlockNumber,
+        addresses: &'tx BTreeSet<Address>,
+    ) -> impl Iterator<Item = anyhow::Result<(BlockNumber, tables::AccountChange)>> + 'tx
+    where
+        'db: 'tx,
+    {
+        walk_changes(tx, from, to).filter(move |res| {
+            res.as_ref()
+                .map_or(true, |(_, change)| addresses.contains(&change.address))
+        })
+    }
+
+    /// Walks account changes recorded in blocks `from..=to` as owned block number, address and
+    /// storage-encoded account, left empty if the account did not exist.
+    pub fn walk_owned<'db, 'tx, K: TransactionKind, E: EnvironmentKind>(
+        tx: &'tx MdbxTransaction<'db, K, E>,
+        from: BlockNumber,
+        to: BlockNumber,
+    ) -> impl Iterator<Item = anyhow::Result<(u64, Address, Bytes)>> + 'tx
+    where
+        'db: 'tx,
+    {
+        let span = debug_span!("walk_owned_account_changes", %from, %to, yielded = 0_u64);
+        let walk = TryGenIter::from(move || {
+            let mut yielded = 0_u64;
+            for entry in super::walk_change_entries::<_, _, ADDRESS_LENGTH>(
+                tx.cursor(tables::AccountChangeSet)?,
+                from,
+            ) {
+                let (block_number, address, account) = entry?;
+                if block_number > to {
+                    break;
+                }
+
+                yielded += 1;
+                Span::current().record("yielded", yielded);
+                yield (block_number.0, Address::from(address), account);
+            }
+
+            Ok(())
+        });
+
+        in_span(span, walk)
+    }
+
+    /// Distinct addresses changed in blocks `from..=to`.
+    ///
+    /// Only the address prefix of each change is read, the accounts are not decoded.
+    pub fn changed_addresses<K: TransactionKind, E: EnvironmentKind>(
+        tx: &MdbxTransaction<'_, K, E>,
+        from: BlockNumber,
+        to: BlockNumber,
+    ) -> anyhow::Result<BTreeSet<Address>> {
+        let mut addresses = BTreeSet::new();
+        for entry in super::walk_change_entries::<_, _, ADDRESS_LENGTH>(
+            tx.cursor(tables::AccountChangeSet)?,
+            from,
+        ) {
+            let (block_number, address, _) = entry?;
+            if block_number > to {
+                break;
+            }
+
+            addresses.insert(Address::from(address));
         }
+
+        Ok(addresses)
+    }
+
+    /// Walks account changes recorded in blocks `from..=to`, highest block first.
+    pub fn walk_changes_back<'db, 'tx, K: TransactionKind, E: EnvironmentKind>(
+        tx: &'tx MdbxTransaction<'db, K, E>,
+        from: BlockNumber,
+        to: BlockNumber,
+    ) -> impl Iterator<Item = anyhow::Result<(BlockNumber, tables::AccountChange)>> + 'tx
+    where
+        'db: 'tx,
+    {
+        let span = debug_span!("walk_account_changes_back", %from, %to, yielded = 0_u64);
+        let walk = TryGenIter::from(move || {
+            let mut cursor = tx.cursor(tables::AccountChangeSet)?;
+
+            // Position on the last change at or below `to`
+            let mut entry = match to.0.checked_add(1) {
+                Some(next) if cursor.seek(BlockNumber(next))?.is_some() => cursor.prev()?,
+                _ => cursor.last()?,
+            };
+
+            let mut yielded = 0_u64;
+            while let Some((block_number, change)) = entry {
+                if block_number < from {
+                    break;
+                }
+
+                yielded += 1;
+                Span::current().record("yielded", yielded);
+                yield (block_number, change);
+
+                entry = cursor.prev()?;
+            }
+
+            Ok(())
+        });
+
+        in_span(span, walk)
     }
 
     pub fn walk<'db, 'tx, K: TransactionKind, E: EnvironmentKind>(
@@ -177,18 +492,9 @@ pub mod storage {
         changeset_block: Option<BlockNumber>,
     ) -> anyhow::Result<U256> {
         if let Some(block_number) = changeset_block {
-            let tables::StorageChange { location, value } = tx
-                .cursor(tables::StorageChangeSet)?
-                .seek_both_range(
-                    tables::StorageChangeKey {
-                        block_number,
-                        address,
-                    },
-                    location_to_find,
-                )?
-                .ok_or_else(|| format_err!("changeset does not contain storage entry"))?;
-            anyhow::ensure!(location == location_to_find);
-            Ok(value)
+            tx.cursor(tables::StorageChangeSet)?
+                .find_storage(block_number, (address, location_to_find))?
+                .ok_or_else(|| format_err!("changeset does not contain storage entry"))
         } else {
             Ok(tx
                 .cursor(tables::Storage)?
@@ -199,6 +505,120 @@ pub mod storage {
         }
     }
 
+    /// Raw value recorded for the storage slot in the block's changeset.
+    pub fn seek_storage_change<K: TransactionKind>(
+        cursor: &mut MdbxCursor<'_, K, tables::StorageChangeSet>,
+        block_number: BlockNumber,
+        (address, location): (Address, H256),
+    ) -> anyhow::Result<Option<Bytes>> {
+        super::seek_change(
+            cursor,
+            tables::StorageChangeKey {
+                block_number,
+                address,
+            },
+            location,
+        )
+    }
+
+    impl<'tx, K: TransactionKind> MdbxCursor<'tx, K, tables::StorageChangeSet> {
+        pub fn find_storage(
+            &mut self,
+            block_number: BlockNumber,
+            key: (Address, H256),
+        ) -> anyhow::Result<Option<U256>> {
+            seek_storage_change(self, block_number, key)?
+                .map(|v| U256::decode(&v))
+                .transpose()
+        }
+    }
+
+    /// Walks storage changes recorded in blocks `from..=to`, lowest block first.
+    pub fn walk_changes<'db, 'tx, K: TransactionKind, E: EnvironmentKind>(
+        tx: &'tx MdbxTransaction<'db, K, E>,
+        from: BlockNumber,
+        to: BlockNumber,
+    ) -> impl Iterator<Item = anyhow::Result<(BlockNumber, (Address, H256), U256)>> + 'tx
+    where
+        'db: 'tx,
+    {
+        let span = debug_span!("walk_storage_changes", %from, %to, yielded = 0_u64);
+        let walk = TryGenIter::from(move || {
+            let mut walker = tx.cursor(tables::StorageChangeSet)?.walk(Some(from));
+
+            let mut yielded = 0_u64;
+            while let Some((
+                tables::StorageChangeKey {
+                    block_number,
+                    address,
+                },
+                tables::StorageChange { location, value },
+            )) = walker.next().transpose()?
+            {
+                if block_number > to {
+                    break;
+                }
+
+                yielded += 1;
+                Span::current().record("yielded", yielded);
+                yield (block_number, (address, location), value);
+            }
+
+            Ok(())
+        });
+
+        in_span(span, walk)
+    }
+
+    /// Walks storage changes of a single account recorded in blocks `from..=to`, lowest block
+    /// first, then by location.
+    pub fn walk_account_storage<'db, 'tx, K: TransactionKind, E: EnvironmentKind>(
+        tx: &'tx MdbxTransaction<'db, K, E>,
+        address: Address,
+        from: BlockNumber,
+        to: BlockNumber,
+    ) -> impl Iterator<Item = anyhow::Result<(BlockNumber, H256, U256)>> + 'tx
+    where
+        'db: 'tx,
+    {
+        let span =
+            debug_span!("walk_account_storage_changes", ?address, %from, %to, yielded = 0_u64);
+        let walk = TryGenIter::from(move || {
+            let mut cursor = tx.cursor(tables::StorageChangeSet)?;
+
+            let mut yielded = 0_u64;
+            let mut entry = cursor.seek(from)?;
+            while let Some((tables::StorageChangeKey { block_number, .. }, _)) = entry {
+                if block_number > to {
+                    break;
+                }
+
+                let mut change = cursor
+                    .seek_exact(tables::StorageChangeKey {
+                        block_number,
+                        address,
+                    })?
+                    .map(|(_, change)| change);
+                while let Some(tables::StorageChange { location, value }) = change {
+                    yielded += 1;
+                    Span::current().record("yielded", yielded);
+                    yield (block_number, location, value);
+
+                    change = cursor.next_dup()?.map(|(_, change)| change);
+                }
+
+                entry = match block_number.0.checked_add(1) {
+                    Some(next) => cursor.seek(BlockNumber(next))?,
+                    None => None,
+                };
+            }
+
+            Ok(())
+        });
+
+        in_span(span, walk)
+    }
+
     pub fn walk<'db, 'tx, K: TransactionKind, E: EnvironmentKind>(
         tx: &'tx MdbxTransaction<'db, K, E>,
         searched_address: Address,
@@ -353,6 +773,7 @@ pub mod tests {
         },
     };
     use hex_literal::hex;
+    use std::collections::BTreeSet;
 
     #[test]
     fn read_storage() {
@@ -395,6 +816,607 @@ pub mod tests {
         );
     }
 
+    #[test]
+    fn walk_account_changes() {
+        let db = new_mem_chaindata().unwrap();
+        let txn = db.begin_mutable().unwrap();
+
+        let address1 = hex!("a000000000000000000000000000000000000001").into();
+        let address2 = hex!("b000000000000000000000000000000000000002").into();
+
+        let account = |nonce| {
+            Some(Account {
+                nonce,
+                ..Default::default()
+            })
+        };
+
+        for (block, address, account) in [
+            (1, address1, None),
+            (2, address1, account(1)),
+            (2, address2, None),
+            (4, address2, account(2)),
+            (5, address1, account(3)),
+            (5, address2, account(4)),
+        ] {
+            txn.set(
+                tables::AccountChangeSet,
+                BlockNumber(block),
+                tables::AccountChange { address, account },
+            )
+            .unwrap();
+        }
+
+        for (from, to) in [(0, u64::MAX), (2, 4), (3, 3), (2, 5), (6, 10)] {
+            let forward = super::account::walk_changes(&txn, from.into(), to.into())
+                .collect::<anyhow::Result<Vec<_>>>()
+                .unwrap();
+            let mut backward = super::account::walk_changes_back(&txn, from.into(), to.into())
+                .collect::<anyhow::Result<Vec<_>>>()
+                .unwrap();
+
+            assert!(forward
+                .iter()
+                .all(|(block, _)| (from..=to).contains(&block.0)));
+            backward.reverse();
+            assert_eq!(forward, backward);
+        }
+
+        for block in 0..=6 {
+            assert_eq!(
+                super::account::changes_at(&txn, block.into())
+                    .collect::<anyhow::Result<Vec<_>>>()
+                    .unwrap(),
+                super::account::walk_changes(&txn, block.into(), block.into())
+                    .map(|res| res.map(|(_, change)| change))
+                    .collect::<anyhow::Result<Vec<_>>>()
+                    .unwrap()
+            );
+        }
+        assert_eq!(
+            super::account::changes_at(&txn, 2.into())
+                .map(|res| res.map(|change| change.address))
+                .collect::<anyhow::Result<Vec<_>>>()
+                .unwrap(),
+            vec![address1, address2]
+        );
+
+        let only_address2 = [address2].into_iter().collect();
+        assert_eq!(
+            super::account::walk_changes_filtered(&txn, 1.into(), 5.into(), &only_address2)
+                .map(|res| res.map(|(block, change)| (block.0, change.account)))
+                .collect::<anyhow::Result<Vec<_>>>()
+                .unwrap(),
+            vec![(2, None), (4, account(2)), (5, account(4))]
+        );
+        assert!(
+            super::account::walk_changes_filtered(&txn, 0.into(), 10.into(), &BTreeSet::new())
+                .next()
+                .is_none()
+        );
+
+        assert_eq!(
+            super::account::walk_changes_back(&txn, 2.into(), 4.into())
+                .map(|res| res.map(|(block, change)| (block.0, change.address)))
+                .collect::<anyhow::Result<Vec<_>>>()
+                .unwrap(),
+            vec![(4, address2), (2, address2), (2, address1)]
+        );
+    }
+
+    #[test]
+    fn walk_owned_account_changes() {
+        let db = new_mem_chaindata().unwrap();
+        let txn = db.begin_mutable().unwrap();
+
+        let address1 = hex!("a000000000000000000000000000000000000001").into();
+        let address2 = hex!("b000000000000000000000000000000000000002").into();
+        let account = Account {
+            nonce: 5,
+            ..Default::default()
+        };
+
+        for (block, change) in [
+            (1, tables::AccountChange::deleted(address1)),
+            (2, tables::AccountChange::set(address1, account)),
+            (2, tables::AccountChange::deleted(address2)),
+            (3, tables::AccountChange::set(address2, account)),
+        ] {
+            txn.set(tables::AccountChangeSet, BlockNumber(block), change)
+                .unwrap();
+        }
+
+        let changes = super::account::walk_owned(&txn, 2.into(), 2.into())
+            .collect::<anyhow::Result<Vec<_>>>()
+            .unwrap();
+        drop(txn);
+
+        assert_eq!(
+            changes,
+            vec![
+                (
+                    2,
+                    address1,
+                    Bytes::copy_from_slice(&account.encode_for_storage())
+                ),
+                (2, address2, Bytes::new()),
+            ]
+        );
+    }
+
+    #[test]
+    fn changed_addresses() {
+        let db = new_mem_chaindata().unwrap();
+        let txn = db.begin_mutable().unwrap();
+
+        let address1 = hex!("a000000000000000000000000000000000000001").into();
+        let address2 = hex!("b000000000000000000000000000000000000002").into();
+        let address3 = hex!("c000000000000000000000000000000000000003").into();
+
+        for (block, address) in [
+            (1, address1),
+            (2, address1),
+            (2, address2),
+            (3, address1),
+            (6, address3),
+        ] {
+            txn.set(
+                tables::AccountChangeSet,
+                BlockNumber(block),
+                tables::AccountChange::deleted(address),
+            )
+            .unwrap();
+        }
+
+        for (from, to, expected) in [
+            (1, 3, vec![address1, address2]),
+            (3, 5, vec![address1]),
+            (0, u64::MAX, vec![address1, address2, address3]),
+            (4, 5, vec![]),
+        ] {
+            assert_eq!(
+                super::account::changed_addresses(&txn, from.into(), to.into()).unwrap(),
+                expected.into_iter().collect::<BTreeSet<_>>()
+            );
+        }
+    }
+
+    #[test]
+    fn find_account_range() {
+        let db = new_mem_chaindata().unwrap();
+        let txn = db.begin_mutable().unwrap();
+
+        let address1 = hex!("a000000000000000000000000000000000000001").into();
+        let address2 = hex!("b000000000000000000000000000000000000002").into();
+        let address3 = hex!("c000000000000000000000000000000000000003").into();
+
+        let account = |nonce| {
+            Some(Account {
+                nonce,
+                ..Default::default()
+            })
+        };
+
+        for (block, address, account) in [
+            (1, address1, None),
+            (3, address2, None),
+            (3, address1, account(1)),
+            (4, address2, account(1)),
+            (7, address1, account(2)),
+            (9, address1, account(3)),
+            (9, address2, account(2)),
+        ] {
+            txn.set(
+                tables::AccountChangeSet,
+                BlockNumber(block),
+                tables::AccountChange { address, account },
+            )
+            .unwrap();
+        }
+
+        let mut cursor = txn.cursor(tables::AccountChangeSet).unwrap();
+        for (from, to, address, expected) in [
+            (
+                0,
+                u64::MAX,
+                address1,
+                vec![(1, None), (3, account(1)), (7, account(2)), (9, account(3))],
+            ),
+            (2, 7, address1, vec![(3, account(1)), (7, account(2))]),
+            (4, 9, address2, vec![(4, account(1)), (9, account(2))]),
+            (5, 6, address1, vec![]),
+            (0, u64::MAX, address3, vec![]),
+        ] {
+            assert_eq!(
+                cursor
+                    .find_account_range(from.into(), to.into(), address)
+                    .unwrap(),
+                expected
+                    .into_iter()
+                    .map(|(block, account)| (BlockNumber(block), account))
+                    .collect::<Vec<_>>()
+            );
+        }
+    }
+
+    #[test]
+    fn seek_account_change() {
+        let db = new_mem_chaindata().unwrap();
+        let txn = db.begin_mutable().unwrap();
+
+        let address1 = hex!("a000000000000000000000000000000000000001").into();
+        let address2 = hex!("b000000000000000000000000000000000000002").into();
+        let address3 = hex!("c000000000000000000000000000000000000003").into();
+        let address4 = hex!("d000000000000000000000000000000000000004").into();
+        let account = Account {
+            nonce: 5,
+            balance: 100.as_u256(),
+            ..Default::default()
+        };
+
+        // Several duplicates under the same block
+        for (address, account) in [
+            (address1, Some(account)),
+            (address2, None),
+            (address4, Some(account)),
+        ] {
+            txn.set(
+                tables::AccountChangeSet,
+                BlockNumber(3),
+                tables::AccountChange { address, account },
+            )
+            .unwrap();
+        }
+
+        let mut cursor = txn.cursor(tables::AccountChangeSet).unwrap();
+        let encoded = Bytes::from(account.encode().to_vec());
+        for (block, address, expected) in [
+            (3, address1, Some(encoded.clone())),
+            (3, address2, Some(Bytes::new())),
+            (3, address3, None),
+            (3, address4, Some(encoded)),
+            (2, address1, None),
+        ] {
+            assert_eq!(
+                account::seek_account_change(&mut cursor, BlockNumber(block), address).unwrap(),
+                expected
+            );
+        }
+
+        assert_eq!(
+            cursor.find_account(BlockNumber(3), address4).unwrap(),
+            Some(Some(account))
+        );
+        assert_eq!(
+            cursor.find_account(BlockNumber(3), address2).unwrap(),
+            Some(None)
+        );
+        assert_eq!(cursor.find_account(BlockNumber(3), address3).unwrap(), None);
+    }
+
+    #[test]
+    fn find_many_accounts() {
+        let db = new_mem_chaindata().unwrap();
+        let txn = db.begin_mutable().unwrap();
+
+        let addresses = [
+            hex!("a000000000000000000000000000000000000001"),
+            hex!("b000000000000000000000000000000000000002"),
+            hex!("c000000000000000000000000000000000000003"),
+            hex!("d000000000000000000000000000000000000004"),
+            hex!("e000000000000000000000000000000000000005"),
+        ]
+        .map(Address::from);
+
+        for (block, address, nonce) in [
+            (3, addresses[0], Some(1)),
+            (3, addresses[1], None),
+            (3, addresses[3], Some(4)),
+            (4, addresses[2], Some(3)),
+        ] {
+            txn.set(
+                tables::AccountChangeSet,
+                BlockNumber(block),
+                tables::AccountChange {
+                    address,
+                    account: nonce.map(|nonce| Account {
+                        nonce,
+                        ..Default::default()
+                    }),
+                },
+            )
+            .unwrap();
+        }
+
+        let mut cursor = txn.cursor(tables::AccountChangeSet).unwrap();
+        let keys = [
+            addresses[3],
+            addresses[4],
+            addresses[0],
+            addresses[2],
+            addresses[1],
+            addresses[3],
+        ];
+        let expected = keys
+            .iter()
+            .map(|&address| {
+                account::seek_account_change(&mut cursor, BlockNumber(3), address).unwrap()
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            expected.iter().map(Option::is_some).collect::<Vec<_>>(),
+            [true, false, true, false, true, true]
+        );
+
+        assert_eq!(cursor.find_many(BlockNumber(3), &keys).unwrap(), expected);
+        assert_eq!(
+            cursor.find_many(BlockNumber(5), &keys).unwrap(),
+            vec![None; keys.len()]
+        );
+        assert!(cursor.find_many(BlockNumber(3), &[]).unwrap().is_empty());
+    }
+
+    #[test]
+    fn storage_change_encoding() {
+        let key = tables::StorageChangeKey {
+            block_number: 0x1f2e3d.into(),
+            address: hex!("b000000000000000000000000000000000000008").into(),
+        };
+        let change = tables::StorageChange {
+            location: hex!("000000000000000000000000000000000000a000000000000000000000000037")
+                .into(),
+            value: 0x5666856076ebaf477f07_u128.into(),
+        };
+
+        let encoded_key = key.encode();
+        assert_eq!(
+            encoded_key,
+            hex!("00000000001f2e3db000000000000000000000000000000000000008")
+        );
+        assert_eq!(
+            <tables::StorageChangeKey as TableDecode>::decode(&encoded_key).unwrap(),
+            key
+        );
+
+        let encoded_change = change.clone().encode();
+        assert_eq!(&encoded_change[..KECCAK_LENGTH], change.location.as_bytes());
+        assert_eq!(
+            <tables::StorageChange as TableDecode>::decode(&encoded_change).unwrap(),
+            change
+        );
+    }
+
+    #[test]
+    fn walk_storage_changes() {
+        let db = new_mem_chaindata().unwrap();
+        let txn = db.begin_mutable().unwrap();
+
+        let address1 = hex!("a000000000000000000000000000000000000001").into();
+        let address2 = hex!("b000000000000000000000000000000000000002").into();
+        let loc1 = H256::from_low_u64_be(1);
+        let loc2 = H256::from_low_u64_be(2);
+
+        let changes = [
+            (1, (address1, loc1), 0x10),
+            (2, (address1, loc1), 0x11),
+            (2, (address1, loc2), 0x20),
+            (2, (address2, loc1), 0x30),
+            (5, (address2, loc1), 0x31),
+        ];
+        for (block, (address, location), value) in changes {
+            txn.set(
+                tables::StorageChangeSet,
+                tables::StorageChangeKey {
+                    block_number: BlockNumber(block),
+                    address,
+                },
+                tables::StorageChange {
+                    location,
+                    value: value.as_u256(),
+                },
+            )
+            .unwrap();
+        }
+
+        assert_eq!(
+            super::storage::walk_changes(&txn, 2.into(), 4.into())
+                .collect::<anyhow::Result<Vec<_>>>()
+                .unwrap(),
+            changes[1..4]
+                .iter()
+                .map(|&(block, key, value)| (BlockNumber(block), key, value.as_u256()))
+                .collect::<Vec<_>>()
+        );
+
+        let mut cursor = txn.cursor(tables::StorageChangeSet).unwrap();
+        for (block, key, value) in changes {
+            assert_eq!(
+                cursor.find_storage(block.into(), key).unwrap(),
+                Some(value.as_u256())
+            );
+        }
+        assert_eq!(
+            cursor.find_storage(2.into(), (address2, loc2)).unwrap(),
+            None
+        );
+        assert_eq!(
+            cursor.find_storage(3.into(), (address1, loc1)).unwrap(),
+            None
+        );
+    }
+
+    #[test]
+    fn walk_change_entries() {
+        let db = new_mem_chaindata().unwrap();
+        let txn = db.begin_mutable().unwrap();
+
+        let address = hex!("a000000000000000000000000000000000000001").into();
+        let location = H256::from_low_u64_be(7);
+        txn.set(
+            tables::AccountChangeSet,
+            3.into(),
+            tables::AccountChange {
+                address,
+                account: None,
+            },
+        )
+        .unwrap();
+        txn.set(
+            tables::StorageChangeSet,
+            tables::StorageChangeKey {
+                block_number: 3.into(),
+                address,
+            },
+            tables::StorageChange {
+                location,
+                value: 0x42.as_u256(),
+            },
+        )
+        .unwrap();
+
+        let accounts = super::walk_change_entries::<_, _, ADDRESS_LENGTH>(
+            txn.cursor(tables::AccountChangeSet).unwrap(),
+            1.into(),
+        )
+        .collect::<anyhow::Result<Vec<_>>>()
+        .unwrap();
+        assert_eq!(accounts, vec![(3.into(), address.0, Bytes::new())]);
+
+        let storage = super::walk_change_entries::<_, _, KECCAK_LENGTH>(
+            txn.cursor(tables::StorageChangeSet).unwrap(),
+            1.into(),
+        )
+        .collect::<anyhow::Result<Vec<_>>>()
+        .unwrap();
+        assert_eq!(
+            storage,
+            vec![(
+                tables::StorageChangeKey {
+                    block_number: 3.into(),
+                    address,
+                },
+                location.0,
+                Bytes::copy_from_slice(&[0x42]),
+            )]
+        );
+
+        // Subkeys longer than the entries are rejected
+        assert!(super::walk_change_entries::<_, _, 64>(
+            txn.cursor(tables::StorageChangeSet).unwrap(),
+            1.into(),
+        )
+        .next()
+        .unwrap()
+        .is_err());
+    }
+
+    #[test]
+    fn interrupted_change_walk() {
+        let db = new_mem_chaindata().unwrap();
+        let txn = db.begin_mutable().unwrap();
+
+        let addresses = (1..=4_u64)
+            .map(Address::from_low_u64_be)
+            .collect::<Vec<_>>();
+        for (block, &address) in (1..).zip(&addresses) {
+            txn.set(
+                tables::AccountChangeSet,
+                BlockNumber(block),
+                tables::AccountChange {
+                    address,
+                    account: None,
+                },
+            )
+            .unwrap();
+        }
+
+        let mut walk = super::walk_change_entries::<_, _, ADDRESS_LENGTH>(
+            txn.cursor(tables::AccountChangeSet).unwrap(),
+            1.into(),
+        );
+        assert_eq!(walk.next().unwrap().unwrap().1, addresses[0].0);
+        assert_eq!(walk.next().unwrap().unwrap().1, addresses[1].0);
+        drop(walk);
+
+        assert_eq!(
+            txn.cursor(tables::AccountChangeSet)
+                .unwrap()
+                .seek_exact(BlockNumber(3))
+                .unwrap(),
+            Some((
+                BlockNumber(3),
+                tables::AccountChange {
+                    address: addresses[2],
+                    account: None,
+                }
+            ))
+        );
+        assert_eq!(
+            account::changed_addresses(&txn, BlockNumber(2), BlockNumber(4))
+                .unwrap()
+                .into_iter()
+                .collect::<Vec<_>>(),
+            addresses[1..]
+        );
+    }
+
+    #[test]
+    fn walk_account_storage() {
+        let db = new_mem_chaindata().unwrap();
+        let txn = db.begin_mutable().unwrap();
+
+        let contract1 = hex!("a000000000000000000000000000000000000001").into();
+        let contract2 = hex!("b000000000000000000000000000000000000002").into();
+        let loc1 = H256::from_low_u64_be(1);
+        let loc2 = H256::from_low_u64_be(2);
+
+        for (block, address, location, value) in [
+            (1, contract1, loc1, 0x10),
+            (1, contract2, loc1, 0x40),
+            (2, contract2, loc2, 0x41),
+            (3, contract1, loc1, 0x11),
+            (3, contract1, loc2, 0x20),
+            (3, contract2, loc1, 0x42),
+            (6, contract1, loc2, 0x21),
+        ] {
+            txn.set(
+                tables::StorageChangeSet,
+                tables::StorageChangeKey {
+                    block_number: BlockNumber(block),
+                    address,
+                },
+                tables::StorageChange {
+                    location,
+                    value: value.as_u256(),
+                },
+            )
+            .unwrap();
+        }
+
+        let walk = |address, from: u64, to: u64| {
+            super::storage::walk_account_storage(&txn, address, from.into(), to.into())
+                .map(|res| res.map(|(block, location, value)| (block.0, location, value)))
+                .collect::<anyhow::Result<Vec<_>>>()
+                .unwrap()
+        };
+
+        assert_eq!(
+            walk(contract1, 0, u64::MAX),
+            vec![
+                (1, loc1, 0x10.as_u256()),
+                (3, loc1, 0x11.as_u256()),
+                (3, loc2, 0x20.as_u256()),
+                (6, loc2, 0x21.as_u256()),
+            ]
+        );
+        assert_eq!(
+            walk(contract2, 2, 3),
+            vec![(2, loc2, 0x41.as_u256()), (3, loc1, 0x42.as_u256())]
+        );
+        assert_eq!(walk(contract2, 4, 10), vec![]);
+        assert_eq!(walk(contract1, 2, 2), vec![]);
+    }
+
     #[test]
     fn find_next_block() {
         let db = new_mem_chaindata().unwrap();
@@ -38,7 +38,7 @@ impl<'state> Blockchain<'state> {
     ) -> anyhow::Result<Blockchain<'state>> {
         let hash = genesis_block.header.hash();
         let number = genesis_block.header.number;
-        state.insert_block(genesis_block, hash);
+        state.insert_block(genesis_block, hash)?;
         state.canonize_block(number, hash);
 
         Ok(Self {
@@ -105,7 +105,7 @@ impl<'state> Blockchain<'state> {
             num_of_executed_chain_blocks += 1;
         }
 
-        self.state.insert_block(block, hash);
+        self.state.insert_block(block, hash)?;
 
         let current_total_difficulty = self
             .state
@@ -112,7 +112,11 @@ impl ForkChoiceGraph {
                 if self.chains.contains_key(&current) {
                     break;
                 }
-                td += header.difficulty;
+                // No real chain gets anywhere near, so only a bogus one can overflow
+                td = match add_difficulty(td, header.difficulty) {
+                    Ok(td) => td,
+                    Err(_) => break,
+                };
                 current = header.parent_hash;
                 depth += 1;
             }
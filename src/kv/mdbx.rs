@@ -347,6 +347,34 @@ where
         })
     }
 
+    /// Same as [`Self::walk`], but yields the values undecoded.
+    pub fn walk_raw(
+        mut self,
+        start_key: Option<T::SeekKey>,
+    ) -> impl Iterator<Item = anyhow::Result<(T::Key, Bytes)>>
+    where
+        T: Table,
+        T::Key: TableDecode,
+    {
+        TryGenIter::from(move |_| {
+            let mut fv = if let Some(start_key) = start_key {
+                self.inner
+                    .set_range::<TableObjectWrapper<T::Key>, TableObjectWrapper<Bytes>>(
+                        start_key.encode().as_ref(),
+                    )?
+            } else {
+                self.inner.first()?
+            };
+            while let Some((k, v)) = fv {
+                yield (k.0, v.0);
+
+                fv = self.inner.next()?;
+            }
+
+            Ok(())
+        })
+    }
+
     pub fn walk_back(
         mut self,
         start_key: Option<T::SeekKey>,
@@ -397,6 +425,21 @@ where
         Ok(None)
     }
 
+    /// Same as [`Self::seek_both_range`], but returns the duplicate undecoded.
+    pub fn seek_both_range_raw(
+        &mut self,
+        key: T::Key,
+        value: T::SeekBothKey,
+    ) -> anyhow::Result<Option<Bytes>> {
+        Ok(self
+            .inner
+            .get_both_range::<TableObjectWrapper<Bytes>>(
+                key.encode().as_ref(),
+                value.encode().as_ref(),
+            )?
+            .map(|v| v.0))
+    }
+
     pub fn last_dup(&mut self) -> anyhow::Result<Option<T::Value>>
     where
         T::Key: TableDecode,
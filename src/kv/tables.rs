@@ -732,6 +732,28 @@ pub struct AccountChange {
     pub account: Option<crate::models::Account>,
 }
 
+impl AccountChange {
+    /// Account did not exist before the change.
+    pub fn deleted(address: Address) -> Self {
+        Self {
+            address,
+            account: None,
+        }
+    }
+
+    pub fn set(address: Address, account: crate::models::Account) -> Self {
+        Self {
+            address,
+            account: Some(account),
+        }
+    }
+
+    /// Whether the change is encoded with an empty account value.
+    pub fn is_deletion(&self) -> bool {
+        self.account.is_none()
+    }
+}
+
 impl TableEncode for AccountChange {
     type Encoded = VariableVec<{ ADDRESS_LENGTH + MAX_ACCOUNT_LEN }>;
 
@@ -985,6 +1007,26 @@ mod tests {
         }
     }
 
+    #[test]
+    fn account_change_deletion() {
+        let address = Address::from(hex!("be00000000000000000000000000000000000000"));
+
+        let deleted = AccountChange::deleted(address);
+        let empty = AccountChange::set(address, crate::models::Account::default());
+        assert!(deleted.is_deletion());
+        assert!(!empty.is_deletion());
+
+        let encoded = deleted.clone().encode();
+        assert_eq!(encoded.as_ref(), address.as_bytes());
+        assert_eq!(AccountChange::decode(&encoded).unwrap(), deleted);
+
+        let encoded = empty.clone().encode();
+        assert!(encoded.len() > ADDRESS_LENGTH);
+        let decoded = AccountChange::decode(&encoded).unwrap();
+        assert!(!decoded.is_deletion());
+        assert_eq!(decoded, empty);
+    }
+
     #[test]
     fn table_meta() {
         assert!(!CHAINDATA_TABLES[tables::Account::const_db_name()].dup_sort);
@@ -112,13 +112,22 @@ impl Account {
 
         let field_set = AccountStorageFlags::from_bytes([enc.get_u8()]);
 
-        let decode_length = field_set.nonce_len();
+        let decode_length = usize::from(field_set.nonce_len());
         if decode_length > 0 {
-            a.nonce = bytes_to_u64(&enc[..decode_length.into()]);
-            enc.advance(decode_length.into());
+            if decode_length > 8 {
+                bail!("nonce cannot be longer than 8 bytes")
+            }
+            if enc.len() < decode_length {
+                bail!("account encoding truncated in nonce")
+            }
+            a.nonce = bytes_to_u64(&enc[..decode_length]);
+            enc.advance(decode_length);
         }
 
         if field_set.code_hash() {
+            if enc.len() < KECCAK_LENGTH {
+                bail!("account encoding truncated in code hash")
+            }
             a.code_hash = H256::from_slice(&enc[..KECCAK_LENGTH]);
             enc.advance(KECCAK_LENGTH);
         }
@@ -558,3 +558,27 @@ impl PartialHeader {
         }
     }
 }
+
+/// Adds the difficulty of a block to the total difficulty of its parent, failing instead of
+/// wrapping around.
+pub fn add_difficulty(td: U256, block_difficulty: U256) -> anyhow::Result<U256> {
+    td.checked_add(block_difficulty)
+        .ok_or_else(|| format_err!("total difficulty overflow: {} + {}", td, block_difficulty))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn difficulty_accumulation() {
+        assert_eq!(
+            add_difficulty(17.as_u256(), 25.as_u256()).unwrap(),
+            42.as_u256()
+        );
+        assert_eq!(add_difficulty(U256::MAX - 1, U256::ONE).unwrap(), U256::MAX);
+        assert_eq!(add_difficulty(U256::MAX, U256::ZERO).unwrap(), U256::MAX);
+        assert!(add_difficulty(U256::MAX, U256::ONE).is_err());
+        assert!(add_difficulty(U256::MAX - 1, 2.as_u256()).is_err());
+    }
+}
@@ -24,6 +24,13 @@ impl Receipt {
         }
     }
 
+    /// Check the stored bloom against the one recomputed from the logs.
+    ///
+    /// Receipts from peers are decoded as is, so this is how malformed ones are detected.
+    pub fn verify_bloom(&self) -> bool {
+        self.bloom == logs_bloom(&self.logs)
+    }
+
     fn rlp_header(&self) -> fastrlp::Header {
         let mut h = fastrlp::Header {
             list: true,
@@ -172,3 +179,54 @@ impl UntypedReceipt {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hex_literal::hex;
+
+    fn receipt(tx_type: TxType) -> Receipt {
+        Receipt::new(
+            tx_type,
+            true,
+            21000,
+            vec![Log {
+                address: hex!("22341ae42d6dd7384bc8584e50419ea3ac75b83f").into(),
+                topics: vec![hex!(
+                    "04491edcd115127caedbd478e2e7895ed80c7847e903431f94f9cfa579cad47f"
+                )
+                .into()],
+                data: vec![].into(),
+            }],
+        )
+    }
+
+    #[test]
+    fn verify_bloom() {
+        for tx_type in [TxType::Legacy, TxType::EIP2930, TxType::EIP1559] {
+            let receipt = receipt(tx_type);
+            assert!(receipt.verify_bloom());
+
+            let mut out = Vec::new();
+            receipt.encode(&mut out);
+            let decoded = Receipt::decode(&mut &*out).unwrap();
+            assert_eq!(decoded, receipt);
+            assert!(decoded.verify_bloom());
+
+            let mut corrupted = receipt;
+            corrupted.bloom.0[0] ^= 0x01;
+            let mut out = Vec::new();
+            corrupted.encode(&mut out);
+            assert!(!Receipt::decode(&mut &*out).unwrap().verify_bloom());
+        }
+    }
+
+    #[test]
+    fn verify_empty_bloom() {
+        let mut empty = Receipt::new(TxType::Legacy, false, 0, vec![]);
+        assert!(empty.verify_bloom());
+
+        empty.bloom = receipt(TxType::Legacy).bloom;
+        assert!(!empty.verify_bloom());
+    }
+}
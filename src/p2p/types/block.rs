@@ -1,5 +1,10 @@
-use crate::models::{Block, BlockNumber, H256};
+use crate::models::{Block, BlockNumber, H256, U256};
 use fastrlp::*;
+use rand::Rng;
+use std::{collections::HashSet, num::NonZeroUsize};
+
+/// Soft limit on the number of block hashes in a single `GetBlockBodies` request.
+pub const MAX_BODIES_REQUEST: NonZeroUsize = NonZeroUsize::new(128).unwrap();
 
 #[derive(Debug, Clone, PartialEq, Eq, RlpEncodable, RlpDecodable)]
 pub struct GetBlockBodies {
@@ -7,6 +12,41 @@ pub struct GetBlockBodies {
     pub hashes: Vec<H256>,
 }
 
+impl GetBlockBodies {
+    /// Request for the bodies, with repeated hashes dropped.
+    pub fn new(hashes: Vec<H256>) -> Self {
+        Self {
+            request_id: rand::thread_rng().gen::<u64>(),
+            hashes: dedup_hashes(hashes),
+        }
+    }
+
+    /// Same as [`Self::new`], but split into requests of at most `max` hashes each.
+    pub fn new_batched(hashes: Vec<H256>, max: NonZeroUsize) -> Vec<Self> {
+        batch_hashes(&dedup_hashes(hashes), max)
+            .map(|(request_id, hashes)| Self { request_id, hashes })
+            .collect()
+    }
+}
+
+/// Splits the hashes into chunks of at most `max` hashes, in order, each under its own random
+/// request id.
+pub(crate) fn batch_hashes(
+    hashes: &[H256],
+    max: NonZeroUsize,
+) -> impl Iterator<Item = (u64, Vec<H256>)> + '_ {
+    hashes
+        .chunks(max.get())
+        .map(|hashes| (rand::thread_rng().gen::<u64>(), hashes.to_vec()))
+}
+
+/// Drops repeated hashes, keeping the first occurrence of each.
+fn dedup_hashes(mut hashes: Vec<H256>) -> Vec<H256> {
+    let mut seen = HashSet::with_capacity(hashes.len());
+    hashes.retain(|&hash| seen.insert(hash));
+    hashes
+}
+
 #[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
 pub enum BlockId {
     Hash(H256),
@@ -48,8 +88,44 @@ pub struct BlockHashAndNumber {
     pub number: BlockNumber,
 }
 
+/// Full block announcement, only valid on proof-of-work chains.
 #[derive(Debug, Clone, PartialEq, Eq, RlpEncodable, RlpDecodable)]
 pub struct NewBlock {
     pub block: Block,
-    pub total_difficulty: u128,
+    pub total_difficulty: U256,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bodies_request_dedup() {
+        let hash = H256::from_low_u64_be;
+
+        let request = GetBlockBodies::new(vec![hash(3), hash(1), hash(3), hash(2), hash(1)]);
+        assert_eq!(request.hashes, [hash(3), hash(1), hash(2)]);
+        assert!(GetBlockBodies::new(vec![]).hashes.is_empty());
+    }
+
+    #[test]
+    fn bodies_request_batching() {
+        let hashes = (0..300)
+            .chain(0..50)
+            .map(H256::from_low_u64_be)
+            .collect::<Vec<_>>();
+
+        let requests = GetBlockBodies::new_batched(hashes.clone(), MAX_BODIES_REQUEST);
+        assert_eq!(
+            requests.iter().map(|r| r.hashes.len()).collect::<Vec<_>>(),
+            [128, 128, 44]
+        );
+        assert_eq!(
+            requests
+                .into_iter()
+                .flat_map(|r| r.hashes)
+                .collect::<Vec<_>>(),
+            hashes[..300]
+        );
+    }
 }
@@ -54,8 +54,134 @@ impl From<HeaderRequest> for GetBlockHeadersParams {
     }
 }
 
+/// Splits the headers of blocks `from..=to` into consecutive forward requests of at most `chunk`
+/// headers each, for syncing up to `to`.
+///
+/// Requests are numbered in order, starting from zero. Nothing is planned for an empty range or
+/// a zero `chunk`.
+pub fn plan_header_requests(from: u64, to: u64, chunk: u64) -> Vec<GetBlockHeaders> {
+    if from > to || chunk == 0 {
+        return vec![];
+    }
+
+    let mut requests = vec![];
+    let mut start = from;
+    loop {
+        let limit = (to - start).saturating_add(1).min(chunk);
+        requests.push(GetBlockHeaders {
+            request_id: requests.len() as u64,
+            params: GetBlockHeadersParams::from(HeaderRequest {
+                start: BlockId::Number(BlockNumber(start)),
+                limit,
+                ..Default::default()
+            }),
+        });
+
+        match start.checked_add(limit) {
+            Some(next) if next <= to => start = next,
+            _ => break,
+        }
+    }
+
+    requests
+}
+
 #[derive(Debug, Clone, PartialEq, Eq, RlpEncodable, RlpDecodable)]
 pub struct BlockHeaders {
     pub request_id: u64,
     pub headers: Vec<BlockHeader>,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hex_literal::hex;
+
+    fn rlp_encode<T: Encodable>(v: &T) -> Vec<u8> {
+        let mut out = Vec::new();
+        v.encode(&mut out);
+        out
+    }
+
+    #[test]
+    fn get_block_headers_by_number() {
+        let encoded = hex!("ca820457c682270f050580");
+        let request = GetBlockHeaders {
+            request_id: 1111,
+            params: GetBlockHeadersParams {
+                start: BlockId::Number(BlockNumber(9999)),
+                limit: 5,
+                skip: 5,
+                reverse: 0,
+            },
+        };
+
+        assert_eq!(GetBlockHeaders::decode(&mut &encoded[..]).unwrap(), request);
+        assert_eq!(rlp_encode(&request), encoded);
+    }
+
+    #[test]
+    fn get_block_headers_by_hash() {
+        let encoded = hex!(
+            "e8820457e4a000000000000000000000000000000000000000000000000000000000deadc0de050580"
+        );
+        let request = GetBlockHeaders {
+            request_id: 1111,
+            params: GetBlockHeadersParams {
+                start: BlockId::Hash(H256(hex!(
+                    "00000000000000000000000000000000000000000000000000000000deadc0de"
+                ))),
+                limit: 5,
+                skip: 5,
+                reverse: 0,
+            },
+        };
+
+        assert_eq!(GetBlockHeaders::decode(&mut &encoded[..]).unwrap(), request);
+        assert_eq!(rlp_encode(&request), encoded);
+    }
+
+    #[test]
+    fn header_request_plan() {
+        for (from, to, chunk) in [
+            (0, 0, 1),
+            (1, 10, 3),
+            (1, 9, 3),
+            (100, 100_000, 192),
+            (u64::MAX - 5, u64::MAX, 4),
+        ] {
+            let plan = plan_header_requests(from, to, chunk);
+            let mut next = Some(from);
+            for (i, request) in plan.iter().enumerate() {
+                assert_eq!(request.request_id, i as u64);
+                let params = &request.params;
+                assert_eq!(params.start, BlockId::Number(BlockNumber(next.unwrap())));
+                assert!(params.limit > 0 && params.limit <= chunk);
+                assert_eq!((params.skip, params.reverse), (0, 0));
+                next = next.unwrap().checked_add(params.limit);
+            }
+            assert_eq!(next, to.checked_add(1), "{} {} {}", from, to, chunk);
+        }
+        assert_eq!(plan_header_requests(1, 10, 3).len(), 4);
+
+        assert!(plan_header_requests(10, 9, 3).is_empty());
+        assert!(plan_header_requests(1, 10, 0).is_empty());
+    }
+
+    #[test]
+    fn get_block_headers_reverse() {
+        let request = GetBlockHeadersParams::from(HeaderRequest {
+            start: BlockId::Number(BlockNumber(14_000_000)),
+            limit: 192,
+            skip: 0,
+            reverse: true,
+        });
+        assert_eq!(request.reverse, 1);
+
+        let encoded = rlp_encode(&request);
+        assert_eq!(
+            GetBlockHeadersParams::decode(&mut &*encoded).unwrap(),
+            request
+        );
+    }
+}
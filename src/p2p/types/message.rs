@@ -1,13 +1,14 @@
 use crate::{
-    models::{BlockBody, MessageWithSignature, H256},
+    models::{BlockBody, MessageWithSignature, Receipt, H256},
     p2p::types::*,
     sentry::devp2p::PeerId,
 };
 use anyhow::anyhow;
+use bytes::Bytes;
 use ethereum_interfaces::sentry as grpc_sentry;
 use fastrlp::*;
 use rand::Rng;
-use std::fmt::Display;
+use std::{fmt::Display, num::NonZeroUsize};
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, strum::EnumIter)]
 pub enum MessageId {
@@ -117,6 +118,42 @@ pub struct BlockBodies {
     pub bodies: Vec<BlockBody>,
 }
 
+#[derive(Debug, Clone, PartialEq, Eq, RlpEncodable, RlpDecodable)]
+pub struct GetNodeData {
+    pub request_id: u64,
+    pub hashes: Vec<H256>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, RlpEncodable, RlpDecodable)]
+pub struct NodeData {
+    pub request_id: u64,
+    pub data: Vec<Bytes>,
+}
+
+/// Soft limit on the number of block hashes in a single `GetReceipts` request.
+pub const MAX_RECEIPTS_REQUEST: NonZeroUsize = NonZeroUsize::new(256).unwrap();
+
+#[derive(Debug, Clone, PartialEq, Eq, RlpEncodable, RlpDecodable)]
+pub struct GetReceipts {
+    pub request_id: u64,
+    pub hashes: Vec<H256>,
+}
+
+impl GetReceipts {
+    /// Splits the hashes into requests of at most `max` hashes each, in order.
+    pub fn new_batched(hashes: Vec<H256>, max: NonZeroUsize) -> Vec<Self> {
+        batch_hashes(&hashes, max)
+            .map(|(request_id, hashes)| Self { request_id, hashes })
+            .collect()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, RlpEncodable, RlpDecodable)]
+pub struct Receipts {
+    pub request_id: u64,
+    pub receipts: Vec<Vec<Receipt>>,
+}
+
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum Message {
     NewBlockHashes(NewBlockHashes),
@@ -224,3 +261,36 @@ impl InboundMessage {
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn receipts_request_batching() {
+        let hashes = (0..600).map(H256::from_low_u64_be).collect::<Vec<_>>();
+
+        let requests = GetReceipts::new_batched(hashes.clone(), MAX_RECEIPTS_REQUEST);
+        assert_eq!(
+            requests.iter().map(|r| r.hashes.len()).collect::<Vec<_>>(),
+            [256, 256, 88]
+        );
+        assert_eq!(
+            requests
+                .into_iter()
+                .flat_map(|r| r.hashes)
+                .collect::<Vec<_>>(),
+            hashes
+        );
+
+        assert_eq!(
+            GetReceipts::new_batched(hashes[..256].to_vec(), MAX_RECEIPTS_REQUEST).len(),
+            1
+        );
+        assert!(GetReceipts::new_batched(vec![], MAX_RECEIPTS_REQUEST).is_empty());
+        assert_eq!(
+            GetReceipts::new_batched(hashes[..3].to_vec(), NonZeroUsize::new(1).unwrap()).len(),
+            3
+        );
+    }
+}
@@ -4,8 +4,9 @@ mod message;
 mod penalty;
 mod rlp;
 mod status;
+mod transaction;
 
-pub use self::{block::*, header::*, message::*, penalty::*, rlp::*, status::*};
+pub use self::{block::*, header::*, message::*, penalty::*, rlp::*, status::*, transaction::*};
 
 use super::node::SentryId;
 use crate::sentry::devp2p::PeerId;
@@ -6,7 +6,9 @@ use fastrlp::*;
 
 impl Decodable for BlockId {
     fn decode(buf: &mut &[u8]) -> Result<Self, DecodeError> {
-        if buf.len() == 32 {
+        // Hashes are the only 32 byte strings, block numbers always fit into 8 bytes.
+        let header = Header::decode(&mut &**buf)?;
+        if !header.list && header.payload_length == 32 {
             Ok(BlockId::Hash(<H256 as Decodable>::decode(buf)?))
         } else {
             Ok(BlockId::Number(<BlockNumber as Decodable>::decode(buf)?))
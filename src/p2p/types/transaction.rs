@@ -0,0 +1,631 @@
+use crate::{
+    crypto::keccak256,
+    models::*,
+    util::{u256_to_h256, write_hex_string},
+};
+use bytes::{BufMut, Bytes, BytesMut};
+use educe::Educe;
+use fastrlp::*;
+use sha2::{Digest, Sha256};
+
+/// EIP-2718 type byte of EIP-4844 blob transactions.
+pub const BLOB_TX_TYPE: u8 = 0x03;
+/// Version byte of blob versioned hashes of KZG commitments.
+pub const VERSIONED_HASH_VERSION_KZG: u8 = 0x01;
+/// Length of KZG commitments and proofs.
+pub const KZG_LENGTH: usize = 48;
+
+/// EIP-4844 blob transaction, without the blobs themselves.
+#[derive(Clone, Educe, PartialEq, Eq)]
+#[educe(Debug)]
+pub struct BlobTransaction {
+    pub chain_id: ChainId,
+    pub nonce: u64,
+    pub max_priority_fee_per_gas: U256,
+    pub max_fee_per_gas: U256,
+    pub gas_limit: u64,
+    /// Blob transactions cannot create contracts.
+    pub to: Address,
+    pub value: U256,
+    #[educe(Debug(method = "write_hex_string"))]
+    pub input: Bytes,
+    pub access_list: Vec<AccessListItem>,
+    pub max_fee_per_blob_gas: U256,
+    pub blob_versioned_hashes: Vec<H256>,
+    pub signature: MessageSignature,
+}
+
+#[derive(RlpEncodable)]
+struct BlobTransactionRef<'a> {
+    chain_id: &'a ChainId,
+    nonce: &'a u64,
+    max_priority_fee_per_gas: &'a U256,
+    max_fee_per_gas: &'a U256,
+    gas_limit: &'a u64,
+    to: &'a Address,
+    value: &'a U256,
+    input: &'a Bytes,
+    access_list: &'a Vec<AccessListItem>,
+    max_fee_per_blob_gas: &'a U256,
+    blob_versioned_hashes: &'a Vec<H256>,
+    odd_y_parity: bool,
+    r: U256,
+    s: U256,
+}
+
+#[derive(RlpDecodable)]
+struct BlobTransactionOwned {
+    chain_id: ChainId,
+    nonce: u64,
+    max_priority_fee_per_gas: U256,
+    max_fee_per_gas: U256,
+    gas_limit: u64,
+    to: Address,
+    value: U256,
+    input: Bytes,
+    access_list: Vec<AccessListItem>,
+    max_fee_per_blob_gas: U256,
+    blob_versioned_hashes: Vec<H256>,
+    odd_y_parity: bool,
+    r: U256,
+    s: U256,
+}
+
+impl TryFrom<BlobTransactionOwned> for BlobTransaction {
+    type Error = DecodeError;
+
+    fn try_from(
+        BlobTransactionOwned {
+            chain_id,
+            nonce,
+            max_priority_fee_per_gas,
+            max_fee_per_gas,
+            gas_limit,
+            to,
+            value,
+            input,
+            access_list,
+            max_fee_per_blob_gas,
+            blob_versioned_hashes,
+            odd_y_parity,
+            r,
+            s,
+        }: BlobTransactionOwned,
+    ) -> Result<Self, Self::Error> {
+        if max_fee_per_gas.checked_mul(gas_limit.into()).is_none() {
+            return Err(DecodeError::Custom("gas limit price product overflow"));
+        }
+
+        Ok(Self {
+            chain_id,
+            nonce,
+            max_priority_fee_per_gas,
+            max_fee_per_gas,
+            gas_limit,
+            to,
+            value,
+            input,
+            access_list,
+            max_fee_per_blob_gas,
+            blob_versioned_hashes,
+            signature: MessageSignature::new(odd_y_parity, u256_to_h256(r), u256_to_h256(s))
+                .ok_or(DecodeError::Custom("Invalid transaction signature format"))?,
+        })
+    }
+}
+
+impl BlobTransaction {
+    fn as_rlp(&self) -> BlobTransactionRef<'_> {
+        BlobTransactionRef {
+            chain_id: &self.chain_id,
+            nonce: &self.nonce,
+            max_priority_fee_per_gas: &self.max_priority_fee_per_gas,
+            max_fee_per_gas: &self.max_fee_per_gas,
+            gas_limit: &self.gas_limit,
+            to: &self.to,
+            value: &self.value,
+            input: &self.input,
+            access_list: &self.access_list,
+            max_fee_per_blob_gas: &self.max_fee_per_blob_gas,
+            blob_versioned_hashes: &self.blob_versioned_hashes,
+            odd_y_parity: self.signature.odd_y_parity(),
+            r: U256::from_be_bytes(self.signature.r().0),
+            s: U256::from_be_bytes(self.signature.s().0),
+        }
+    }
+
+    /// Type byte followed by the RLP payload, as hashed and included in blocks.
+    fn typed_payload_length(&self) -> usize {
+        1 + self.as_rlp().length()
+    }
+
+    fn encode_typed_payload(&self, out: &mut dyn BufMut) {
+        out.put_u8(BLOB_TX_TYPE);
+        self.as_rlp().encode(out);
+    }
+
+    pub fn hash(&self) -> H256 {
+        let mut buf = BytesMut::with_capacity(self.typed_payload_length());
+        self.encode_typed_payload(&mut buf);
+        keccak256(&buf)
+    }
+}
+
+/// Versioned hash of a KZG commitment, as listed in `blob_versioned_hashes`.
+pub fn kzg_to_versioned_hash(commitment: &[u8]) -> H256 {
+    let mut hash = H256::from_slice(&Sha256::digest(commitment));
+    hash.0[0] = VERSIONED_HASH_VERSION_KZG;
+    hash
+}
+
+/// Blob transaction in its network form, as returned in `PooledTransactions`, with the blobs
+/// and their KZG commitments and proofs.
+#[derive(Clone, Educe, PartialEq, Eq)]
+#[educe(Debug)]
+pub struct BlobTransactionWithSidecar {
+    pub tx: BlobTransaction,
+    #[educe(Debug(ignore))]
+    pub blobs: Vec<Bytes>,
+    pub commitments: Vec<Bytes>,
+    pub proofs: Vec<Bytes>,
+}
+
+#[derive(RlpEncodable)]
+struct BlobTransactionWithSidecarRef<'a> {
+    tx: BlobTransactionRef<'a>,
+    blobs: &'a Vec<Bytes>,
+    commitments: &'a Vec<Bytes>,
+    proofs: &'a Vec<Bytes>,
+}
+
+#[derive(RlpDecodable)]
+struct BlobTransactionWithSidecarOwned {
+    tx: BlobTransactionOwned,
+    blobs: Vec<Bytes>,
+    commitments: Vec<Bytes>,
+    proofs: Vec<Bytes>,
+}
+
+impl BlobTransactionWithSidecar {
+    fn as_rlp(&self) -> BlobTransactionWithSidecarRef<'_> {
+        BlobTransactionWithSidecarRef {
+            tx: self.tx.as_rlp(),
+            blobs: &self.blobs,
+            commitments: &self.commitments,
+            proofs: &self.proofs,
+        }
+    }
+
+    fn typed_payload_length(&self) -> usize {
+        1 + self.as_rlp().length()
+    }
+
+    fn encode_typed_payload(&self, out: &mut dyn BufMut) {
+        out.put_u8(BLOB_TX_TYPE);
+        self.as_rlp().encode(out);
+    }
+
+    /// Checks that there is a commitment and a proof for every blob, and that the commitments
+    /// are the ones the transaction commits to.
+    ///
+    /// The KZG proofs themselves are not verified.
+    pub fn validate(&self) -> Result<(), DecodeError> {
+        let blob_count = self.tx.blob_versioned_hashes.len();
+        if self.blobs.len() != blob_count
+            || self.commitments.len() != blob_count
+            || self.proofs.len() != blob_count
+        {
+            return Err(DecodeError::Custom("blob sidecar length mismatch"));
+        }
+
+        if self
+            .commitments
+            .iter()
+            .chain(&self.proofs)
+            .any(|v| v.len() != KZG_LENGTH)
+        {
+            return Err(DecodeError::Custom(
+                "invalid KZG commitment or proof length",
+            ));
+        }
+
+        if self
+            .commitments
+            .iter()
+            .zip(&self.tx.blob_versioned_hashes)
+            .any(|(commitment, &hash)| kzg_to_versioned_hash(commitment) != hash)
+        {
+            return Err(DecodeError::Custom(
+                "blob versioned hash does not match commitment",
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+impl TryFrom<BlobTransactionWithSidecarOwned> for BlobTransactionWithSidecar {
+    type Error = DecodeError;
+
+    fn try_from(
+        BlobTransactionWithSidecarOwned {
+            tx,
+            blobs,
+            commitments,
+            proofs,
+        }: BlobTransactionWithSidecarOwned,
+    ) -> Result<Self, Self::Error> {
+        let tx = Self {
+            tx: tx.try_into()?,
+            blobs,
+            commitments,
+            proofs,
+        };
+        tx.validate()?;
+
+        Ok(tx)
+    }
+}
+
+/// Transaction as gossiped in `Transactions`: either a bare legacy RLP list, or an RLP string
+/// wrapping the EIP-2718 type byte and the typed payload.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum TransactionEnvelope {
+    /// Legacy, EIP-2930 and EIP-1559 transactions.
+    Message(MessageWithSignature),
+    /// Blob transaction in its consensus encoding, without the blobs.
+    Blob(BlobTransaction),
+    /// Blob transaction in its network encoding, wrapped with the blobs.
+    BlobWithSidecar(BlobTransactionWithSidecar),
+}
+
+impl TransactionEnvelope {
+    /// EIP-2718 type byte, 0 for legacy transactions.
+    pub fn tx_type(&self) -> u8 {
+        match self {
+            Self::Message(tx) => tx.tx_type() as u8,
+            Self::Blob(_) | Self::BlobWithSidecar(_) => BLOB_TX_TYPE,
+        }
+    }
+
+    pub fn hash(&self) -> H256 {
+        match self {
+            Self::Message(tx) => tx.hash(),
+            Self::Blob(tx) => tx.hash(),
+            Self::BlobWithSidecar(tx) => tx.tx.hash(),
+        }
+    }
+}
+
+impl From<MessageWithSignature> for TransactionEnvelope {
+    fn from(tx: MessageWithSignature) -> Self {
+        Self::Message(tx)
+    }
+}
+
+impl From<BlobTransaction> for TransactionEnvelope {
+    fn from(tx: BlobTransaction) -> Self {
+        Self::Blob(tx)
+    }
+}
+
+impl From<BlobTransactionWithSidecar> for TransactionEnvelope {
+    fn from(tx: BlobTransactionWithSidecar) -> Self {
+        Self::BlobWithSidecar(tx)
+    }
+}
+
+impl Encodable for TransactionEnvelope {
+    fn length(&self) -> usize {
+        match self {
+            Self::Message(tx) => tx.length(),
+            Self::Blob(tx) => {
+                let payload_length = tx.typed_payload_length();
+                length_of_length(payload_length) + payload_length
+            }
+            Self::BlobWithSidecar(tx) => {
+                let payload_length = tx.typed_payload_length();
+                length_of_length(payload_length) + payload_length
+            }
+        }
+    }
+
+    fn encode(&self, out: &mut dyn BufMut) {
+        match self {
+            Self::Message(tx) => tx.encode(out),
+            Self::Blob(tx) => {
+                Header {
+                    list: false,
+                    payload_length: tx.typed_payload_length(),
+                }
+                .encode(out);
+                tx.encode_typed_payload(out);
+            }
+            Self::BlobWithSidecar(tx) => {
+                Header {
+                    list: false,
+                    payload_length: tx.typed_payload_length(),
+                }
+                .encode(out);
+                tx.encode_typed_payload(out);
+            }
+        }
+    }
+}
+
+impl Decodable for TransactionEnvelope {
+    fn decode(buf: &mut &[u8]) -> Result<Self, DecodeError> {
+        let mut payload = &**buf;
+        let h = Header::decode(&mut payload)?;
+
+        if h.list {
+            return Ok(Self::Message(MessageWithSignature::decode(buf)?));
+        }
+
+        if payload.len() < h.payload_length {
+            return Err(DecodeError::InputTooShort);
+        }
+
+        // Type byte must be part of the string, not whatever follows an empty one
+        match payload[..h.payload_length].first().copied() {
+            None => Err(DecodeError::Custom("no tx body")),
+            Some(0x01 | 0x02) => Ok(Self::Message(MessageWithSignature::decode(buf)?)),
+            Some(BLOB_TX_TYPE) => {
+                let mut body = &payload[1..h.payload_length];
+                *buf = &payload[h.payload_length..];
+
+                // The network form wraps the transaction's field list into an outer list
+                let wrapped = {
+                    let mut peek = body;
+                    Header::decode(&mut peek)?;
+                    matches!(peek.first(), Some(&b) if b >= EMPTY_LIST_CODE)
+                };
+                let tx = if wrapped {
+                    Self::BlobWithSidecar(
+                        BlobTransactionWithSidecarOwned::decode(&mut body)?.try_into()?,
+                    )
+                } else {
+                    Self::Blob(BlobTransactionOwned::decode(&mut body)?.try_into()?)
+                };
+
+                if !body.is_empty() {
+                    return Err(DecodeError::ListLengthMismatch {
+                        expected: h.payload_length,
+                        got: h.payload_length - body.len(),
+                    });
+                }
+
+                Ok(tx)
+            }
+            Some(_) => Err(DecodeError::Custom("unknown tx type")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hex_literal::hex;
+
+    fn signature() -> MessageSignature {
+        MessageSignature::new(
+            false,
+            hex!("36b241b061a36a32ab7fe86c7aa9eb592dd59018cd0443adc0903590c16b02b0"),
+            hex!("5edcc541b4741c5cc6dd347c5ed9577ef293a62787b4510465fadbfe39ee4094"),
+        )
+        .unwrap()
+    }
+
+    fn access_list() -> Vec<AccessListItem> {
+        vec![AccessListItem {
+            address: hex!("de0b295669a9fd93d5f28d9ec85e40f4cb697bae").into(),
+            slots: vec![H256::from_low_u64_be(3), H256::from_low_u64_be(7)],
+        }]
+    }
+
+    fn transactions() -> Vec<TransactionEnvelope> {
+        let to = Address::from(hex!("811a752c8cd697e3cb27279c330ed1ada745a8d7"));
+        let input = Bytes::from_static(&hex!("6ebaf477f83e051589c1188bcc6ddccd"));
+        vec![
+            MessageWithSignature {
+                message: Message::Legacy {
+                    chain_id: Some(ChainId(5)),
+                    nonce: 12,
+                    gas_price: 20_000_000_000_u64.into(),
+                    gas_limit: 21000,
+                    action: TransactionAction::Call(to),
+                    value: 10.as_u256(),
+                    input: input.clone(),
+                },
+                signature: signature(),
+            }
+            .into(),
+            MessageWithSignature {
+                message: Message::EIP2930 {
+                    chain_id: ChainId(5),
+                    nonce: 7,
+                    gas_price: 30_000_000_000_u64.into(),
+                    gas_limit: 5_748_100,
+                    action: TransactionAction::Create,
+                    value: 0.as_u256(),
+                    input: input.clone(),
+                    access_list: access_list(),
+                },
+                signature: signature(),
+            }
+            .into(),
+            MessageWithSignature {
+                message: Message::EIP1559 {
+                    chain_id: ChainId(5),
+                    nonce: 7,
+                    max_priority_fee_per_gas: 10_000_000_000_u64.into(),
+                    max_fee_per_gas: 30_000_000_000_u64.into(),
+                    gas_limit: 5_748_100,
+                    action: TransactionAction::Call(to),
+                    value: 2.as_u256(),
+                    input: input.clone(),
+                    access_list: access_list(),
+                },
+                signature: signature(),
+            }
+            .into(),
+            BlobTransaction {
+                chain_id: ChainId(5),
+                nonce: 8,
+                max_priority_fee_per_gas: 10_000_000_000_u64.into(),
+                max_fee_per_gas: 30_000_000_000_u64.into(),
+                gas_limit: 21000,
+                to,
+                value: 0.as_u256(),
+                input,
+                access_list: access_list(),
+                max_fee_per_blob_gas: 1_000_000_000_u64.into(),
+                blob_versioned_hashes: vec![H256(hex!(
+                    "01a915e4d060149eb4365960e6a7a45f334393093061116b197e3240065ff2d8"
+                ))],
+                signature: signature(),
+            }
+            .into(),
+        ]
+    }
+
+    #[test]
+    fn transaction_envelope_rlp() {
+        for (tx, tx_type) in transactions().into_iter().zip(0_u8..) {
+            assert_eq!(tx.tx_type(), tx_type);
+
+            let mut encoded = BytesMut::new();
+            tx.encode(&mut encoded);
+            assert_eq!(encoded.len(), tx.length());
+            if tx_type > 0 {
+                // Typed transactions are wrapped in an RLP string
+                let h = Header::decode(&mut &*encoded).unwrap();
+                assert!(!h.list);
+                assert_eq!(encoded[encoded.len() - h.payload_length], tx_type);
+            }
+
+            let buf = &mut &*encoded;
+            assert_eq!(TransactionEnvelope::decode(buf).unwrap(), tx);
+            assert!(buf.is_empty());
+        }
+    }
+
+    #[test]
+    fn transactions_message_rlp() {
+        let txs = transactions();
+        let mut encoded = BytesMut::new();
+        txs.encode(&mut encoded);
+
+        assert_eq!(
+            <Vec<TransactionEnvelope>>::decode(&mut &*encoded).unwrap(),
+            txs
+        );
+    }
+
+    #[test]
+    fn blob_transaction_hash() {
+        let tx = transactions().pop().unwrap();
+        let mut encoded = BytesMut::new();
+        tx.encode(&mut encoded);
+        let h = Header::decode(&mut &*encoded).unwrap();
+
+        assert_eq!(
+            tx.hash(),
+            keccak256(&encoded[encoded.len() - h.payload_length..])
+        );
+    }
+
+    fn blob_transaction_with_sidecar() -> BlobTransactionWithSidecar {
+        let tx = match transactions().pop().unwrap() {
+            TransactionEnvelope::Blob(tx) => tx,
+            other => unreachable!("{:?}", other),
+        };
+        let commitment = Bytes::from(vec![0xc0; KZG_LENGTH]);
+
+        BlobTransactionWithSidecar {
+            tx: BlobTransaction {
+                blob_versioned_hashes: vec![kzg_to_versioned_hash(&commitment)],
+                ..tx
+            },
+            blobs: vec![Bytes::from(vec![0x42; 4096])],
+            commitments: vec![commitment],
+            proofs: vec![Bytes::from(vec![0x11; KZG_LENGTH])],
+        }
+    }
+
+    #[test]
+    fn blob_transaction_with_sidecar_rlp() {
+        let sidecar = blob_transaction_with_sidecar();
+        assert_eq!(
+            sidecar.tx.blob_versioned_hashes[0].0[0],
+            VERSIONED_HASH_VERSION_KZG
+        );
+        let consensus = TransactionEnvelope::from(sidecar.tx.clone());
+        let tx = TransactionEnvelope::from(sidecar.clone());
+        assert_eq!(tx.tx_type(), BLOB_TX_TYPE);
+        assert_eq!(tx.hash(), consensus.hash());
+
+        let mut encoded = BytesMut::new();
+        tx.encode(&mut encoded);
+        assert_eq!(encoded.len(), tx.length());
+        let buf = &mut &*encoded;
+        assert_eq!(TransactionEnvelope::decode(buf).unwrap(), tx);
+        assert!(buf.is_empty());
+
+        // Consensus encoding is still told apart from the network one
+        let mut encoded = BytesMut::new();
+        consensus.encode(&mut encoded);
+        assert_eq!(
+            TransactionEnvelope::decode(&mut &*encoded).unwrap(),
+            consensus
+        );
+    }
+
+    #[test]
+    fn blob_sidecar_mismatch_is_rejected() {
+        let valid = blob_transaction_with_sidecar();
+        valid.validate().unwrap();
+
+        let mut wrong_hash = valid.clone();
+        wrong_hash.tx.blob_versioned_hashes[0] = H256::repeat_byte(0x01);
+        let mut missing_proof = valid.clone();
+        missing_proof.proofs.clear();
+        let mut short_commitment = valid;
+        short_commitment.commitments[0].truncate(KZG_LENGTH - 1);
+
+        for invalid in [wrong_hash, missing_proof, short_commitment] {
+            assert!(invalid.validate().is_err());
+
+            let mut encoded = BytesMut::new();
+            TransactionEnvelope::from(invalid).encode(&mut encoded);
+            assert!(TransactionEnvelope::decode(&mut &*encoded).is_err());
+        }
+    }
+
+    #[test]
+    fn unknown_tx_type_is_rejected() {
+        let mut encoded = BytesMut::new();
+        transactions()[2].encode(&mut encoded);
+
+        for tx_type in [0x00, 0x04, 0x7f] {
+            let mut encoded = encoded.to_vec();
+            let h = Header::decode(&mut &*encoded).unwrap();
+            let type_offset = encoded.len() - h.payload_length;
+            encoded[type_offset] = tx_type;
+
+            assert!(TransactionEnvelope::decode(&mut &*encoded).is_err());
+        }
+    }
+
+    #[test]
+    fn empty_tx_body_is_rejected() {
+        // Type byte following an empty string must not be taken for the transaction's
+        for encoded in [&hex!("8003")[..], &hex!("8001"), &hex!("80")] {
+            assert!(matches!(
+                TransactionEnvelope::decode(&mut &*encoded),
+                Err(DecodeError::Custom("no tx body"))
+            ));
+        }
+        assert!(<Vec<TransactionEnvelope>>::decode(&mut &hex!("c28003")[..]).is_err());
+    }
+}
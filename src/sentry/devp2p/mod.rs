@@ -20,8 +20,8 @@ mod types;
 pub mod util;
 
 pub use disc::*;
-pub use peer::{DisconnectReason, PeerStream};
-pub use rlpx::{ListenOptions, Swarm, SwarmBuilder};
+pub use peer::{DisconnectReason, PeerStream, Snappy};
+pub use rlpx::{IngressOptions, ListenOptions, Swarm, SwarmBuilder};
 pub use types::{
     CapabilityId, CapabilityInfo, CapabilityName, CapabilityServer, CapabilityVersion,
     InboundEvent, Message, NodeRecord, OutboundEvent, PeerId,
@@ -19,7 +19,7 @@ use tracing::*;
 const MAX_PAYLOAD_SIZE: usize = 16 * 1024 * 1024;
 
 /// RLPx disconnect reason.
-#[derive(Clone, Copy, Debug, Display, Primitive)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Display, Primitive)]
 pub enum DisconnectReason {
     #[display(fmt = "disconnect requested")]
     DisconnectRequested = 0x00,
@@ -49,6 +49,37 @@ pub enum DisconnectReason {
     SubprotocolSpecific = 0x10,
 }
 
+impl Encodable for DisconnectReason {
+    fn encode(&self, out: &mut dyn BufMut) {
+        let reason = *self as u8;
+        Header {
+            list: true,
+            payload_length: reason.length(),
+        }
+        .encode(out);
+        reason.encode(out);
+    }
+
+    fn length(&self) -> usize {
+        let payload_length = (*self as u8).length();
+        length_of_length(payload_length) + payload_length
+    }
+}
+
+impl Decodable for DisconnectReason {
+    fn decode(buf: &mut &[u8]) -> Result<Self, DecodeError> {
+        // Some clients send the reason without wrapping it into a list
+        if let Some(prelude) = buf.first() {
+            if *prelude == 0xc1 {
+                buf.advance(1);
+            }
+        }
+
+        DisconnectReason::from_u8(u8::decode(buf)?)
+            .ok_or(DecodeError::Custom("unknown disconnect reason"))
+    }
+}
+
 /// RLPx protocol version.
 #[derive(Copy, Clone, Debug, Primitive)]
 pub enum ProtocolVersion {
@@ -70,19 +101,62 @@ pub struct HelloMessage {
     pub id: PeerId,
 }
 
+/// Snappy compression of message payloads, used since devp2p v5.
 #[derive(Debug)]
-struct Snappy {
+pub struct Snappy {
     encoder: snap::raw::Encoder,
     decoder: snap::raw::Decoder,
+    max_payload_size: usize,
 }
 
 impl Default for Snappy {
     fn default() -> Self {
+        Self::new(MAX_PAYLOAD_SIZE)
+    }
+}
+
+impl Snappy {
+    pub fn new(max_payload_size: usize) -> Self {
         Self {
             encoder: snap::raw::Encoder::new(),
             decoder: snap::raw::Decoder::new(),
+            max_payload_size,
         }
     }
+
+    fn check_payload_size(&self, payload_len: usize) -> io::Result<()> {
+        if payload_len > self.max_payload_size {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "payload size ({}) exceeds limit ({} bytes)",
+                    payload_len, self.max_payload_size
+                ),
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// Appends compressed payload to `out`.
+    pub fn compress(&mut self, payload: &[u8], out: &mut BytesMut) -> io::Result<()> {
+        self.check_payload_size(payload.len())?;
+
+        let mut buf = out.split_off(out.len());
+        buf.resize(snap::raw::max_compress_len(payload.len()), 0);
+        let compressed_len = self.encoder.compress(payload, &mut buf)?;
+        buf.truncate(compressed_len);
+        out.unsplit(buf);
+
+        Ok(())
+    }
+
+    /// Decompresses payload, checking its declared size before allocating for it.
+    pub fn decompress(&mut self, input: &[u8]) -> io::Result<Bytes> {
+        self.check_payload_size(snap::raw::decompress_len(input)?)?;
+
+        Ok(self.decoder.decompress_vec(input)?.into())
+    }
 }
 
 /// RLPx transport peer stream
@@ -206,17 +280,7 @@ where
         match message_id {
             0 => {}
             1 => {
-                let mut decodable_data = payload;
-                if let Some(prelude) = decodable_data.first() {
-                    if *prelude == 0xc1 {
-                        decodable_data.advance(1);
-                    }
-                }
-
-                if let Some(reason) = u8::decode(&mut decodable_data)
-                    .ok()
-                    .and_then(DisconnectReason::from_u8)
-                {
+                if let Ok(reason) = DisconnectReason::decode(&mut &*payload) {
                     bail!("explicit disconnect: {reason}");
                 } else {
                     bail!(
@@ -322,35 +386,14 @@ where
 
                 let (cap, id, data) = match u8::decode(&mut &val[..1]) {
                     Ok(message_id) => {
-                        let input = &val[1..];
-                        let payload_len = snap::raw::decompress_len(input)?;
-                        if payload_len > MAX_PAYLOAD_SIZE {
-                            return Poll::Ready(Some(Err(io::Error::new(
-                                io::ErrorKind::InvalidInput,
-                                format!(
-                                    "payload size ({}) exceeds limit ({} bytes)",
-                                    payload_len, MAX_PAYLOAD_SIZE
-                                ),
-                            ))));
-                        }
-                        let data = Bytes::from(s.snappy.decoder.decompress_vec(input)?);
+                        let data = s.snappy.decompress(&val[1..])?;
                         trace!("Decompressed raw message data: {}", hex::encode(&data));
 
                         if message_id < 0x10 {
                             match message_id {
                                 0x01 => {
                                     s.disconnected = true;
-                                    let mut decodable_data = &*data;
-                                    if let Some(prelude) = decodable_data.first() {
-                                        if *prelude == 0xc1 {
-                                            decodable_data.advance(1);
-                                        }
-                                    }
-
-                                    if let Some(reason) = u8::decode(&mut decodable_data)
-                                        .ok()
-                                        .and_then(DisconnectReason::from_u8)
-                                    {
+                                    if let Ok(reason) = DisconnectReason::decode(&mut &*data) {
                                         return Poll::Ready(Some(Ok(PeerMessage::Disconnect(
                                             reason,
                                         ))));
@@ -447,12 +490,9 @@ where
         let (message_id, payload) = match message {
             PeerMessage::Disconnect(reason) => {
                 this.disconnected = true;
-                (
-                    0x01,
-                    fastrlp::encode_fixed_size(&reason.to_u8().unwrap())
-                        .to_vec()
-                        .into(),
-                )
+                let mut out = BytesMut::new();
+                reason.encode(&mut out);
+                (0x01, out.freeze())
             }
             PeerMessage::Ping => {
                 debug!("sending ping message");
@@ -502,25 +542,7 @@ where
         let mut msg = BytesMut::with_capacity(2 + payload.len());
         message_id.encode(&mut msg);
 
-        let mut buf = msg.split_off(msg.len());
-
-        if payload.len() > MAX_PAYLOAD_SIZE {
-            return Err(io::Error::new(
-                io::ErrorKind::InvalidInput,
-                format!(
-                    "payload size ({}) exceeds limit ({} bytes)",
-                    payload.len(),
-                    MAX_PAYLOAD_SIZE
-                ),
-            ));
-        }
-
-        buf.resize(snap::raw::max_compress_len(payload.len()), 0);
-
-        let compressed_len = this.snappy.encoder.compress(&payload, &mut buf).unwrap();
-        buf.truncate(compressed_len);
-
-        msg.unsplit(buf);
+        this.snappy.compress(&payload, &mut msg)?;
 
         Pin::new(&mut this.stream).start_send(msg.freeze())?;
 
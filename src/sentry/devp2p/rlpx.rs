@@ -10,7 +10,7 @@ use super::{
 use anyhow::{anyhow, bail, Context};
 use cidr::IpCidr;
 use educe::Educe;
-use futures::sink::SinkExt;
+use futures::{sink::SinkExt, Stream};
 use lru::LruCache;
 use parking_lot::Mutex;
 use secp256k1::SecretKey;
@@ -18,6 +18,7 @@ use std::{
     collections::{hash_map::Entry, BTreeMap, HashMap},
     fmt::Debug,
     future::Future,
+    io,
     net::SocketAddr,
     num::NonZeroUsize,
     ops::Deref,
@@ -31,7 +32,7 @@ use task_group::TaskGroup;
 use tokio::{
     net::{TcpListener, TcpStream},
     sync::{
-        mpsc::{channel, unbounded_channel},
+        mpsc::{channel, unbounded_channel, Sender},
         oneshot::{channel as oneshot, Sender as OneshotSender},
         OwnedSemaphorePermit, Semaphore,
     },
@@ -113,6 +114,7 @@ struct PeerStreamHandshakeData<C> {
     client_version: String,
     capabilities: Arc<CapabilitySet>,
     capability_server: Arc<C>,
+    ingress_options: IngressOptions,
 }
 
 async fn handle_incoming<TS, C>(
@@ -152,10 +154,67 @@ async fn handle_incoming<TS, C>(
     .await;
 }
 
+/// Reads messages from peer until it disconnects.
+///
+/// Subprotocol messages are queued for the capability server. Once the queue is full the peer is
+/// not read from until it drains, leaving the peer throttled by TCP flow control.
+async fn route_ingress<S>(
+    mut stream: S,
+    ingress_tx: Sender<InboundEvent>,
+    pongs_tx: Sender<()>,
+    pinged: Arc<AtomicBool>,
+) -> DisconnectSignal
+where
+    S: Stream<Item = Result<PeerMessage, io::Error>> + Unpin,
+{
+    while let Some(message) = stream.next().await {
+        match message {
+            Err(e) => {
+                debug!("Peer incoming error: {}", e);
+                break;
+            }
+            Ok(PeerMessage::Subprotocol(SubprotocolMessage { cap_name, message })) => {
+                // Wait for room in the queue, the capability server is gone if it is closed
+                if ingress_tx
+                    .send(InboundEvent::Message {
+                        capability_name: cap_name,
+                        message,
+                    })
+                    .await
+                    .is_err()
+                {
+                    break;
+                }
+            }
+            Ok(PeerMessage::Disconnect(reason)) => {
+                // Peer has requested disconnection.
+                return DisconnectSignal {
+                    initiator: DisconnectInitiator::Remote,
+                    reason,
+                };
+            }
+            Ok(PeerMessage::Ping) => {
+                let _ = pongs_tx.send(()).await;
+            }
+            Ok(PeerMessage::Pong) => {
+                // Pong received, peer is off the hook
+                pinged.store(false, Ordering::SeqCst);
+            }
+        }
+    }
+
+    // Ingress stream is closed, force disconnect the peer.
+    DisconnectSignal {
+        initiator: DisconnectInitiator::Remote,
+        reason: DisconnectReason::DisconnectRequested,
+    }
+}
+
 /// Set up newly connected peer's state, start its tasks
 fn setup_peer_state<C, Io>(
     streams: Weak<Mutex<PeerStreams>>,
     capability_server: Arc<C>,
+    ingress_options: IngressOptions,
     remote_id: PeerId,
     peer: PeerStream<Io>,
 ) -> ConnectedPeerState
@@ -169,7 +228,7 @@ where
         .copied()
         .map(|cap_info| (cap_info.name, cap_info.version))
         .collect::<HashMap<_, _>>();
-    let (mut sink, mut stream) = futures::StreamExt::split(peer);
+    let (mut sink, stream) = futures::StreamExt::split(peer);
     let (peer_disconnect_tx, mut peer_disconnect_rx) = unbounded_channel();
     let tasks = TaskGroup::default();
 
@@ -179,62 +238,35 @@ where
     let (pings_tx, mut pings) = channel(1);
     let (pongs_tx, mut pongs) = channel(1);
 
+    let (ingress_tx, mut ingress_rx) = channel(ingress_options.capacity.get());
+    let (ingress_done_tx, ingress_done_rx) = oneshot();
+
     // This will handle incoming packets from peer.
     tasks.spawn_with_name(format!("peer {} ingress router", remote_id), {
         let peer_disconnect_tx = peer_disconnect_tx.clone();
-        let capability_server = capability_server.clone();
+        let ingress_tx = ingress_tx.clone();
         let pinged = pinged.clone();
         async move {
-            let disconnect_signal = {
-                async move {
-                    while let Some(message) = stream.next().await {
-                        match message {
-                            Err(e) => {
-                                debug!("Peer incoming error: {}", e);
-                                break;
-                            }
-                            Ok(PeerMessage::Subprotocol(SubprotocolMessage {
-                                cap_name,
-                                message,
-                            })) => {
-                                // Actually handle the message
-                                capability_server
-                                    .on_peer_event(
-                                        remote_id,
-                                        InboundEvent::Message {
-                                            capability_name: cap_name,
-                                            message,
-                                        },
-                                    )
-                                    .await
-                            }
-                            Ok(PeerMessage::Disconnect(reason)) => {
-                                // Peer has requested disconnection.
-                                return DisconnectSignal {
-                                    initiator: DisconnectInitiator::Remote,
-                                    reason,
-                                };
-                            }
-                            Ok(PeerMessage::Ping) => {
-                                let _ = pongs_tx.send(()).await;
-                            }
-                            Ok(PeerMessage::Pong) => {
-                                // Pong received, peer is off the hook
-                                pinged.store(false, Ordering::SeqCst);
-                            }
-                        }
-                    }
+            let disconnect_signal = route_ingress(stream, ingress_tx, pongs_tx, pinged).await;
 
-                    // Ingress stream is closed, force disconnect the peer.
-                    DisconnectSignal {
-                        initiator: DisconnectInitiator::Remote,
-                        reason: DisconnectReason::DisconnectRequested,
-                    }
+            let _ = peer_disconnect_tx.send(disconnect_signal);
+        }
+        .instrument(span!(Level::DEBUG, "IN", "peer={}", remote_id.to_string(),))
+    });
+
+    // This will feed queued packets to capability server, disconnect being the last one.
+    tasks.spawn_with_name(format!("peer {} ingress handler", remote_id), {
+        let capability_server = capability_server.clone();
+        async move {
+            while let Some(event) = ingress_rx.recv().await {
+                let disconnect = matches!(event, InboundEvent::Disconnect { .. });
+                capability_server.on_peer_event(remote_id, event).await;
+                if disconnect {
+                    break;
                 }
             }
-            .await;
 
-            let _ = peer_disconnect_tx.send(disconnect_signal);
+            let _ = ingress_done_tx.send(());
         }
         .instrument(span!(Level::DEBUG, "IN", "peer={}", remote_id.to_string(),))
     });
@@ -312,14 +344,16 @@ where
                         // We have sent disconnect message, wait for grace period.
                         sleep(GRACE_PERIOD).await;
                     }
-                    capability_server
-                        .on_peer_event(
-                            remote_id,
-                            InboundEvent::Disconnect {
-                                reason: Some(reason),
-                            },
-                        )
-                        .await;
+                    // Queue disconnect behind pending messages so none of them outlive the peer.
+                    if ingress_tx
+                        .send(InboundEvent::Disconnect {
+                            reason: Some(reason),
+                        })
+                        .await
+                        .is_ok()
+                    {
+                        let _ = ingress_done_rx.await;
+                    }
                     break;
                 }
             }
@@ -390,6 +424,7 @@ async fn handle_incoming_request<C, Io>(
         client_version,
         capabilities,
         capability_server,
+        ingress_options,
         port,
     } = handshake_data;
     // Do handshake and convert incoming connection into stream.
@@ -435,6 +470,7 @@ async fn handle_incoming_request<C, Io>(
                                 connection_state: PeerConnectionState::Connected(setup_peer_state(
                                     Arc::downgrade(&streams),
                                     capability_server,
+                                    ingress_options,
                                     remote_id,
                                     peer,
                                 )),
@@ -506,6 +542,7 @@ pub struct Swarm<C: CapabilityServer> {
     capabilities: Arc<CapabilitySet>,
     #[educe(Debug(ignore))]
     capability_server: Arc<C>,
+    ingress_options: IngressOptions,
 
     #[educe(Debug(ignore))]
     secret_key: SecretKey,
@@ -518,6 +555,7 @@ pub struct Swarm<C: CapabilityServer> {
 pub struct SwarmBuilder {
     task_group: Option<Arc<TaskGroup>>,
     listen_options: Option<ListenOptions>,
+    ingress_options: IngressOptions,
     client_version: String,
 }
 
@@ -532,6 +570,11 @@ impl SwarmBuilder {
         self
     }
 
+    pub fn with_ingress_options(mut self, options: IngressOptions) -> Self {
+        self.ingress_options = options;
+        self
+    }
+
     pub fn with_client_version(mut self, version: String) -> Self {
         self.client_version = version;
         self
@@ -551,6 +594,7 @@ impl SwarmBuilder {
             capability_mask.into(),
             capability_server,
             self.listen_options,
+            self.ingress_options,
         )
         .await
     }
@@ -588,11 +632,27 @@ impl ListenOptions {
     }
 }
 
+/// Per-peer buffering of inbound messages awaiting the capability server.
+#[derive(Clone, Copy, Debug)]
+pub struct IngressOptions {
+    /// Messages queued before reading from the peer is paused.
+    pub capacity: NonZeroUsize,
+}
+
+impl Default for IngressOptions {
+    fn default() -> Self {
+        Self {
+            capacity: NonZeroUsize::new(64).unwrap(),
+        }
+    }
+}
+
 impl Swarm<()> {
     pub fn builder() -> SwarmBuilder {
         SwarmBuilder {
             task_group: None,
             listen_options: None,
+            ingress_options: Default::default(),
             client_version: format!("rust-devp2p/{}", env!("CARGO_PKG_VERSION")),
         }
     }
@@ -617,6 +677,7 @@ impl<C: CapabilityServer> Swarm<C> {
         capabilities: CapabilitySet,
         capability_server: Arc<C>,
         listen_options: Option<ListenOptions>,
+        ingress_options: IngressOptions,
     ) -> anyhow::Result<Arc<Self>> {
         let tasks = task_group.unwrap_or_default();
 
@@ -646,6 +707,7 @@ impl<C: CapabilityServer> Swarm<C> {
                     client_version: client_version.clone(),
                     capabilities: capabilities.clone(),
                     capability_server: capability_server.clone(),
+                    ingress_options,
                 };
 
                 handle_incoming(
@@ -666,6 +728,7 @@ impl<C: CapabilityServer> Swarm<C> {
             node_filter,
             capabilities,
             capability_server,
+            ingress_options,
             secret_key,
             client_version,
             port,
@@ -770,6 +833,7 @@ impl<C: CapabilityServer> Swarm<C> {
 
         let capability_set = self.capabilities.get_capabilities().to_vec();
         let capability_server = self.capability_server.clone();
+        let ingress_options = self.ingress_options;
 
         let secret_key = self.secret_key;
         let client_version = self.client_version.clone();
@@ -886,6 +950,7 @@ impl<C: CapabilityServer> Swarm<C> {
                                 PeerConnectionState::Connected(setup_peer_state(
                                     Arc::downgrade(&streams),
                                     capability_server,
+                                    ingress_options,
                                     remote_id,
                                     peer,
                                 ));
@@ -935,3 +1000,71 @@ impl<C: CapabilityServer> Deref for Swarm<C> {
         &self.capability_server
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use arrayvec::ArrayString;
+    use bytes::Bytes;
+
+    #[tokio::test(start_paused = true)]
+    async fn saturated_ingress_pauses_reads() {
+        const CAPACITY: usize = 2;
+        const MESSAGES: usize = 5;
+
+        let read = Arc::new(AtomicUsize::new(0));
+        let stream = futures::stream::iter((0..MESSAGES).map({
+            let read = read.clone();
+            move |id| {
+                read.fetch_add(1, Ordering::SeqCst);
+                Ok(PeerMessage::Subprotocol(SubprotocolMessage {
+                    cap_name: CapabilityName(ArrayString::from("eth").unwrap()),
+                    message: Message {
+                        id,
+                        data: Bytes::new(),
+                    },
+                }))
+            }
+        }));
+
+        let (ingress_tx, mut ingress_rx) = channel(CAPACITY);
+        let (pongs_tx, _pongs) = channel(1);
+        let router = tokio::spawn(route_ingress(
+            stream,
+            ingress_tx,
+            pongs_tx,
+            Arc::new(AtomicBool::new(false)),
+        ));
+
+        // With the clock paused, sleeps only complete once every task is idle: the router is then
+        // stuck sending to the full queue.
+        let settle = || sleep(Duration::from_millis(50));
+
+        // Queue is full and one more message is held waiting for room
+        settle().await;
+        assert_eq!(read.load(Ordering::SeqCst), CAPACITY + 1);
+
+        // Each drained message lets exactly one more in
+        let mut received = vec![];
+        for expected_read in CAPACITY + 2..=MESSAGES {
+            received.push(ingress_rx.recv().await.unwrap());
+            settle().await;
+            assert_eq!(read.load(Ordering::SeqCst), expected_read);
+        }
+
+        while received.len() < MESSAGES {
+            received.push(ingress_rx.recv().await.unwrap());
+        }
+        let ids = received
+            .into_iter()
+            .map(|event| match event {
+                InboundEvent::Message { message, .. } => message.id,
+                other => panic!("unexpected event {:?}", other),
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(ids, (0..MESSAGES).collect::<Vec<_>>());
+
+        let DisconnectSignal { reason, .. } = router.await.unwrap();
+        assert_eq!(reason, DisconnectReason::DisconnectRequested);
+    }
+}
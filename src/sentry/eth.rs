@@ -1,17 +1,48 @@
-use super::devp2p::*;
-use crate::models::*;
-use anyhow::anyhow;
+use super::devp2p::{Message, *};
+use crate::{
+    genesis::GenesisState,
+    models::*,
+    p2p::types::{GetBlockHeadersParams, NewBlock, NewBlockHashes, TransactionEnvelope},
+    res::chainspec::{GOERLI, MAINNET, RINKEBY, ROPSTEN, SEPOLIA},
+};
+use anyhow::{anyhow, bail, Context};
 use arrayvec::ArrayString;
+use bytes::{Bytes, BytesMut};
 use enum_primitive_derive::*;
-use ethereum_forkid::{ForkFilter, ForkId};
+use ethereum_forkid::{ForkFilter, ForkHash, ForkId};
 use fastrlp::*;
-use std::{collections::BTreeSet, convert::TryFrom};
+use futures::{Sink, SinkExt, Stream, StreamExt};
+use num_traits::FromPrimitive;
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+    convert::TryFrom,
+    fmt::{self, Display, Formatter},
+    marker::PhantomData,
+    str::FromStr,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+use thiserror::Error;
+use tracing::{debug, instrument, warn, Span};
+
+pub use ethereum_forkid::ValidationError as ForkFilterError;
 
 pub fn capability_name() -> CapabilityName {
     CapabilityName(ArrayString::from("eth").unwrap())
 }
 
-#[derive(Clone, Debug, RlpEncodable, RlpDecodable, RlpMaxEncodedLen)]
+/// Upper bound on the difficulty of a genesis block, far above that of any real chain.
+pub const MAX_GENESIS_DIFFICULTY: U256 = U256::new(u64::MAX as u128);
+
+/// Upper bound on the next fork a peer may announce in its fork id.
+///
+/// Forks are scheduled at block numbers or unix timestamps, both of which are orders of magnitude
+/// below this, so anything above comes from a broken or malicious peer.
+pub const MAX_NEXT_FORK: u64 = 1 << 40;
+
+/// Block of the mainnet DAO fork, which Ethereum Classic did not take.
+pub const DAO_FORK_BLOCK: u64 = 1_920_000;
+
+#[derive(Clone, Debug, PartialEq, Eq, RlpEncodable, RlpMaxEncodedLen)]
 pub struct StatusMessage {
     pub protocol_version: usize,
     pub network_id: u64,
@@ -21,29 +52,902 @@ pub struct StatusMessage {
     pub fork_id: ForkId,
 }
 
-#[derive(Clone, Debug)]
+/// Error for a fork id announcing its next fork beyond [`MAX_NEXT_FORK`].
+pub const IMPLAUSIBLE_NEXT_FORK: DecodeError =
+    DecodeError::Custom("fork id announces its next fork beyond any schedulable fork");
+
+impl Decodable for StatusMessage {
+    fn decode(buf: &mut &[u8]) -> Result<Self, DecodeError> {
+        let rlp_head = Header::decode(buf)?;
+        if !rlp_head.list {
+            return Err(DecodeError::UnexpectedString);
+        }
+
+        let started_len = buf.len();
+        let this = Self {
+            protocol_version: Decodable::decode(buf)?,
+            network_id: Decodable::decode(buf)?,
+            total_difficulty: Decodable::decode(buf)?,
+            best_hash: Decodable::decode(buf)?,
+            genesis_hash: Decodable::decode(buf)?,
+            fork_id: Decodable::decode(buf)?,
+        };
+
+        let consumed = started_len - buf.len();
+        if consumed != rlp_head.payload_length {
+            return Err(DecodeError::ListLengthMismatch {
+                expected: rlp_head.payload_length,
+                got: consumed,
+            });
+        }
+
+        if this.fork_id.next > MAX_NEXT_FORK {
+            return Err(IMPLAUSIBLE_NEXT_FORK);
+        }
+
+        Ok(this)
+    }
+}
+
+impl StatusMessage {
+    pub fn builder() -> StatusMessageBuilder {
+        StatusMessageBuilder::default()
+    }
+
+    /// Decodes the status, reporting which field is malformed rather than a bare RLP error.
+    ///
+    /// Hashes are checked to be exactly 32 bytes long before being copied out of the input.
+    pub fn decode_validated(data: &[u8]) -> anyhow::Result<Self> {
+        fn field<T: Decodable>(buf: &mut &[u8], name: &str) -> anyhow::Result<T> {
+            T::decode(buf).map_err(|e| anyhow!("{}: {}", name, e))
+        }
+
+        fn hash_field(buf: &mut &[u8], name: &str) -> anyhow::Result<H256> {
+            let h = Header::decode(buf).map_err(|e| anyhow!("{}: {}", name, e))?;
+            if h.list {
+                bail!("{}: expected a string, got a list", name);
+            }
+            if h.payload_length != KECCAK_LENGTH {
+                bail!(
+                    "{}: expected {} bytes, got {}",
+                    name,
+                    KECCAK_LENGTH,
+                    h.payload_length
+                );
+            }
+            if buf.len() < KECCAK_LENGTH {
+                bail!("{}: input too short", name);
+            }
+
+            let hash = H256::from_slice(&buf[..KECCAK_LENGTH]);
+            *buf = &buf[KECCAK_LENGTH..];
+            Ok(hash)
+        }
+
+        let buf = &mut &*data;
+        let h = Header::decode(buf).map_err(|e| anyhow!("status: {}", e))?;
+        if !h.list {
+            bail!("status: expected a list");
+        }
+        if buf.len() < h.payload_length {
+            bail!("status: input too short");
+        }
+        if buf.len() > h.payload_length {
+            bail!(
+                "status: {} trailing bytes after list",
+                buf.len() - h.payload_length
+            );
+        }
+        let payload = &mut &buf[..h.payload_length];
+
+        let status = Self {
+            protocol_version: field(payload, "protocol_version")?,
+            network_id: field(payload, "network_id")?,
+            total_difficulty: field(payload, "total_difficulty")?,
+            best_hash: hash_field(payload, "best_hash")?,
+            genesis_hash: hash_field(payload, "genesis_hash")?,
+            fork_id: field(payload, "fork_id")?,
+        };
+        if !payload.is_empty() {
+            bail!("status: {} trailing bytes in list", payload.len());
+        }
+        if status.fork_id.next > MAX_NEXT_FORK {
+            bail!("fork_id: {}", IMPLAUSIBLE_NEXT_FORK);
+        }
+
+        Ok(status)
+    }
+
+    /// Checks the status for claims that contradict each other, regardless of our chain.
+    ///
+    /// Only the `advertised` protocol versions could have been negotiated, so a status speaking
+    /// any other is not taken at its word.
+    pub fn sanity_check(&self, advertised: &[EthProtocolVersion]) -> Result<(), StatusSanityError> {
+        if !advertised
+            .iter()
+            .any(|&version| version as usize == self.protocol_version)
+        {
+            return Err(StatusSanityError::UnsupportedProtocolVersion(
+                self.protocol_version,
+            ));
+        }
+
+        // Only the genesis block counts towards the total difficulty of a peer still at genesis
+        if self.best_hash == self.genesis_hash && self.total_difficulty > MAX_GENESIS_DIFFICULTY {
+            return Err(StatusSanityError::DifficultyAtGenesis(
+                self.total_difficulty,
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// Check that the peer's status speaks the protocol version negotiated for the connection.
+    pub fn validate_against(&self, negotiated: EthProtocolVersion) -> Result<(), HandshakeError> {
+        if self.protocol_version != negotiated as usize {
+            return Err(HandshakeError::ProtocolVersionMismatch {
+                negotiated: negotiated as usize,
+                theirs: self.protocol_version,
+            });
+        }
+
+        self.sanity_check(&[negotiated])
+            .map_err(HandshakeError::ContradictoryStatus)
+    }
+}
+
+/// [`ForkId`] in its textual `hash/next` form, e.g. `fc64ec04/1150000`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ForkIdText(pub ForkId);
+
+impl From<ForkId> for ForkIdText {
+    fn from(fork_id: ForkId) -> Self {
+        Self(fork_id)
+    }
+}
+
+impl From<ForkIdText> for ForkId {
+    fn from(text: ForkIdText) -> Self {
+        text.0
+    }
+}
+
+impl Display for ForkIdText {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", hex::encode(self.0.hash.0), self.0.next)
+    }
+}
+
+impl FromStr for ForkIdText {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        let (hash, next) = s
+            .split_once('/')
+            .ok_or_else(|| anyhow!("fork id {:?} is not of the form hash/next", s))?;
+
+        let mut fork_hash = [0; 4];
+        hex::decode_to_slice(hash, &mut fork_hash)
+            .with_context(|| format!("invalid fork hash {:?}", hash))?;
+        let next = next
+            .parse()
+            .with_context(|| format!("invalid next fork {:?}", next))?;
+
+        Ok(Self(ForkId {
+            hash: ForkHash(fork_hash),
+            next,
+        }))
+    }
+}
+
+/// Builder for [`StatusMessage`] that refuses to build until every field has been set.
+///
+/// ```
+/// use akula::{models::*, sentry::eth::StatusMessage};
+/// use ethereum_forkid::{ForkHash, ForkId};
+/// use hex_literal::hex;
+///
+/// let genesis = H256(hex!("d4e56740f876aef8c010b86a40d5f56745a118d0906a34e69aec8c0db1cb8fa3"));
+/// let status = StatusMessage::builder()
+///     .protocol_version(66)
+///     .network_id(1)
+///     .total_difficulty(U256::from(17_179_869_184_u64))
+///     .best_hash(genesis)
+///     .genesis_hash(genesis)
+///     .fork_id(ForkId {
+///         hash: ForkHash(hex!("fc64ec04")),
+///         next: 1_150_000,
+///     })
+///     .build()
+///     .unwrap();
+/// assert_eq!(status.network_id, 1);
+///
+/// assert!(StatusMessage::builder().network_id(1).build().is_err());
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct StatusMessageBuilder {
+    protocol_version: Option<usize>,
+    network_id: Option<u64>,
+    total_difficulty: Option<U256>,
+    best_hash: Option<H256>,
+    genesis_hash: Option<H256>,
+    fork_id: Option<ForkId>,
+}
+
+impl StatusMessageBuilder {
+    pub fn protocol_version(mut self, protocol_version: usize) -> Self {
+        self.protocol_version = Some(protocol_version);
+        self
+    }
+
+    pub fn network_id(mut self, network_id: u64) -> Self {
+        self.network_id = Some(network_id);
+        self
+    }
+
+    pub fn total_difficulty(mut self, total_difficulty: U256) -> Self {
+        self.total_difficulty = Some(total_difficulty);
+        self
+    }
+
+    pub fn best_hash(mut self, best_hash: H256) -> Self {
+        self.best_hash = Some(best_hash);
+        self
+    }
+
+    pub fn genesis_hash(mut self, genesis_hash: H256) -> Self {
+        self.genesis_hash = Some(genesis_hash);
+        self
+    }
+
+    pub fn fork_id(mut self, fork_id: ForkId) -> Self {
+        self.fork_id = Some(fork_id);
+        self
+    }
+
+    pub fn build(self) -> anyhow::Result<StatusMessage> {
+        Ok(StatusMessage {
+            protocol_version: self
+                .protocol_version
+                .ok_or_else(|| anyhow!("no protocol version"))?,
+            network_id: self.network_id.ok_or_else(|| anyhow!("no network id"))?,
+            total_difficulty: self
+                .total_difficulty
+                .ok_or_else(|| anyhow!("no total difficulty"))?,
+            best_hash: self.best_hash.ok_or_else(|| anyhow!("no best hash"))?,
+            genesis_hash: self
+                .genesis_hash
+                .ok_or_else(|| anyhow!("no genesis hash"))?,
+            fork_id: self.fork_id.ok_or_else(|| anyhow!("no fork id"))?,
+        })
+    }
+}
+
+/// Incrementally computes the EIP-2124 fork hash, starting from the genesis hash.
+#[derive(Clone, Copy, Debug)]
+pub struct ForkHashBuilder(ForkHash);
+
+impl ForkHashBuilder {
+    pub fn new(genesis: H256) -> Self {
+        Self(ForkHash::from(genesis))
+    }
+
+    /// Folds in a passed fork, given by its block number or timestamp.
+    pub fn add(&mut self, fork: u64) -> &mut Self {
+        self.0 += fork;
+        self
+    }
+
+    pub fn build(&self) -> ForkHash {
+        self.0
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
 pub struct Forks {
     pub genesis: H256,
+    /// Block numbers of forks.
     pub forks: BTreeSet<u64>,
+    /// Activation timestamps of post-Merge forks, which always follow block forks.
+    pub time_forks: BTreeSet<u64>,
 }
 
-#[derive(Clone, Debug)]
+impl Forks {
+    /// Fork schedule of the chain specification.
+    ///
+    /// Chain specifications only schedule forks by block number, so no timestamp forks are set.
+    pub fn from_chain_spec(spec: &ChainSpec) -> anyhow::Result<Self> {
+        let genesis = GenesisState::new(spec.clone());
+        let forks = Self {
+            genesis: genesis.header(&genesis.initial_state()).hash(),
+            forks: spec.gather_forks().into_iter().map(|fork| *fork).collect(),
+            time_forks: BTreeSet::new(),
+        };
+        forks.validate()?;
+
+        Ok(forks)
+    }
+
+    /// Checks that the schedule can be told apart from genesis and announced to peers.
+    ///
+    /// Sets keep forks ordered and unique, but cannot catch timestamp forks mixed up with block
+    /// forks or scheduled at genesis.
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if self.genesis.is_zero() {
+            bail!("fork schedule has no genesis hash");
+        }
+
+        if let Some(&fork) = self
+            .forks
+            .iter()
+            .chain(&self.time_forks)
+            .find(|&&fork| fork > MAX_NEXT_FORK)
+        {
+            bail!("fork at {} is beyond any schedulable fork", fork);
+        }
+
+        if self.time_forks.contains(&0) {
+            bail!("timestamp fork scheduled at genesis");
+        }
+
+        if let (Some(&last_block_fork), Some(&first_time_fork)) =
+            (self.forks.iter().next_back(), self.time_forks.iter().next())
+        {
+            if first_time_fork <= last_block_fork {
+                bail!(
+                    "timestamp fork at {} does not follow block fork at {}",
+                    first_time_fork,
+                    last_block_fork
+                );
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Whether the remote went past the DAO fork of our schedule without taking it, as
+    /// Ethereum Classic peers do.
+    ///
+    /// Such a peer keeps announcing the fork hash preceding the DAO fork, with a later next fork.
+    /// The fork filter accepts it while our head is below the DAO fork.
+    pub fn is_dao_incompatible(&self, remote: ForkId) -> bool {
+        if !self.forks.contains(&DAO_FORK_BLOCK) {
+            return false;
+        }
+
+        let mut hash = ForkHashBuilder::new(self.genesis);
+        for &fork in self.forks.range(1..DAO_FORK_BLOCK) {
+            hash.add(fork);
+        }
+
+        remote.hash == hash.build() && remote.next > DAO_FORK_BLOCK
+    }
+
+    /// Schedule of a private chain, identified by its custom genesis alone.
+    pub fn private(genesis: H256) -> Self {
+        Self {
+            genesis,
+            forks: BTreeSet::new(),
+            time_forks: BTreeSet::new(),
+        }
+    }
+
+    /// Fork ID of the chain at given head, as per EIP-2124 and EIP-6122.
+    pub fn current_fork_id(&self, head: u64, head_timestamp: u64) -> ForkId {
+        let mut hash = ForkHashBuilder::new(self.genesis);
+        for (fork, passed) in self
+            .forks
+            .iter()
+            .map(|&fork| (fork, fork <= head))
+            .chain(
+                self.time_forks
+                    .iter()
+                    .map(|&fork| (fork, fork <= head_timestamp)),
+            )
+            .filter(|&(fork, _)| fork != 0)
+        {
+            if !passed {
+                return ForkId {
+                    hash: hash.build(),
+                    next: fork,
+                };
+            }
+            hash.add(fork);
+        }
+
+        ForkId {
+            hash: hash.build(),
+            next: 0,
+        }
+    }
+
+    /// Whether the fork hash is the one of our schedule at the given head.
+    ///
+    /// Stricter than fork filter validation, which accepts any hash along our schedule. The head
+    /// timestamp is unknown, so the hash may include any number of timestamp forks once all block
+    /// forks are passed.
+    pub fn matches_fork_hash(&self, head: u64, remote_hash: ForkHash) -> bool {
+        let mut hash = ForkHashBuilder::new(self.genesis);
+        for &fork in self.forks.iter().filter(|&&fork| fork != 0) {
+            if fork > head {
+                return hash.build() == remote_hash;
+            }
+            hash.add(fork);
+        }
+
+        if hash.build() == remote_hash {
+            return true;
+        }
+        self.time_forks
+            .iter()
+            .filter(|&&fork| fork != 0)
+            .any(|&fork| hash.add(fork).build() == remote_hash)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
 pub struct StatusData {
     pub network_id: u64,
     pub total_difficulty: U256,
     pub best_hash: H256,
     pub fork_data: Forks,
+    /// Head our fork id is computed at, as announced by the sync side.
+    pub max_block: u64,
+}
+
+/// Consensus the chain is under, deciding how sync targets are compared.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ChainMode {
+    /// Peers are ranked by total difficulty.
+    PoW,
+    /// Total difficulty is frozen since the Merge, so peers are ranked by the height they
+    /// announced since the handshake.
+    PoS,
+}
+
+impl ChainMode {
+    /// Mode of the chain at the given total difficulty: proof-of-stake once it reached the
+    /// terminal total difficulty of the Merge, if the chain schedules one.
+    pub fn at(spec: &ChainSpec, total_difficulty: U256) -> Self {
+        match terminal_total_difficulty(spec) {
+            Some(terminal_total_difficulty) if total_difficulty >= terminal_total_difficulty => {
+                Self::PoS
+            }
+            _ => Self::PoW,
+        }
+    }
+}
+
+impl StatusData {
+    /// Whether this status makes a better sync target, by total difficulty and then by best hash
+    /// so that ties resolve the same way everywhere.
+    pub fn is_better_than(&self, other: &StatusData) -> bool {
+        (self.total_difficulty, self.best_hash) > (other.total_difficulty, other.best_hash)
+    }
+}
+
+/// Best sync target among the peers' statuses, see [`StatusData::is_better_than`].
+pub fn select_best<'a>(peers: impl Iterator<Item = &'a StatusData>) -> Option<&'a StatusData> {
+    peers.fold(None, |best, status| match best {
+        Some(best) if !status.is_better_than(best) => Some(best),
+        _ => Some(status),
+    })
 }
 
 #[derive(Clone, Debug)]
 pub struct FullStatusData {
     pub status: StatusData,
     pub fork_filter: ForkFilter,
+    /// Highest total difficulty a peer may claim, unbounded if `None`.
+    pub max_total_difficulty: Option<U256>,
+}
+
+/// Claims of a status message that cannot all be true.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Error)]
+pub enum StatusSanityError {
+    #[error("protocol version {0} is not one we advertise")]
+    UnsupportedProtocolVersion(usize),
+    #[error("total difficulty {0} claimed at genesis")]
+    DifficultyAtGenesis(U256),
+}
+
+#[derive(Debug, Error)]
+pub enum HandshakeError {
+    #[error("genesis mismatch (ours: {ours:?}, theirs: {theirs:?})")]
+    GenesisMismatch { ours: H256, theirs: H256 },
+    #[error("network ID mismatch (ours: {ours}, theirs: {theirs})")]
+    NetworkIdMismatch { ours: u64, theirs: u64 },
+    #[error("status protocol version {theirs} does not match negotiated eth/{negotiated}")]
+    ProtocolVersionMismatch { negotiated: usize, theirs: usize },
+    /// Peer has not caught up with forks we have passed, but shares our history.
+    #[error("peer is behind our forks: {0:?}")]
+    StaleFork(ForkFilterError),
+    #[error("incompatible fork ID: {0:?}")]
+    IncompatibleFork(ForkFilterError),
+    #[error("expected status message, got message {0}")]
+    UnexpectedMessage(usize),
+    #[error("malformed status message: {0}")]
+    MalformedStatus(DecodeError),
+    #[error("contradictory status message: {0}")]
+    ContradictoryStatus(StatusSanityError),
+    #[error("no status message received within {0:?}")]
+    Timeout(Duration),
+    #[error("peer did not take the DAO fork: {0:?}")]
+    DaoForkRejected(ForkId),
+    #[error("total difficulty {theirs} is above the {max} possible on this network")]
+    ImplausibleTotalDifficulty { max: U256, theirs: U256 },
+}
+
+impl HandshakeError {
+    /// Reason to disconnect the peer with.
+    pub const fn disconnect_reason(&self) -> DisconnectReason {
+        match self {
+            Self::ProtocolVersionMismatch { .. }
+            | Self::UnexpectedMessage(_)
+            | Self::MalformedStatus(_)
+            | Self::ContradictoryStatus(_)
+            | Self::ImplausibleTotalDifficulty { .. } => DisconnectReason::ProtocolBreach,
+            Self::GenesisMismatch { .. }
+            | Self::NetworkIdMismatch { .. }
+            | Self::StaleFork(_)
+            | Self::IncompatibleFork(_)
+            | Self::DaoForkRejected(_) => DisconnectReason::UselessPeer,
+            Self::Timeout(_) => DisconnectReason::PingTimeout,
+        }
+    }
+
+    /// Whether the peer must be disconnected, rather than kept around to be served.
+    pub const fn is_fatal(&self) -> bool {
+        !matches!(self, Self::StaleFork(_))
+    }
+}
+
+impl From<ForkFilterError> for HandshakeError {
+    fn from(e: ForkFilterError) -> Self {
+        match e {
+            ForkFilterError::RemoteStale { .. } => Self::StaleFork(e),
+            ForkFilterError::LocalIncompatibleOrStale { .. } => Self::IncompatibleFork(e),
+        }
+    }
+}
+
+/// Default number of peers that must announce a fork we do not know of before we report that
+/// our node is stale.
+pub const DEFAULT_LOCAL_STALE_THRESHOLD: usize = 5;
+/// Number of unknown forks followed at once, so that peers cannot make the monitor grow without
+/// bound by announcing made-up forks.
+pub const MAX_CANDIDATE_FORKS: usize = 8;
+
+/// Observation about our own fork schedule, as opposed to that of a single peer.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ForkEvent {
+    /// Peers sharing our fork hash announce an upcoming fork missing from our schedule, so the
+    /// node probably needs an upgrade to stay on the chain.
+    LocalStale { next_fork_block: u64 },
+}
+
+/// Collects the next forks announced by peers, to tell when our own schedule falls behind.
+#[derive(Debug)]
+pub struct ForkMonitor {
+    threshold: usize,
+    /// Peers announcing each fork unknown to us, at most [`MAX_CANDIDATE_FORKS`] of them.
+    announcers: HashMap<u64, HashSet<PeerId>>,
+    /// Forks already reported, so that each is reported once.
+    reported: HashSet<u64>,
+}
+
+impl Default for ForkMonitor {
+    fn default() -> Self {
+        Self::new(DEFAULT_LOCAL_STALE_THRESHOLD)
+    }
+}
+
+impl ForkMonitor {
+    pub fn new(threshold: usize) -> Self {
+        Self {
+            threshold,
+            announcers: Default::default(),
+            reported: Default::default(),
+        }
+    }
+
+    /// Records the fork id a peer announced against our fork id at `head`, returning an event the
+    /// first time enough distinct peers announce the same fork we do not know of.
+    ///
+    /// Only peers on our fork hash are counted: a next fork other than ours, and before it, means
+    /// the peer's client schedules a fork that ours does not. A later one just means the peer
+    /// does not know about our next fork, and one we are already past cannot be on our chain.
+    pub fn observe(
+        &mut self,
+        peer: PeerId,
+        head: u64,
+        ours: ForkId,
+        remote: ForkId,
+    ) -> Option<ForkEvent> {
+        let next = remote.next;
+        if remote.hash != ours.hash
+            || next <= head
+            || (ours.next != 0 && next >= ours.next)
+            || self.reported.contains(&next)
+        {
+            return None;
+        }
+        if !self.announcers.contains_key(&next) && self.announcers.len() >= MAX_CANDIDATE_FORKS {
+            return None;
+        }
+
+        let announcers = self.announcers.entry(next).or_default();
+        announcers.insert(peer);
+        if announcers.len() < self.threshold {
+            return None;
+        }
+
+        self.announcers.remove(&next);
+        self.reported.insert(next);
+        Some(ForkEvent::LocalStale {
+            next_fork_block: next,
+        })
+    }
+
+    /// Stops counting the peer's announcements, to be called once it disconnects.
+    pub fn remove_peer(&mut self, peer: PeerId) {
+        self.announcers.retain(|_, announcers| {
+            announcers.remove(&peer);
+            !announcers.is_empty()
+        });
+    }
+}
+
+/// Time a peer has to complete the handshake by default.
+pub const DEFAULT_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// [`perform_handshake_with_timeout`] with [`DEFAULT_HANDSHAKE_TIMEOUT`].
+pub async fn perform_handshake<S>(
+    stream: &mut S,
+    our_status: &FullStatusData,
+    peer: PeerId,
+    negotiated: EthProtocolVersion,
+) -> anyhow::Result<StatusMessage>
+where
+    S: Stream<Item = anyhow::Result<Message>> + Sink<Message, Error = anyhow::Error> + Unpin,
+{
+    perform_handshake_with_timeout(
+        stream,
+        our_status,
+        peer,
+        negotiated,
+        DEFAULT_HANDSHAKE_TIMEOUT,
+    )
+    .await
+}
+
+/// Exchanges status messages with the peer and validates its status against ours.
+///
+/// Validation failures are returned as [`HandshakeError`], as is the peer not sending its status
+/// within `handshake_timeout`. The handshake span records whether the peer was accepted and, on
+/// failure, the reason to disconnect it with.
+#[instrument(
+    skip_all,
+    fields(
+        %peer,
+        version = %negotiated,
+        outcome = tracing::field::Empty,
+        disconnect_reason = tracing::field::Empty,
+    )
+)]
+pub async fn perform_handshake_with_timeout<S>(
+    stream: &mut S,
+    our_status: &FullStatusData,
+    peer: PeerId,
+    negotiated: EthProtocolVersion,
+    handshake_timeout: Duration,
+) -> anyhow::Result<StatusMessage>
+where
+    S: Stream<Item = anyhow::Result<Message>> + Sink<Message, Error = anyhow::Error> + Unpin,
+{
+    let res = tokio::time::timeout(
+        handshake_timeout,
+        exchange_status(stream, our_status, negotiated),
+    )
+    .await
+    .unwrap_or_else(|_| Err(HandshakeError::Timeout(handshake_timeout).into()));
+
+    let span = Span::current();
+    match &res {
+        Ok(_) => {
+            span.record("outcome", "accepted");
+        }
+        Err(e) => {
+            span.record("outcome", "rejected");
+            if let Some(e) = e.downcast_ref::<HandshakeError>() {
+                span.record(
+                    "disconnect_reason",
+                    tracing::field::display(e.disconnect_reason()),
+                );
+            }
+            debug!("Handshake failed: {}", e);
+        }
+    }
+
+    res
+}
+
+async fn exchange_status<S>(
+    stream: &mut S,
+    our_status: &FullStatusData,
+    negotiated: EthProtocolVersion,
+) -> anyhow::Result<StatusMessage>
+where
+    S: Stream<Item = anyhow::Result<Message>> + Sink<Message, Error = anyhow::Error> + Unpin,
+{
+    stream
+        .send(Message {
+            id: EthMessageId::Status as usize,
+            data: fastrlp::encode_fixed_size(&our_status.to_status_message(negotiated))
+                .to_vec()
+                .into(),
+        })
+        .await?;
+
+    let message = stream
+        .next()
+        .await
+        .ok_or_else(|| anyhow!("peer disconnected during handshake"))??;
+    if message.id != EthMessageId::Status as usize {
+        return Err(HandshakeError::UnexpectedMessage(message.id).into());
+    }
+
+    let status =
+        decode_strict::<StatusMessage>(&message.data).map_err(HandshakeError::MalformedStatus)?;
+    status.validate_against(negotiated)?;
+    our_status.validate_peer_status(&status)?;
+
+    Ok(status)
+}
+
+pub fn check_network_id(ours: u64, theirs: u64) -> Result<(), HandshakeError> {
+    if ours != theirs {
+        return Err(HandshakeError::NetworkIdMismatch { ours, theirs });
+    }
+
+    Ok(())
+}
+
+pub fn check_genesis(ours: H256, theirs: H256) -> Result<(), HandshakeError> {
+    if ours != theirs {
+        return Err(HandshakeError::GenesisMismatch { ours, theirs });
+    }
+
+    Ok(())
+}
+
+impl FullStatusData {
+    /// Status with the fork filter set up at its head.
+    pub fn new(status: StatusData) -> Self {
+        // Fork filter only tracks head block, so it always treats timestamp forks as upcoming.
+        let fork_filter = ForkFilter::new(
+            status.max_block,
+            status.fork_data.genesis,
+            status
+                .fork_data
+                .forks
+                .iter()
+                .chain(&status.fork_data.time_forks)
+                .copied()
+                .collect::<Vec<_>>(),
+        );
+
+        Self {
+            max_total_difficulty: known_max_total_difficulty(status.network_id),
+            status,
+            fork_filter,
+        }
+    }
+
+    /// Overrides the total difficulty bound of the network.
+    pub fn with_max_total_difficulty(mut self, max_total_difficulty: Option<U256>) -> Self {
+        self.max_total_difficulty = max_total_difficulty;
+        self
+    }
+
+    /// Validate remote peer's fork ID against our fork schedule, as per EIP-2124.
+    pub fn check_peer_fork(&self, remote: ForkId) -> Result<(), ForkFilterError> {
+        self.fork_filter.validate(remote)
+    }
+
+    /// Check that the peer's status describes the same network and a compatible chain.
+    pub fn validate_peer_status(&self, status: &StatusMessage) -> Result<(), HandshakeError> {
+        check_network_id(self.status.network_id, status.network_id)?;
+        check_genesis(self.status.fork_data.genesis, status.genesis_hash)?;
+        if let Some(max) = self.max_total_difficulty {
+            if status.total_difficulty > max {
+                return Err(HandshakeError::ImplausibleTotalDifficulty {
+                    max,
+                    theirs: status.total_difficulty,
+                });
+            }
+        }
+        if self.status.fork_data.is_dao_incompatible(status.fork_id) {
+            return Err(HandshakeError::DaoForkRejected(status.fork_id));
+        }
+        Ok(self.check_peer_fork(status.fork_id)?)
+    }
+
+    /// Moves our head, returning whether it crossed a fork and changed our fork id.
+    ///
+    /// The fork filter is reused across heads, and only recomputes its state at fork boundaries.
+    pub fn refresh(&mut self, head: u64) -> bool {
+        self.status.max_block = head;
+
+        let before = self.fork_filter.current();
+        self.fork_filter.set_head(head);
+        self.fork_filter.current() != before
+    }
+
+    /// Takes over a status announced by the sync side. The fork filter is only rebuilt if the
+    /// network or the fork schedule changed, otherwise it is [refreshed](Self::refresh).
+    pub fn update(&mut self, status: StatusData) {
+        if status.network_id != self.status.network_id || status.fork_data != self.status.fork_data
+        {
+            *self = Self::new(status);
+            return;
+        }
+
+        let head = status.max_block;
+        self.status = status;
+        self.refresh(head);
+    }
+
+    /// Time left until the next fork of our fork id activates, at the given block rate.
+    ///
+    /// Timestamp forks are counted down on the wall clock instead. `None` if no fork is
+    /// scheduled, the block rate is not positive, or the estimate does not fit a [`Duration`].
+    pub fn estimate_next_fork_eta(
+        &self,
+        current_block: u64,
+        blocks_per_sec: f64,
+    ) -> Option<Duration> {
+        let next = self.fork_filter.current().next;
+        if next == 0 {
+            return None;
+        }
+
+        if self.status.fork_data.time_forks.contains(&next) {
+            let now = SystemTime::now().duration_since(UNIX_EPOCH).ok()?;
+            return Some(Duration::from_secs(next).saturating_sub(now));
+        }
+
+        if !(blocks_per_sec.is_finite() && blocks_per_sec > 0.0) {
+            return None;
+        }
+
+        Duration::try_from_secs_f64(next.saturating_sub(current_block) as f64 / blocks_per_sec).ok()
+    }
+
+    /// Status message we announce to a peer that negotiated given protocol version.
+    pub fn to_status_message(&self, protocol_version: EthProtocolVersion) -> StatusMessage {
+        StatusMessage {
+            protocol_version: protocol_version as usize,
+            network_id: self.status.network_id,
+            total_difficulty: self.status.total_difficulty,
+            best_hash: self.status.best_hash,
+            genesis_hash: self.status.fork_data.genesis,
+            fork_id: self.fork_filter.current(),
+        }
+    }
 }
 
 impl TryFrom<ethereum_interfaces::sentry::StatusData> for FullStatusData {
     type Error = anyhow::Error;
 
+    fn try_from(value: ethereum_interfaces::sentry::StatusData) -> Result<Self, Self::Error> {
+        StatusData::try_from(value).map(Self::new)
+    }
+}
+
+impl TryFrom<ethereum_interfaces::sentry::StatusData> for StatusData {
+    type Error = anyhow::Error;
+
     fn try_from(value: ethereum_interfaces::sentry::StatusData) -> Result<Self, Self::Error> {
         let ethereum_interfaces::sentry::StatusData {
             network_id,
@@ -59,27 +963,33 @@ impl TryFrom<ethereum_interfaces::sentry::StatusData> for FullStatusData {
             .ok_or_else(|| anyhow!("no genesis"))?
             .into();
 
-        let fork_filter = ForkFilter::new(max_block, genesis, fork_data.forks.clone());
-        let status = StatusData {
+        let fork_count = fork_data.forks.len();
+        let fork_data = Forks {
+            genesis,
+            forks: fork_data.forks.into_iter().collect(),
+            time_forks: BTreeSet::new(),
+        };
+        if fork_data.forks.len() != fork_count {
+            warn!(
+                "Collapsed {} duplicate forks in fork schedule",
+                fork_count - fork_data.forks.len()
+            );
+        }
+        fork_data.validate()?;
+
+        Ok(Self {
             network_id,
             total_difficulty: total_difficulty
                 .ok_or_else(|| anyhow!("no total difficulty"))?
                 .into(),
             best_hash: best_hash.ok_or_else(|| anyhow!("no best hash"))?.into(),
-            fork_data: Forks {
-                genesis,
-                forks: fork_data.forks.into_iter().collect(),
-            },
-        };
-
-        Ok(Self {
-            status,
-            fork_filter,
+            fork_data,
+            max_block,
         })
     }
 }
 
-#[derive(Clone, Copy, Debug, Primitive)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Primitive)]
 pub enum EthMessageId {
     Status = 0,
     NewBlockHashes = 1,
@@ -98,8 +1008,3028 @@ pub enum EthMessageId {
     Receipts = 16,
 }
 
-#[derive(Clone, Copy, Debug, Primitive)]
+/// Message id not defined by any eth protocol version.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Error)]
+#[error("unknown eth message id {0:#04x}")]
+pub struct UnknownMessageId(pub u8);
+
+impl TryFrom<u8> for EthMessageId {
+    type Error = UnknownMessageId;
+
+    fn try_from(id: u8) -> Result<Self, Self::Error> {
+        Self::from_u8(id).ok_or(UnknownMessageId(id))
+    }
+}
+
+impl From<EthMessageId> for u8 {
+    fn from(id: EthMessageId) -> Self {
+        id.as_id()
+    }
+}
+
+impl Display for EthMessageId {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?} ({:#04x})", self, *self as u8)
+    }
+}
+
+impl EthMessageId {
+    pub fn try_from_id(id: u8) -> Result<Self, UnknownMessageId> {
+        Self::try_from(id)
+    }
+
+    pub const fn as_id(self) -> u8 {
+        self as u8
+    }
+
+    /// Id of the message answering this request, `None` if this is not a request.
+    pub const fn response_id(self) -> Option<Self> {
+        Some(match self {
+            Self::GetBlockHeaders => Self::BlockHeaders,
+            Self::GetBlockBodies => Self::BlockBodies,
+            Self::GetPooledTransactions => Self::PooledTransactions,
+            Self::GetNodeData => Self::NodeData,
+            Self::GetReceipts => Self::Receipts,
+            _ => return None,
+        })
+    }
+
+    /// Whether this message answers a request.
+    pub const fn is_response(self) -> bool {
+        matches!(
+            self,
+            Self::BlockHeaders
+                | Self::BlockBodies
+                | Self::PooledTransactions
+                | Self::NodeData
+                | Self::Receipts
+        )
+    }
+
+    /// Whether this message is part of the given protocol version.
+    pub const fn is_valid_for(self, version: EthProtocolVersion) -> bool {
+        match self {
+            Self::GetNodeData | Self::NodeData => {
+                (version as usize) < EthProtocolVersion::Eth67 as usize
+            }
+            _ => true,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Primitive)]
 pub enum EthProtocolVersion {
     Eth65 = 65,
     Eth66 = 66,
+    Eth67 = 67,
+    Eth68 = 68,
+}
+
+impl Display for EthProtocolVersion {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "eth/{}", *self as u8)
+    }
+}
+
+/// Eth protocol versions advertised during capability negotiation, lowest first.
+///
+/// eth/65 is left out unless the `eth65` feature is enabled, since sentry clients expect request
+/// ids in requests and responses. The variant is kept either way so that recorded eth/65 traffic
+/// can still be decoded.
+#[cfg(not(feature = "eth65"))]
+pub const SUPPORTED_PROTOCOL_VERSIONS: &[EthProtocolVersion] =
+    &[EthProtocolVersion::Eth66, EthProtocolVersion::Eth67];
+/// Eth protocol versions advertised during capability negotiation, lowest first.
+#[cfg(feature = "eth65")]
+pub const SUPPORTED_PROTOCOL_VERSIONS: &[EthProtocolVersion] = &[
+    EthProtocolVersion::Eth65,
+    EthProtocolVersion::Eth66,
+    EthProtocolVersion::Eth67,
+];
+
+/// Number of message ids reserved by the eth capability.
+pub const ETH_MESSAGE_ID_SPACE: CapabilityLength = EthMessageId::Receipts as usize + 1;
+
+/// Eth capabilities for the devp2p hello message.
+pub fn supported_capabilities() -> BTreeMap<CapabilityId, CapabilityLength> {
+    supported_capabilities_named(capability_name())
+}
+
+/// Supported eth protocol versions, advertised under the given capability name.
+pub fn supported_capabilities_named(
+    name: CapabilityName,
+) -> BTreeMap<CapabilityId, CapabilityLength> {
+    SUPPORTED_PROTOCOL_VERSIONS
+        .iter()
+        .map(|&version| {
+            (
+                CapabilityId {
+                    name,
+                    version: version as CapabilityVersion,
+                },
+                ETH_MESSAGE_ID_SPACE,
+            )
+        })
+        .collect()
+}
+
+/// Picks the highest protocol version supported by both sides.
+pub fn negotiate_protocol_version(
+    local: &[EthProtocolVersion],
+    remote: impl IntoIterator<Item = CapabilityVersion>,
+) -> Option<EthProtocolVersion> {
+    remote
+        .into_iter()
+        .filter(|&version| local.iter().any(|&v| v as CapabilityVersion == version))
+        .max()
+        .and_then(EthProtocolVersion::from_usize)
+}
+
+#[derive(Debug, Error)]
+pub enum ResponseError {
+    #[error("unsolicited {response:?} with request id {request_id}")]
+    Unsolicited {
+        request_id: u64,
+        response: EthMessageId,
+    },
+    #[error("{response:?} does not answer {request:?} with request id {request_id}")]
+    KindMismatch {
+        request_id: u64,
+        request: EthMessageId,
+        response: EthMessageId,
+    },
+}
+
+/// How long a request may stay unanswered before it is no longer tracked.
+pub const DEFAULT_REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Outstanding requests sent to a peer, keyed by their eth/66 request id.
+#[derive(Debug)]
+pub struct PendingRequests {
+    timeout: Duration,
+    requests: HashMap<u64, (EthMessageId, Instant)>,
+}
+
+impl PendingRequests {
+    pub fn new(timeout: Duration) -> Self {
+        Self {
+            timeout,
+            requests: Default::default(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.requests.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.requests.is_empty()
+    }
+
+    pub fn register(&mut self, request_id: u64, request: EthMessageId) -> anyhow::Result<()> {
+        if request.response_id().is_none() {
+            bail!("{:?} is not a request", request);
+        }
+        if self.requests.contains_key(&request_id) {
+            bail!("request id {} is already pending", request_id);
+        }
+
+        self.requests.insert(request_id, (request, Instant::now()));
+
+        Ok(())
+    }
+
+    /// Matches a response to its request, returning the id of the request message.
+    ///
+    /// Responses of the wrong kind leave the request pending.
+    pub fn resolve(
+        &mut self,
+        request_id: u64,
+        response: EthMessageId,
+    ) -> Result<EthMessageId, ResponseError> {
+        self.resolve_timed(request_id, response)
+            .map(|(request, _)| request)
+    }
+
+    /// Like [`Self::resolve`], also returning how long the request was pending, for
+    /// [`Metrics::observe_latency`](super::metrics::Metrics::observe_latency).
+    pub fn resolve_timed(
+        &mut self,
+        request_id: u64,
+        response: EthMessageId,
+    ) -> Result<(EthMessageId, Duration), ResponseError> {
+        let &(request, sent_at) =
+            self.requests
+                .get(&request_id)
+                .ok_or(ResponseError::Unsolicited {
+                    request_id,
+                    response,
+                })?;
+
+        if request.response_id() != Some(response) {
+            return Err(ResponseError::KindMismatch {
+                request_id,
+                request,
+                response,
+            });
+        }
+
+        self.requests.remove(&request_id);
+
+        Ok((request, sent_at.elapsed()))
+    }
+
+    /// Drops requests sent more than the timeout before `now`, returning them.
+    pub fn expire(&mut self, now: Instant) -> Vec<(u64, EthMessageId)> {
+        let timeout = self.timeout;
+        let mut expired = vec![];
+        self.requests
+            .retain(|&request_id, &mut (request, sent_at)| {
+                if now.saturating_duration_since(sent_at) > timeout {
+                    expired.push((request_id, request));
+                    false
+                } else {
+                    true
+                }
+            });
+        expired.sort_unstable_by_key(|&(request_id, _)| request_id);
+
+        expired
+    }
+}
+
+/// Checks a `PooledTransactions` response against the hashes of its `GetPooledTransactions`.
+///
+/// Peers may leave out transactions they no longer have, but returning a transaction that was
+/// not asked for, or the same one twice, is a protocol breach.
+pub fn validate_pooled_response(
+    requested: &[H256],
+    response: &[TransactionEnvelope],
+) -> anyhow::Result<()> {
+    if response.len() > requested.len() {
+        bail!(
+            "{} pooled transactions returned for {} requested",
+            response.len(),
+            requested.len()
+        );
+    }
+
+    let requested = requested.iter().collect::<HashSet<_>>();
+    let mut seen = HashSet::with_capacity(response.len());
+    for tx in response {
+        let hash = tx.hash();
+        if !requested.contains(&hash) {
+            bail!("unrequested pooled transaction {:?}", hash);
+        }
+        if !seen.insert(hash) {
+            bail!("duplicate pooled transaction {:?}", hash);
+        }
+    }
+
+    Ok(())
+}
+
+/// Whether peers on this chain may announce blocks with `NewBlock`.
+///
+/// Block propagation moved to the consensus layer with the Merge (EIP-3675), so chains with
+/// beacon consensus must treat full block announcements as a protocol breach.
+pub fn accepts_new_block(spec: &ChainSpec) -> bool {
+    !matches!(
+        spec.consensus.seal_verification,
+        SealVerificationParams::Beacon { .. }
+    )
+}
+
+/// Total difficulty at which the chain moves to beacon consensus, if it does.
+pub fn terminal_total_difficulty(spec: &ChainSpec) -> Option<U256> {
+    match spec.consensus.seal_verification {
+        SealVerificationParams::Beacon {
+            terminal_total_difficulty,
+            ..
+        } => terminal_total_difficulty,
+        _ => None,
+    }
+}
+
+/// Highest total difficulty of the chain, if it has moved to beacon consensus.
+///
+/// Total difficulty is frozen at the terminal block, which may overshoot the terminal total
+/// difficulty by its own difficulty.
+pub fn max_total_difficulty(spec: &ChainSpec) -> Option<U256> {
+    terminal_total_difficulty(spec).map(|terminal_total_difficulty| {
+        terminal_total_difficulty.saturating_add(U256::from(u64::MAX))
+    })
+}
+
+/// [`max_total_difficulty`] of the well-known network with this id.
+fn known_max_total_difficulty(network_id: u64) -> Option<U256> {
+    [&*MAINNET, &*ROPSTEN, &*RINKEBY, &*GOERLI, &*SEPOLIA]
+        .into_iter()
+        .find(|spec| spec.params.network_id.0 == network_id)
+        .and_then(max_total_difficulty)
+}
+
+/// Maximum accepted payload sizes of eth messages, checked before decoding.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MessageLimits {
+    pub status: usize,
+    /// Requests for headers, bodies, receipts, node data and pooled transactions.
+    pub request: usize,
+    /// Block hash and pooled transaction hash announcements.
+    pub announcement: usize,
+    /// Responses, broadcast transactions and new blocks.
+    pub response: usize,
+}
+
+impl Default for MessageLimits {
+    fn default() -> Self {
+        Self {
+            status: 1024,
+            request: 256 * 1024,
+            announcement: 256 * 1024,
+            // Responses are soft limited to 2 MiB, allow a single large item to overshoot.
+            response: 10 * 1024 * 1024,
+        }
+    }
+}
+
+impl MessageLimits {
+    pub const fn limit(&self, id: EthMessageId) -> usize {
+        match id {
+            EthMessageId::Status => self.status,
+            EthMessageId::GetBlockHeaders
+            | EthMessageId::GetBlockBodies
+            | EthMessageId::GetPooledTransactions
+            | EthMessageId::GetNodeData
+            | EthMessageId::GetReceipts => self.request,
+            EthMessageId::NewBlockHashes | EthMessageId::NewPooledTransactionHashes => {
+                self.announcement
+            }
+            EthMessageId::Transactions
+            | EthMessageId::BlockHeaders
+            | EthMessageId::BlockBodies
+            | EthMessageId::NewBlock
+            | EthMessageId::PooledTransactions
+            | EthMessageId::NodeData
+            | EthMessageId::Receipts => self.response,
+        }
+    }
+
+    pub fn check(&self, id: EthMessageId, payload_len: usize) -> anyhow::Result<()> {
+        let limit = self.limit(id);
+        if payload_len > limit {
+            bail!(
+                "{:?} payload of {} bytes exceeds limit of {} bytes",
+                id,
+                payload_len,
+                limit
+            );
+        }
+
+        Ok(())
+    }
+}
+
+/// eth/68 announcement of pooled transactions, carrying the type and size of each one.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct NewPooledTransactionHashes68 {
+    pub types: Vec<u8>,
+    pub sizes: Vec<u32>,
+    pub hashes: Vec<H256>,
+}
+
+impl NewPooledTransactionHashes68 {
+    fn rlp_header(&self) -> Header {
+        Header {
+            list: true,
+            payload_length: self.types.as_slice().length()
+                + self.sizes.length()
+                + self.hashes.length(),
+        }
+    }
+}
+
+impl Encodable for NewPooledTransactionHashes68 {
+    fn encode(&self, out: &mut dyn BufMut) {
+        self.rlp_header().encode(out);
+        // Types are packed into a single byte array rather than a list.
+        self.types.as_slice().encode(out);
+        self.sizes.encode(out);
+        self.hashes.encode(out);
+    }
+
+    fn length(&self) -> usize {
+        let payload_length = self.rlp_header().payload_length;
+        length_of_length(payload_length) + payload_length
+    }
+}
+
+impl Decodable for NewPooledTransactionHashes68 {
+    fn decode(buf: &mut &[u8]) -> Result<Self, DecodeError> {
+        let rlp_head = Header::decode(buf)?;
+        if !rlp_head.list {
+            return Err(DecodeError::UnexpectedString);
+        }
+
+        let started_len = buf.len();
+        let this = Self {
+            types: BytesMut::decode(buf)?.to_vec(),
+            sizes: Decodable::decode(buf)?,
+            hashes: Decodable::decode(buf)?,
+        };
+
+        let consumed = started_len - buf.len();
+        if consumed != rlp_head.payload_length {
+            return Err(DecodeError::ListLengthMismatch {
+                expected: rlp_head.payload_length,
+                got: consumed,
+            });
+        }
+
+        if this.types.len() != this.hashes.len() || this.sizes.len() != this.hashes.len() {
+            return Err(DecodeError::Custom(
+                "transaction types, sizes and hashes must have equal length",
+            ));
+        }
+
+        Ok(this)
+    }
+}
+
+/// Request or response message tagged with the request id introduced in eth/66.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RequestPair<T> {
+    pub request_id: u64,
+    pub message: T,
+}
+
+impl<T> RequestPair<T> {
+    fn rlp_header(&self) -> Header
+    where
+        T: Encodable,
+    {
+        Header {
+            list: true,
+            payload_length: self.request_id.length() + self.message.length(),
+        }
+    }
+
+    /// Decodes a message framed for the given protocol version.
+    ///
+    /// eth/65 messages carry no request id, so they are assigned request id 0.
+    pub fn decode_versioned(
+        protocol_version: EthProtocolVersion,
+        buf: &mut &[u8],
+    ) -> anyhow::Result<Self>
+    where
+        T: Decodable,
+    {
+        if matches!(protocol_version, EthProtocolVersion::Eth65) {
+            if Self::decode(&mut &**buf).is_ok() {
+                bail!("request id is not allowed in eth/65");
+            }
+
+            return Ok(Self {
+                request_id: 0,
+                message: T::decode(buf)?,
+            });
+        }
+
+        Ok(Self::decode(buf)?)
+    }
+
+    /// Encodes the message framed for the given protocol version, dropping the request id on
+    /// eth/65.
+    pub fn encode_versioned(&self, protocol_version: EthProtocolVersion, out: &mut dyn BufMut)
+    where
+        T: Encodable,
+    {
+        if matches!(protocol_version, EthProtocolVersion::Eth65) {
+            self.message.encode(out);
+        } else {
+            self.encode(out);
+        }
+    }
+}
+
+impl<T: Encodable> Encodable for RequestPair<T> {
+    fn encode(&self, out: &mut dyn BufMut) {
+        self.rlp_header().encode(out);
+        self.request_id.encode(out);
+        self.message.encode(out);
+    }
+
+    fn length(&self) -> usize {
+        let payload_length = self.rlp_header().payload_length;
+        length_of_length(payload_length) + payload_length
+    }
+}
+
+impl<T: Decodable> Decodable for RequestPair<T> {
+    fn decode(buf: &mut &[u8]) -> Result<Self, DecodeError> {
+        let rlp_head = Header::decode(buf)?;
+        if !rlp_head.list {
+            return Err(DecodeError::UnexpectedString);
+        }
+
+        let started_len = buf.len();
+        let this = Self {
+            request_id: Decodable::decode(buf)?,
+            message: Decodable::decode(buf)?,
+        };
+
+        let consumed = started_len - buf.len();
+        if consumed != rlp_head.payload_length {
+            return Err(DecodeError::ListLengthMismatch {
+                expected: rlp_head.payload_length,
+                got: consumed,
+            });
+        }
+
+        Ok(this)
+    }
+}
+
+/// Request id of an eth/66 request or response, without decoding the message it carries.
+pub fn peek_request_id(mut data: &[u8]) -> Result<u64, DecodeError> {
+    let rlp_head = Header::decode(&mut data)?;
+    if !rlp_head.list {
+        return Err(DecodeError::UnexpectedString);
+    }
+
+    u64::decode(&mut data)
+}
+
+impl<T: Decodable> RequestPair<Vec<T>> {
+    /// Starts decoding the items of a response one at a time, returning its request id.
+    ///
+    /// Same as [`RlpListIter::new`] for eth/66 and later.
+    pub fn decode_streaming(payload: &[u8]) -> anyhow::Result<(u64, RlpListIter<'_, T>)> {
+        RlpListIter::new(EthProtocolVersion::Eth66, payload)
+    }
+}
+
+/// Decodes the list of a response payload one item at a time instead of collecting all of them.
+#[derive(Clone, Debug)]
+pub struct RlpListIter<'a, T> {
+    buf: &'a [u8],
+    truncated: bool,
+    done: bool,
+    _marker: PhantomData<T>,
+}
+
+/// Decodes a `BlockBodies` payload one body at a time.
+pub type BlockBodiesIter<'a> = RlpListIter<'a, BlockBody>;
+
+impl<'a, T> RlpListIter<'a, T> {
+    fn from_list(mut payload: &'a [u8]) -> anyhow::Result<Self> {
+        let rlp_head = Header::decode(&mut payload)?;
+        if !rlp_head.list {
+            return Err(DecodeError::UnexpectedString.into());
+        }
+
+        Ok(Self {
+            buf: &payload[..rlp_head.payload_length.min(payload.len())],
+            truncated: payload.len() < rlp_head.payload_length,
+            done: false,
+            _marker: PhantomData,
+        })
+    }
+
+    /// Starts decoding a payload framed for the given protocol version, returning its request id.
+    ///
+    /// eth/65 messages carry no request id, so they are assigned request id 0.
+    pub fn new(
+        protocol_version: EthProtocolVersion,
+        mut payload: &'a [u8],
+    ) -> anyhow::Result<(u64, Self)> {
+        if matches!(protocol_version, EthProtocolVersion::Eth65) {
+            return Ok((0, Self::from_list(payload)?));
+        }
+
+        let rlp_head = Header::decode(&mut payload)?;
+        if !rlp_head.list {
+            return Err(DecodeError::UnexpectedString.into());
+        }
+        let request_id = u64::decode(&mut payload)?;
+
+        Ok((request_id, Self::from_list(payload)?))
+    }
+}
+
+impl<T: Decodable> Iterator for RlpListIter<'_, T> {
+    type Item = anyhow::Result<T>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+
+        if self.buf.is_empty() {
+            self.done = true;
+            return self
+                .truncated
+                .then(|| Err(anyhow!("list payload is truncated")));
+        }
+
+        let item = T::decode(&mut self.buf);
+        self.done = item.is_err();
+        Some(item.map_err(anyhow::Error::from))
+    }
+}
+
+/// `NewPooledTransactionHashes` payload in the format of the negotiated protocol version.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum PooledTransactionHashes {
+    Hashes(Vec<H256>),
+    Eth68(NewPooledTransactionHashes68),
+}
+
+impl PooledTransactionHashes {
+    pub fn decode_versioned(
+        protocol_version: EthProtocolVersion,
+        buf: &[u8],
+    ) -> anyhow::Result<Self> {
+        Ok(match protocol_version {
+            EthProtocolVersion::Eth65 | EthProtocolVersion::Eth66 | EthProtocolVersion::Eth67 => {
+                Self::Hashes(decode_strict(buf)?)
+            }
+            EthProtocolVersion::Eth68 => Self::Eth68(decode_strict(buf)?),
+        })
+    }
+
+    pub fn hashes(&self) -> &[H256] {
+        match self {
+            Self::Hashes(hashes) => hashes,
+            Self::Eth68(NewPooledTransactionHashes68 { hashes, .. }) => hashes,
+        }
+    }
+}
+
+impl Encodable for PooledTransactionHashes {
+    fn encode(&self, out: &mut dyn BufMut) {
+        match self {
+            Self::Hashes(hashes) => hashes.encode(out),
+            Self::Eth68(announcement) => announcement.encode(out),
+        }
+    }
+
+    fn length(&self) -> usize {
+        match self {
+            Self::Hashes(hashes) => hashes.length(),
+            Self::Eth68(announcement) => announcement.length(),
+        }
+    }
+}
+
+/// Eth subprotocol message, decoded according to its id and the negotiated protocol version.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum EthMessage {
+    Status(StatusMessage),
+    NewBlockHashes(NewBlockHashes),
+    Transactions(Vec<TransactionEnvelope>),
+    GetBlockHeaders(RequestPair<GetBlockHeadersParams>),
+    BlockHeaders(RequestPair<Vec<BlockHeader>>),
+    GetBlockBodies(RequestPair<Vec<H256>>),
+    BlockBodies(RequestPair<Vec<BlockBody>>),
+    NewBlock(Box<NewBlock>),
+    NewPooledTransactionHashes(PooledTransactionHashes),
+    GetPooledTransactions(RequestPair<Vec<H256>>),
+    PooledTransactions(RequestPair<Vec<TransactionEnvelope>>),
+    GetNodeData(RequestPair<Vec<H256>>),
+    NodeData(RequestPair<Vec<Bytes>>),
+    GetReceipts(RequestPair<Vec<H256>>),
+    Receipts(RequestPair<Vec<Vec<Receipt>>>),
+}
+
+impl EthMessage {
+    pub const fn id(&self) -> EthMessageId {
+        match self {
+            Self::Status(_) => EthMessageId::Status,
+            Self::NewBlockHashes(_) => EthMessageId::NewBlockHashes,
+            Self::Transactions(_) => EthMessageId::Transactions,
+            Self::GetBlockHeaders(_) => EthMessageId::GetBlockHeaders,
+            Self::BlockHeaders(_) => EthMessageId::BlockHeaders,
+            Self::GetBlockBodies(_) => EthMessageId::GetBlockBodies,
+            Self::BlockBodies(_) => EthMessageId::BlockBodies,
+            Self::NewBlock(_) => EthMessageId::NewBlock,
+            Self::NewPooledTransactionHashes(_) => EthMessageId::NewPooledTransactionHashes,
+            Self::GetPooledTransactions(_) => EthMessageId::GetPooledTransactions,
+            Self::PooledTransactions(_) => EthMessageId::PooledTransactions,
+            Self::GetNodeData(_) => EthMessageId::GetNodeData,
+            Self::NodeData(_) => EthMessageId::NodeData,
+            Self::GetReceipts(_) => EthMessageId::GetReceipts,
+            Self::Receipts(_) => EthMessageId::Receipts,
+        }
+    }
+
+    /// Encodes the message payload, see [`decode_eth_message`].
+    pub fn encode_versioned(&self, protocol_version: EthProtocolVersion) -> Bytes {
+        let mut out = BytesMut::new();
+        match self {
+            Self::Status(v) => v.encode(&mut out),
+            Self::NewBlockHashes(v) => v.encode(&mut out),
+            Self::Transactions(v) => v.encode(&mut out),
+            Self::GetBlockHeaders(v) => v.encode_versioned(protocol_version, &mut out),
+            Self::BlockHeaders(v) => v.encode_versioned(protocol_version, &mut out),
+            Self::GetBlockBodies(v) => v.encode_versioned(protocol_version, &mut out),
+            Self::BlockBodies(v) => v.encode_versioned(protocol_version, &mut out),
+            Self::NewBlock(v) => v.encode(&mut out),
+            Self::NewPooledTransactionHashes(v) => v.encode(&mut out),
+            Self::GetPooledTransactions(v) => v.encode_versioned(protocol_version, &mut out),
+            Self::PooledTransactions(v) => v.encode_versioned(protocol_version, &mut out),
+            Self::GetNodeData(v) => v.encode_versioned(protocol_version, &mut out),
+            Self::NodeData(v) => v.encode_versioned(protocol_version, &mut out),
+            Self::GetReceipts(v) => v.encode_versioned(protocol_version, &mut out),
+            Self::Receipts(v) => v.encode_versioned(protocol_version, &mut out),
+        }
+        out.freeze()
+    }
+}
+
+/// Message id and payload to send to a peer speaking the given protocol version, failing for
+/// messages that are not part of it.
+///
+/// Inverse of [`decode_eth_message`].
+pub fn encode_eth_message(
+    message: &EthMessage,
+    protocol_version: EthProtocolVersion,
+) -> anyhow::Result<(EthMessageId, Bytes)> {
+    let id = message.id();
+    if !id.is_valid_for(protocol_version) {
+        bail!("{:?} is not part of eth/{}", id, protocol_version as usize);
+    }
+
+    Ok((id, message.encode_versioned(protocol_version)))
+}
+
+pub fn decode_eth_message(
+    id: EthMessageId,
+    protocol_version: EthProtocolVersion,
+    payload: &[u8],
+) -> anyhow::Result<EthMessage> {
+    let buf = &mut &*payload;
+
+    if !id.is_valid_for(protocol_version) {
+        bail!("{:?} is not part of eth/{}", id, protocol_version as usize);
+    }
+
+    let message = match id {
+        EthMessageId::Status => EthMessage::Status(Decodable::decode(buf)?),
+        EthMessageId::NewBlockHashes => EthMessage::NewBlockHashes(Decodable::decode(buf)?),
+        EthMessageId::Transactions => EthMessage::Transactions(Decodable::decode(buf)?),
+        EthMessageId::GetBlockHeaders => {
+            EthMessage::GetBlockHeaders(RequestPair::decode_versioned(protocol_version, buf)?)
+        }
+        EthMessageId::BlockHeaders => {
+            EthMessage::BlockHeaders(RequestPair::decode_versioned(protocol_version, buf)?)
+        }
+        EthMessageId::GetBlockBodies => {
+            EthMessage::GetBlockBodies(RequestPair::decode_versioned(protocol_version, buf)?)
+        }
+        EthMessageId::BlockBodies => {
+            EthMessage::BlockBodies(RequestPair::decode_versioned(protocol_version, buf)?)
+        }
+        EthMessageId::NewBlock => EthMessage::NewBlock(Box::new(Decodable::decode(buf)?)),
+        EthMessageId::NewPooledTransactionHashes => {
+            return Ok(EthMessage::NewPooledTransactionHashes(
+                PooledTransactionHashes::decode_versioned(protocol_version, payload)?,
+            ))
+        }
+        EthMessageId::GetPooledTransactions => {
+            EthMessage::GetPooledTransactions(RequestPair::decode_versioned(protocol_version, buf)?)
+        }
+        EthMessageId::PooledTransactions => {
+            EthMessage::PooledTransactions(RequestPair::decode_versioned(protocol_version, buf)?)
+        }
+        EthMessageId::GetNodeData => {
+            EthMessage::GetNodeData(RequestPair::decode_versioned(protocol_version, buf)?)
+        }
+        EthMessageId::NodeData => {
+            EthMessage::NodeData(RequestPair::decode_versioned(protocol_version, buf)?)
+        }
+        EthMessageId::GetReceipts => {
+            EthMessage::GetReceipts(RequestPair::decode_versioned(protocol_version, buf)?)
+        }
+        EthMessageId::Receipts => {
+            EthMessage::Receipts(RequestPair::decode_versioned(protocol_version, buf)?)
+        }
+    };
+
+    if !buf.is_empty() {
+        bail!("{} trailing bytes after {:?}", buf.len(), id);
+    }
+
+    Ok(message)
+}
+
+/// Decodes a single RLP item, failing if any bytes follow it.
+///
+/// Trailing bytes are more likely a framing bug on either side than padding, so they are not
+/// skipped over.
+pub fn decode_strict<T: Decodable>(data: &[u8]) -> Result<T, DecodeError> {
+    let buf = &mut &*data;
+    let v = T::decode(buf)?;
+    if !buf.is_empty() {
+        return Err(DecodeError::Custom("trailing bytes after RLP item"));
+    }
+
+    Ok(v)
+}
+
+/// RLP encoding and decoding of a message type, so that hot messages can be given hand-rolled
+/// implementations without touching the code exchanging them.
+pub trait RlpCodec<T> {
+    fn encode(&self, value: &T, out: &mut dyn BufMut);
+    fn decode(&self, buf: &mut &[u8]) -> Result<T, DecodeError>;
+}
+
+/// Codec backed by the derived `fastrlp` implementations.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct DerivedRlpCodec;
+
+impl<T: Encodable + Decodable> RlpCodec<T> for DerivedRlpCodec {
+    fn encode(&self, value: &T, out: &mut dyn BufMut) {
+        value.encode(out)
+    }
+
+    fn decode(&self, buf: &mut &[u8]) -> Result<T, DecodeError> {
+        T::decode(buf)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        p2p::types::{BlockId, GetBlockBodies, GetNodeData},
+        sentry::{metrics::Metrics, mock::MockPeer},
+    };
+    use hex_literal::hex;
+    use proptest::prelude::*;
+
+    fn rlp_encode<T: Encodable>(v: &T) -> BytesMut {
+        let mut out = BytesMut::new();
+        v.encode(&mut out);
+        out
+    }
+
+    fn status_message(protocol_version: EthProtocolVersion) -> StatusMessage {
+        StatusMessage {
+            protocol_version: protocol_version as usize,
+            network_id: 1,
+            total_difficulty: U256::from(17_179_869_184_u64),
+            best_hash: H256(hex!(
+                "d4e56740f876aef8c010b86a40d5f56745a118d0906a34e69aec8c0db1cb8fa3"
+            )),
+            genesis_hash: H256(hex!(
+                "d4e56740f876aef8c010b86a40d5f56745a118d0906a34e69aec8c0db1cb8fa3"
+            )),
+            fork_id: ForkId {
+                hash: ForkHash(hex!("fc64ec04")),
+                next: 1_150_000,
+            },
+        }
+    }
+
+    #[test]
+    fn corrupted_fork_id_rlp() {
+        #[derive(RlpEncodable)]
+        struct RawStatus {
+            protocol_version: usize,
+            network_id: u64,
+            total_difficulty: U256,
+            best_hash: H256,
+            genesis_hash: H256,
+            fork_id: Vec<Bytes>,
+        }
+
+        let status = |fork_id: &[&[u8]]| {
+            let status = status_message(EthProtocolVersion::Eth66);
+            rlp_encode(&RawStatus {
+                protocol_version: status.protocol_version,
+                network_id: status.network_id,
+                total_difficulty: status.total_difficulty,
+                best_hash: status.best_hash,
+                genesis_hash: status.genesis_hash,
+                fork_id: fork_id
+                    .iter()
+                    .map(|field| Bytes::copy_from_slice(field))
+                    .collect(),
+            })
+        };
+
+        assert_eq!(
+            StatusMessage::decode(&mut &*status(&[&hex!("fc64ec04"), &hex!("118c30")])).unwrap(),
+            status_message(EthProtocolVersion::Eth66)
+        );
+
+        for fork_id in [
+            &[&hex!("fc64ec0400")[..], &hex!("118c30")] as &[&[u8]],
+            &[&hex!("fc64ec")],
+            &[&hex!("fc64ec04")],
+            &[&hex!("fc64ec04"), &hex!("010000000000000000")],
+            &[],
+        ] {
+            assert!(
+                StatusMessage::decode(&mut &*status(fork_id)).is_err(),
+                "{:?}",
+                fork_id
+            );
+        }
+
+        let mut implausible = status_message(EthProtocolVersion::Eth66);
+        implausible.fork_id.next = MAX_NEXT_FORK;
+        let encoded = rlp_encode(&implausible);
+        assert_eq!(
+            decode_strict::<StatusMessage>(&encoded).unwrap(),
+            implausible
+        );
+        StatusMessage::decode_validated(&encoded).unwrap();
+
+        implausible.fork_id.next = u64::MAX;
+        let encoded = rlp_encode(&implausible);
+        assert_eq!(
+            StatusMessage::decode(&mut &*encoded),
+            Err(IMPLAUSIBLE_NEXT_FORK)
+        );
+        assert_eq!(
+            decode_strict::<StatusMessage>(&encoded),
+            Err(IMPLAUSIBLE_NEXT_FORK)
+        );
+        assert!(StatusMessage::decode_validated(&encoded)
+            .unwrap_err()
+            .to_string()
+            .starts_with("fork_id: "));
+    }
+
+    #[test]
+    fn decode_validated_status() {
+        #[derive(RlpEncodable)]
+        struct RawStatus {
+            protocol_version: usize,
+            network_id: u64,
+            total_difficulty: U256,
+            best_hash: Bytes,
+            genesis_hash: Bytes,
+            fork_id: ForkId,
+        }
+
+        let status = status_message(EthProtocolVersion::Eth66);
+        let raw = |best_hash: &[u8], genesis_hash: &[u8]| {
+            rlp_encode(&RawStatus {
+                protocol_version: status.protocol_version,
+                network_id: status.network_id,
+                total_difficulty: status.total_difficulty,
+                best_hash: Bytes::copy_from_slice(best_hash),
+                genesis_hash: Bytes::copy_from_slice(genesis_hash),
+                fork_id: status.fork_id,
+            })
+        };
+
+        let encoded = rlp_encode(&status);
+        assert_eq!(StatusMessage::decode_validated(&encoded).unwrap(), status);
+        assert_eq!(
+            StatusMessage::decode_validated(&raw(&status.best_hash.0, &status.genesis_hash.0))
+                .unwrap(),
+            status
+        );
+
+        for (best_hash, genesis_hash, field) in [
+            (&[0xbe; 31][..], &status.genesis_hash.0[..], "best_hash"),
+            (&[0xbe; 33][..], &status.genesis_hash.0[..], "best_hash"),
+            (&[][..], &status.genesis_hash.0[..], "best_hash"),
+            (&status.best_hash.0[..], &[0xd4; 20][..], "genesis_hash"),
+            (&status.best_hash.0[..], &[0xd4; 64][..], "genesis_hash"),
+        ] {
+            let e = StatusMessage::decode_validated(&raw(best_hash, genesis_hash)).unwrap_err();
+            assert!(e.to_string().starts_with(field), "{}", e);
+        }
+
+        assert!(StatusMessage::decode_validated(&encoded[..encoded.len() - 1]).is_err());
+        assert!(StatusMessage::decode_validated(&[0x80]).is_err());
+    }
+
+    #[tokio::test]
+    async fn contradictory_status() {
+        // Genesis difficulty of mainnet
+        let at_genesis = status_message(EthProtocolVersion::Eth66);
+        at_genesis
+            .sanity_check(SUPPORTED_PROTOCOL_VERSIONS)
+            .unwrap();
+
+        let mut lying = at_genesis.clone();
+        lying.total_difficulty = U256::from(58_750_000_000_000_000_000_000_u128);
+        assert_eq!(
+            lying.sanity_check(SUPPORTED_PROTOCOL_VERSIONS),
+            Err(StatusSanityError::DifficultyAtGenesis(
+                lying.total_difficulty
+            ))
+        );
+        assert!(matches!(
+            lying.validate_against(EthProtocolVersion::Eth66),
+            Err(HandshakeError::ContradictoryStatus(
+                StatusSanityError::DifficultyAtGenesis(_)
+            ))
+        ));
+        lying.best_hash = H256::repeat_byte(0xbe);
+        lying.sanity_check(SUPPORTED_PROTOCOL_VERSIONS).unwrap();
+
+        let mut unknown_version = at_genesis.clone();
+        unknown_version.protocol_version = 99;
+        assert_eq!(
+            unknown_version.sanity_check(SUPPORTED_PROTOCOL_VERSIONS),
+            Err(StatusSanityError::UnsupportedProtocolVersion(99))
+        );
+
+        // Known, but only acceptable where advertised
+        let mut eth65 = at_genesis;
+        eth65.protocol_version = EthProtocolVersion::Eth65 as usize;
+        assert_eq!(
+            eth65.sanity_check(&[EthProtocolVersion::Eth66, EthProtocolVersion::Eth67]),
+            Err(StatusSanityError::UnsupportedProtocolVersion(65))
+        );
+        eth65
+            .sanity_check(&[EthProtocolVersion::Eth65, EthProtocolVersion::Eth66])
+            .unwrap();
+
+        let ours = mainnet_petersburg();
+        let mut theirs = ours.to_status_message(EthProtocolVersion::Eth66);
+        theirs.total_difficulty = U256::from(58_750_000_000_000_000_000_000_u128);
+        let (mut peer, mut stream) = MockPeer::pair(EthProtocolVersion::Eth66);
+        peer.send(EthMessage::Status(theirs)).await.unwrap();
+        let e = perform_handshake(
+            &mut stream,
+            &ours,
+            PeerId::random(),
+            EthProtocolVersion::Eth66,
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(
+            e.downcast_ref::<HandshakeError>()
+                .unwrap()
+                .disconnect_reason(),
+            DisconnectReason::ProtocolBreach
+        );
+    }
+
+    #[test]
+    fn custom_rlp_codec() {
+        struct StatusCodec;
+
+        impl RlpCodec<StatusMessage> for StatusCodec {
+            fn encode(&self, value: &StatusMessage, out: &mut dyn BufMut) {
+                let mut payload = BytesMut::new();
+                value.protocol_version.encode(&mut payload);
+                value.network_id.encode(&mut payload);
+                value.total_difficulty.encode(&mut payload);
+                value.best_hash.encode(&mut payload);
+                value.genesis_hash.encode(&mut payload);
+                value.fork_id.encode(&mut payload);
+
+                Header {
+                    list: true,
+                    payload_length: payload.len(),
+                }
+                .encode(out);
+                out.put_slice(&payload);
+            }
+
+            fn decode(&self, buf: &mut &[u8]) -> Result<StatusMessage, DecodeError> {
+                let header = Header::decode(buf)?;
+                if !header.list {
+                    return Err(DecodeError::UnexpectedString);
+                }
+                Ok(StatusMessage {
+                    protocol_version: Decodable::decode(buf)?,
+                    network_id: Decodable::decode(buf)?,
+                    total_difficulty: Decodable::decode(buf)?,
+                    best_hash: Decodable::decode(buf)?,
+                    genesis_hash: Decodable::decode(buf)?,
+                    fork_id: Decodable::decode(buf)?,
+                })
+            }
+        }
+
+        fn round_trip<C: RlpCodec<StatusMessage>>(codec: &C, status: &StatusMessage) -> BytesMut {
+            let mut out = BytesMut::new();
+            codec.encode(status, &mut out);
+            assert_eq!(&codec.decode(&mut &*out).unwrap(), status);
+            out
+        }
+
+        for status in [
+            status_message(EthProtocolVersion::Eth66),
+            mainnet_petersburg().to_status_message(EthProtocolVersion::Eth67),
+        ] {
+            let derived = round_trip(&DerivedRlpCodec, &status);
+            assert_eq!(derived, rlp_encode(&status));
+            assert_eq!(round_trip(&StatusCodec, &status), derived);
+        }
+    }
+
+    #[tokio::test]
+    async fn trailing_bytes_are_rejected() {
+        let status = status_message(EthProtocolVersion::Eth66);
+        let mut encoded = rlp_encode(&status);
+        assert_eq!(decode_strict::<StatusMessage>(&encoded).unwrap(), status);
+        StatusMessage::decode_validated(&encoded).unwrap();
+
+        encoded.put_u8(0x80);
+        assert!(decode_strict::<StatusMessage>(&encoded).is_err());
+        assert!(StatusMessage::decode_validated(&encoded).is_err());
+        assert!(
+            decode_eth_message(EthMessageId::Status, EthProtocolVersion::Eth66, &encoded).is_err()
+        );
+
+        let (mut peer, mut stream) = MockPeer::pair(EthProtocolVersion::Eth66);
+        peer.send_raw(Message {
+            id: EthMessageId::Status as usize,
+            data: encoded.freeze(),
+        })
+        .await
+        .unwrap();
+        let e = perform_handshake(
+            &mut stream,
+            &mainnet_petersburg(),
+            PeerId::random(),
+            EthProtocolVersion::Eth66,
+        )
+        .await
+        .unwrap_err();
+        assert!(matches!(
+            e.downcast_ref::<HandshakeError>(),
+            Some(HandshakeError::MalformedStatus(_))
+        ));
+
+        for (id, message) in [
+            (
+                EthMessageId::GetBlockBodies,
+                EthMessage::GetBlockBodies(RequestPair {
+                    request_id: 3,
+                    message: vec![H256::repeat_byte(0x01)],
+                }),
+            ),
+            (
+                EthMessageId::NewPooledTransactionHashes,
+                EthMessage::NewPooledTransactionHashes(PooledTransactionHashes::Hashes(vec![
+                    H256::repeat_byte(0x02),
+                ])),
+            ),
+        ] {
+            let mut payload =
+                BytesMut::from(&message.encode_versioned(EthProtocolVersion::Eth66)[..]);
+            decode_eth_message(id, EthProtocolVersion::Eth66, &payload).unwrap();
+            payload.put_u8(0x00);
+            assert!(decode_eth_message(id, EthProtocolVersion::Eth66, &payload).is_err());
+        }
+    }
+
+    #[test]
+    fn status_message_rlp() {
+        let status = status_message(EthProtocolVersion::Eth66);
+        let encoded = rlp_encode(&status);
+
+        let decoded = StatusMessage::decode(&mut &*encoded).unwrap();
+        assert_eq!(decoded, status);
+        assert_ne!(decoded, status_message(EthProtocolVersion::Eth65));
+        assert_eq!(
+            decode_eth_message(EthMessageId::Status, EthProtocolVersion::Eth66, &encoded).unwrap(),
+            EthMessage::Status(status)
+        );
+    }
+
+    fn h256s() -> impl Strategy<Value = H256> {
+        any::<[u8; 32]>().prop_map(H256::from)
+    }
+
+    prop_compose! {
+        fn status_messages()(
+            protocol_version in any::<usize>(),
+            network_id in any::<u64>(),
+            total_difficulty in any::<[u8; 32]>().prop_map(U256::from_be_bytes),
+            best_hash in h256s(),
+            genesis_hash in h256s(),
+            fork_hash in any::<[u8; 4]>(),
+            fork_next in 0..=MAX_NEXT_FORK,
+        ) -> StatusMessage {
+            StatusMessage {
+                protocol_version,
+                network_id,
+                total_difficulty,
+                best_hash,
+                genesis_hash,
+                fork_id: ForkId {
+                    hash: ForkHash(fork_hash),
+                    next: fork_next,
+                },
+            }
+        }
+    }
+
+    proptest! {
+        #[test]
+        fn status_message_rlp_roundtrip(status in status_messages()) {
+            let encoded = rlp_encode(&status);
+            prop_assert_eq!(StatusMessage::decode(&mut &*encoded).unwrap(), status);
+        }
+    }
+
+    #[test]
+    fn status_protocol_version_mismatch() {
+        status_message(EthProtocolVersion::Eth66)
+            .validate_against(EthProtocolVersion::Eth66)
+            .unwrap();
+        assert!(status_message(EthProtocolVersion::Eth65)
+            .validate_against(EthProtocolVersion::Eth66)
+            .is_err());
+    }
+
+    fn mainnet_petersburg() -> FullStatusData {
+        let genesis = H256(hex!(
+            "d4e56740f876aef8c010b86a40d5f56745a118d0906a34e69aec8c0db1cb8fa3"
+        ));
+        let forks = vec![
+            1_150_000, 1_920_000, 2_463_000, 2_675_000, 4_370_000, 7_280_000,
+        ];
+        let head = 7_987_396;
+
+        FullStatusData {
+            status: StatusData {
+                network_id: 1,
+                total_difficulty: U256::ZERO,
+                best_hash: genesis,
+                fork_data: Forks {
+                    genesis,
+                    forks: forks.iter().copied().collect(),
+                    time_forks: BTreeSet::new(),
+                },
+                max_block: head,
+            },
+            fork_filter: ForkFilter::new(head, genesis, forks),
+            max_total_difficulty: None,
+        }
+    }
+
+    #[test]
+    fn best_peer_selection() {
+        let status = |total_difficulty: u64, best_hash: u8| StatusData {
+            total_difficulty: total_difficulty.into(),
+            best_hash: H256::repeat_byte(best_hash),
+            ..mainnet_petersburg().status
+        };
+        let peers = [
+            status(100, 1),
+            status(300, 1),
+            status(200, 9),
+            status(300, 2),
+            status(50, 0xff),
+        ];
+
+        assert!(peers[1].is_better_than(&peers[0]));
+        assert!(!peers[0].is_better_than(&peers[1]));
+        assert!(peers[3].is_better_than(&peers[1]));
+        assert!(!peers[1].is_better_than(&peers[1]));
+
+        assert_eq!(select_best(peers.iter()), Some(&peers[3]));
+        assert_eq!(select_best(peers[..3].iter()), Some(&peers[1]));
+        assert_eq!(select_best([].iter()), None);
+    }
+
+    #[test]
+    fn chain_mode_from_terminal_total_difficulty() {
+        let terminal_total_difficulty = terminal_total_difficulty(&MAINNET).unwrap();
+        assert_eq!(ChainMode::at(&MAINNET, U256::ZERO), ChainMode::PoW);
+        assert_eq!(
+            ChainMode::at(&MAINNET, terminal_total_difficulty - 1),
+            ChainMode::PoW
+        );
+        assert_eq!(
+            ChainMode::at(&MAINNET, terminal_total_difficulty),
+            ChainMode::PoS
+        );
+
+        // No Merge scheduled
+        assert_eq!(terminal_total_difficulty(&RINKEBY), None);
+        assert_eq!(ChainMode::at(&RINKEBY, U256::MAX), ChainMode::PoW);
+    }
+
+    #[test]
+    fn refresh_fork_id() {
+        let mut status = mainnet_petersburg();
+        let fork_id = |hash, next| ForkId {
+            hash: ForkHash(hash),
+            next,
+        };
+
+        assert!(status.refresh(7_279_998));
+        assert_eq!(
+            status.fork_filter.current(),
+            fork_id(hex!("a00bc324"), 7_280_000)
+        );
+
+        assert!(!status.refresh(7_279_999));
+        assert_eq!(
+            status.fork_filter.current(),
+            fork_id(hex!("a00bc324"), 7_280_000)
+        );
+
+        assert!(status.refresh(7_280_000));
+        assert_eq!(status.fork_filter.current(), fork_id(hex!("668db0af"), 0));
+        assert_eq!(
+            status.to_status_message(EthProtocolVersion::Eth66).fork_id,
+            fork_id(hex!("668db0af"), 0)
+        );
+
+        assert!(!status.refresh(7_987_396));
+        assert_eq!(status.status.max_block, 7_987_396);
+    }
+
+    #[test]
+    fn status_update() {
+        let mut ours = mainnet_petersburg().with_max_total_difficulty(Some(U256::ONE));
+
+        // Head moving on keeps everything but the head
+        let mut head_moved = ours.status.clone();
+        head_moved.max_block = 7_280_000;
+        head_moved.best_hash = H256::repeat_byte(0x01);
+        ours.update(head_moved.clone());
+        assert_eq!(ours.status, head_moved);
+        assert_eq!(
+            ours.fork_filter.current(),
+            FullStatusData::new(head_moved.clone())
+                .fork_filter
+                .current()
+        );
+        assert_eq!(ours.max_total_difficulty, Some(U256::ONE));
+
+        // New fork schedule is set up from scratch
+        let mut rescheduled = head_moved;
+        rescheduled.fork_data.forks.insert(9_069_000);
+        ours.update(rescheduled.clone());
+        assert_eq!(ours.fork_filter.current().next, 9_069_000);
+        assert_eq!(
+            ours.max_total_difficulty,
+            FullStatusData::new(rescheduled).max_total_difficulty
+        );
+    }
+
+    #[test]
+    fn handshake_error_disconnect_reason() {
+        let genesis = H256(hex!(
+            "d4e56740f876aef8c010b86a40d5f56745a118d0906a34e69aec8c0db1cb8fa3"
+        ));
+        let err = check_genesis(genesis, H256::repeat_byte(0xaa)).unwrap_err();
+        assert_eq!(err.disconnect_reason(), DisconnectReason::UselessPeer);
+        assert_eq!(err.disconnect_reason() as u8, 0x03);
+        assert_eq!(&rlp_encode(&err.disconnect_reason())[..], hex!("c103"));
+
+        assert_eq!(
+            check_network_id(1, 5).unwrap_err().disconnect_reason(),
+            DisconnectReason::UselessPeer
+        );
+        assert_eq!(
+            status_message(EthProtocolVersion::Eth65)
+                .validate_against(EthProtocolVersion::Eth66)
+                .unwrap_err()
+                .disconnect_reason(),
+            DisconnectReason::ProtocolBreach
+        );
+
+        let fork_err = mainnet_petersburg()
+            .check_peer_fork(ForkId {
+                hash: ForkHash(hex!("a00bc324")),
+                next: 0,
+            })
+            .unwrap_err();
+        assert_eq!(
+            HandshakeError::from(fork_err).disconnect_reason(),
+            DisconnectReason::UselessPeer
+        );
+    }
+
+    #[test]
+    fn fork_filter_outcomes() {
+        let ours = mainnet_petersburg();
+        let with_fork_id = |hash| StatusMessage {
+            fork_id: ForkId {
+                hash: ForkHash(hash),
+                next: 0,
+            },
+            ..ours.to_status_message(EthProtocolVersion::Eth66)
+        };
+
+        // Same fork
+        ours.validate_peer_status(&with_fork_id(hex!("668db0af")))
+            .unwrap();
+
+        // Remote announces Byzantium but is not aware of Petersburg
+        let err = ours
+            .validate_peer_status(&with_fork_id(hex!("a00bc324")))
+            .unwrap_err();
+        assert!(matches!(
+            err,
+            HandshakeError::StaleFork(ForkFilterError::RemoteStale { .. })
+        ));
+        assert!(!err.is_fatal());
+
+        // Remote is on Rinkeby Petersburg, a fork unknown to us
+        let err = ours
+            .validate_peer_status(&with_fork_id(hex!("afec6b27")))
+            .unwrap_err();
+        assert!(matches!(
+            err,
+            HandshakeError::IncompatibleFork(ForkFilterError::LocalIncompatibleOrStale { .. })
+        ));
+        assert!(err.is_fatal());
+
+        assert!(HandshakeError::UnexpectedMessage(2).is_fatal());
+    }
+
+    #[test]
+    fn strict_fork_hash_check() {
+        let ours = mainnet_petersburg();
+        let forks = &ours.status.fork_data;
+
+        // Remote claims to be past the DAO fork, but announces Homestead: the fork filter takes
+        // it for a syncing peer.
+        let tampered = ForkId {
+            hash: ForkHash(hex!("97c2c34c")),
+            next: 1_920_000,
+        };
+        ours.check_peer_fork(tampered).unwrap();
+        assert!(!forks.matches_fork_hash(2_000_000, tampered.hash));
+        assert!(forks.matches_fork_hash(1_919_999, tampered.hash));
+
+        for head in [0, 1_150_000, 4_370_000, 7_280_000, 7_987_396] {
+            assert!(forks.matches_fork_hash(head, forks.current_fork_id(head, 0).hash));
+        }
+
+        let with_time_forks = Forks {
+            time_forks: [1_681_338_455, 1_710_338_135].into_iter().collect(),
+            ..forks.clone()
+        };
+        for timestamp in [0, 1_681_338_455, 1_710_338_135] {
+            let fork_id = with_time_forks.current_fork_id(7_987_396, timestamp);
+            assert!(with_time_forks.matches_fork_hash(7_987_396, fork_id.hash));
+            assert!(!with_time_forks.matches_fork_hash(7_279_999, fork_id.hash));
+        }
+    }
+
+    #[test]
+    fn local_stale_fork() {
+        let ours = mainnet_petersburg().fork_filter.current();
+        assert_eq!(ours.next, 0);
+        let unknown = ForkId {
+            next: 9_069_000,
+            ..ours
+        };
+        let mut monitor = ForkMonitor::new(3);
+        let peers = [(); 4].map(|_| PeerId::random());
+
+        // Peers on other chains, and on our chain without news, do not count
+        let other_chain = ForkId {
+            hash: ForkHash(hex!("deadbeef")),
+            next: 9_069_000,
+        };
+        for &peer in &peers {
+            assert_eq!(monitor.observe(peer, 0, ours, other_chain), None);
+            assert_eq!(monitor.observe(peer, 0, ours, ours), None);
+        }
+
+        assert_eq!(monitor.observe(peers[0], 0, ours, unknown), None);
+        // Reconnecting peer is only counted once
+        assert_eq!(monitor.observe(peers[0], 0, ours, unknown), None);
+        assert_eq!(monitor.observe(peers[1], 0, ours, unknown), None);
+        assert_eq!(
+            monitor.observe(peers[2], 0, ours, unknown),
+            Some(ForkEvent::LocalStale {
+                next_fork_block: 9_069_000
+            })
+        );
+        assert_eq!(monitor.observe(peers[3], 0, ours, unknown), None);
+
+        // Peers unaware of our next fork are the stale ones
+        let scheduled = ForkId {
+            next: 9_069_000,
+            ..ours
+        };
+        let mut monitor = ForkMonitor::new(1);
+        let later = ForkId {
+            next: 9_200_000,
+            ..ours
+        };
+        assert_eq!(monitor.observe(peers[0], 0, scheduled, later), None);
+        assert_eq!(monitor.observe(peers[0], 0, scheduled, scheduled), None);
+    }
+
+    #[test]
+    fn fork_monitor_is_bounded() {
+        let ours = mainnet_petersburg().fork_filter.current();
+        let announcing = |next| ForkId { next, ..ours };
+        let mut monitor = ForkMonitor::new(2);
+        let peer = PeerId::random();
+
+        // Forks we are already past are not counted
+        assert_eq!(
+            monitor.observe(peer, 9_069_000, ours, announcing(9_069_000)),
+            None
+        );
+        assert!(monitor.announcers.is_empty());
+
+        for next in 0..MAX_CANDIDATE_FORKS as u64 * 2 {
+            monitor.observe(peer, 0, ours, announcing(9_069_000 + next));
+        }
+        assert_eq!(monitor.announcers.len(), MAX_CANDIDATE_FORKS);
+
+        // Forks beyond the cap are ignored, those followed keep counting
+        let other = PeerId::random();
+        assert_eq!(
+            monitor.observe(
+                other,
+                0,
+                ours,
+                announcing(9_069_000 + MAX_CANDIDATE_FORKS as u64)
+            ),
+            None
+        );
+        assert_eq!(
+            monitor.observe(other, 0, ours, announcing(9_069_000)),
+            Some(ForkEvent::LocalStale {
+                next_fork_block: 9_069_000
+            })
+        );
+
+        // Disconnected peers take their announcements with them
+        monitor.remove_peer(peer);
+        assert!(monitor.announcers.is_empty());
+    }
+
+    #[test]
+    fn dao_fork_split() {
+        let mut ours = mainnet_petersburg();
+        let forks = ours.status.fork_data.clone();
+
+        // Ethereum Classic past its Homestead successor, still on the pre-DAO hash
+        let classic = ForkId {
+            hash: ForkHash(hex!("97c2c34c")),
+            next: 2_500_000,
+        };
+        assert!(forks.is_dao_incompatible(classic));
+        for syncing in [
+            // Mainnet peer about to take the DAO fork
+            ForkId {
+                hash: ForkHash(hex!("97c2c34c")),
+                next: DAO_FORK_BLOCK,
+            },
+            // Mainnet peer unaware of further forks
+            ForkId {
+                hash: ForkHash(hex!("97c2c34c")),
+                next: 0,
+            },
+            forks.current_fork_id(7_987_396, 0),
+        ] {
+            assert!(!forks.is_dao_incompatible(syncing), "{:?}", syncing);
+        }
+        assert!(!Forks::private(forks.genesis).is_dao_incompatible(classic));
+
+        // The fork filter alone lets the classic peer in while we are below the DAO fork
+        ours.refresh(1_500_000);
+        let base = ours.to_status_message(EthProtocolVersion::Eth66);
+        let status = |fork_id| StatusMessage {
+            fork_id,
+            ..base.clone()
+        };
+        ours.check_peer_fork(classic).unwrap();
+        let err = ours.validate_peer_status(&status(classic)).unwrap_err();
+        assert!(matches!(err, HandshakeError::DaoForkRejected(id) if id == classic));
+        assert!(err.is_fatal());
+        assert_eq!(err.disconnect_reason(), DisconnectReason::UselessPeer);
+
+        ours.validate_peer_status(&status(ForkId {
+            hash: ForkHash(hex!("97c2c34c")),
+            next: DAO_FORK_BLOCK,
+        }))
+        .unwrap();
+    }
+
+    #[test]
+    fn total_difficulty_bound() {
+        let terminal_total_difficulty = U256::from(58_750_000_000_000_000_000_000_u128);
+        let max = max_total_difficulty(&MAINNET).unwrap();
+        assert!(max > terminal_total_difficulty);
+        assert_eq!(max_total_difficulty(&RINKEBY), None);
+
+        let ours = FullStatusData::new(mainnet_petersburg().status);
+        assert_eq!(ours.max_total_difficulty, Some(max));
+        let status = |total_difficulty| StatusMessage {
+            total_difficulty,
+            best_hash: H256::repeat_byte(0xbe),
+            ..ours.to_status_message(EthProtocolVersion::Eth66)
+        };
+
+        // Difficulty frozen at the mainnet terminal block
+        ours.validate_peer_status(&status(U256::from(58_750_003_716_598_352_816_469_u128)))
+            .unwrap();
+
+        let tenfold = U256::from(587_500_000_000_000_000_000_000_u128);
+        let e = ours.validate_peer_status(&status(tenfold)).unwrap_err();
+        assert!(matches!(
+            e,
+            HandshakeError::ImplausibleTotalDifficulty { theirs, .. } if theirs == tenfold
+        ));
+        assert_eq!(e.disconnect_reason(), DisconnectReason::ProtocolBreach);
+
+        let unbounded = ours.clone().with_max_total_difficulty(None);
+        assert!(!matches!(
+            unbounded.validate_peer_status(&status(tenfold)),
+            Err(HandshakeError::ImplausibleTotalDifficulty { .. })
+        ));
+    }
+
+    #[test]
+    fn next_fork_eta() {
+        // Petersburg is the last fork known to us, so Istanbul is picked up from a later schedule
+        let mut status = mainnet_petersburg().status;
+        status.fork_data.forks.insert(9_069_000);
+        let ours = FullStatusData::new(status);
+        assert_eq!(ours.fork_filter.current().next, 9_069_000);
+
+        assert_eq!(
+            ours.estimate_next_fork_eta(9_068_000, 0.5),
+            Some(Duration::from_secs(2_000))
+        );
+        assert_eq!(
+            ours.estimate_next_fork_eta(9_069_000, 0.5),
+            Some(Duration::ZERO)
+        );
+        assert_eq!(ours.estimate_next_fork_eta(9_068_000, 0.0), None);
+        assert_eq!(ours.estimate_next_fork_eta(9_068_000, f64::NAN), None);
+        // An absurdly slow rate overflows the duration instead of panicking
+        assert_eq!(ours.estimate_next_fork_eta(9_068_000, 1e-300), None);
+
+        // No fork scheduled past Petersburg
+        assert_eq!(
+            mainnet_petersburg().estimate_next_fork_eta(7_987_396, 0.1),
+            None
+        );
+
+        let mut status = mainnet_petersburg().status;
+        status.fork_data.time_forks.insert(u64::from(u32::MAX));
+        let eta = FullStatusData::new(status)
+            .estimate_next_fork_eta(7_987_396, 0.0)
+            .unwrap();
+        assert!(eta > Duration::ZERO && eta < Duration::from_secs(u64::from(u32::MAX)));
+    }
+
+    #[test]
+    fn disconnect_reason_rlp() {
+        for (reason, encoded) in [
+            (DisconnectReason::DisconnectRequested, &hex!("c180")[..]),
+            (DisconnectReason::UselessPeer, &hex!("c103")[..]),
+            (DisconnectReason::SubprotocolSpecific, &hex!("c110")[..]),
+        ] {
+            assert_eq!(&rlp_encode(&reason)[..], encoded);
+            assert_eq!(DisconnectReason::decode(&mut &*encoded).unwrap(), reason);
+            // Bare reason without the list header
+            assert_eq!(
+                DisconnectReason::decode(&mut &encoded[1..]).unwrap(),
+                reason
+            );
+        }
+        assert!(DisconnectReason::decode(&mut &hex!("c10c")[..]).is_err());
+    }
+
+    fn status_payload(status: &StatusMessage) -> Message {
+        Message {
+            id: EthMessageId::Status as usize,
+            data: rlp_encode(status).freeze(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_perform_handshake() {
+        let ours = mainnet_petersburg();
+        let theirs = ours.to_status_message(EthProtocolVersion::Eth66);
+
+        let (mut peer, mut stream) = MockPeer::pair(EthProtocolVersion::Eth66);
+        let (status, received) = futures::join!(
+            perform_handshake(
+                &mut stream,
+                &ours,
+                PeerId::random(),
+                EthProtocolVersion::Eth66
+            ),
+            async {
+                peer.send(EthMessage::Status(theirs.clone())).await?;
+                peer.expect(EthMessageId::Status).await
+            }
+        );
+        assert_eq!(status.unwrap(), theirs);
+        assert_eq!(
+            received.unwrap(),
+            EthMessage::Status(ours.to_status_message(EthProtocolVersion::Eth66))
+        );
+
+        // Requests flow both ways once the handshake is done
+        let request = EthMessage::GetBlockHeaders(RequestPair {
+            request_id: 7,
+            message: GetBlockHeadersParams {
+                start: BlockId::Number(BlockNumber(1)),
+                limit: 1,
+                skip: 0,
+                reverse: 0,
+            },
+        });
+        peer.send(request.clone()).await.unwrap();
+        let message = stream.next().await.unwrap().unwrap();
+        assert_eq!(
+            decode_eth_message(
+                EthMessageId::GetBlockHeaders,
+                EthProtocolVersion::Eth66,
+                &message.data
+            )
+            .unwrap(),
+            request
+        );
+
+        stream
+            .send(Message {
+                id: EthMessageId::BlockHeaders as usize,
+                data: EthMessage::BlockHeaders(RequestPair {
+                    request_id: 7,
+                    message: vec![],
+                })
+                .encode_versioned(EthProtocolVersion::Eth66),
+            })
+            .await
+            .unwrap();
+        assert_eq!(
+            peer.expect(EthMessageId::BlockHeaders).await.unwrap(),
+            EthMessage::BlockHeaders(RequestPair {
+                request_id: 7,
+                message: vec![]
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn protocol_version_matrix() {
+        let ours = mainnet_petersburg();
+        let params = GetBlockHeadersParams {
+            start: BlockId::Number(BlockNumber(1)),
+            limit: 1,
+            skip: 0,
+            reverse: 0,
+        };
+        let hashes = vec![H256::repeat_byte(0x01)];
+        let all = [
+            EthProtocolVersion::Eth65,
+            EthProtocolVersion::Eth66,
+            EthProtocolVersion::Eth67,
+            EthProtocolVersion::Eth68,
+        ];
+
+        for (version, request_ids, node_data) in [
+            (EthProtocolVersion::Eth65, false, true),
+            (EthProtocolVersion::Eth66, true, true),
+            (EthProtocolVersion::Eth67, true, false),
+            (EthProtocolVersion::Eth68, true, false),
+        ] {
+            let negotiated = negotiate_protocol_version(&all, [version as CapabilityVersion])
+                .unwrap_or_else(|| panic!("{} not negotiated", version));
+            assert_eq!(negotiated as usize, version as usize);
+
+            let theirs = ours.to_status_message(negotiated);
+            let (mut peer, mut stream) = MockPeer::pair(negotiated);
+            let (status, received) = futures::join!(
+                perform_handshake(&mut stream, &ours, PeerId::random(), negotiated),
+                async {
+                    peer.send(EthMessage::Status(theirs.clone())).await?;
+                    peer.expect(EthMessageId::Status).await
+                }
+            );
+            assert_eq!(status.unwrap(), theirs, "{}", version);
+            assert_eq!(
+                received.unwrap(),
+                EthMessage::Status(ours.to_status_message(negotiated)),
+                "{}",
+                version
+            );
+
+            // First request, framed with or without its request id
+            peer.send(EthMessage::GetBlockHeaders(RequestPair {
+                request_id: 7,
+                message: params.clone(),
+            }))
+            .await
+            .unwrap();
+            let message = stream.next().await.unwrap().unwrap();
+            assert_eq!(message.id, EthMessageId::GetBlockHeaders as usize);
+            assert_eq!(
+                message.data[..] == rlp_encode(&params)[..],
+                !request_ids,
+                "{}",
+                version
+            );
+            assert_eq!(
+                decode_eth_message(EthMessageId::GetBlockHeaders, negotiated, &message.data)
+                    .unwrap(),
+                EthMessage::GetBlockHeaders(RequestPair {
+                    request_id: if request_ids { 7 } else { 0 },
+                    message: params.clone(),
+                }),
+                "{}",
+                version
+            );
+
+            peer.send(EthMessage::GetNodeData(RequestPair {
+                request_id: 8,
+                message: hashes.clone(),
+            }))
+            .await
+            .unwrap();
+            let message = stream.next().await.unwrap().unwrap();
+            assert_eq!(
+                decode_eth_message(EthMessageId::GetNodeData, negotiated, &message.data).is_ok(),
+                node_data,
+                "{}",
+                version
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn perform_handshake_failures() {
+        let ours = mainnet_petersburg();
+        let theirs = ours.to_status_message(EthProtocolVersion::Eth66);
+
+        let handshake = |incoming: Vec<Message>| {
+            let ours = ours.clone();
+            async move {
+                let (mut peer, mut stream) = MockPeer::pair(EthProtocolVersion::Eth66);
+                for message in incoming {
+                    peer.send_raw(message).await.unwrap();
+                }
+                peer.close();
+                perform_handshake(
+                    &mut stream,
+                    &ours,
+                    PeerId::random(),
+                    EthProtocolVersion::Eth66,
+                )
+                .await
+            }
+        };
+        let reason = |res: anyhow::Result<StatusMessage>| {
+            res.unwrap_err()
+                .downcast::<HandshakeError>()
+                .unwrap()
+                .disconnect_reason()
+        };
+
+        assert!(handshake(vec![]).await.is_err());
+
+        let mut other_network = theirs.clone();
+        other_network.network_id = 3;
+        assert_eq!(
+            reason(handshake(vec![status_payload(&other_network)]).await),
+            DisconnectReason::UselessPeer
+        );
+
+        let mut other_chain = theirs.clone();
+        other_chain.genesis_hash = H256::repeat_byte(0xaa);
+        assert_eq!(
+            reason(handshake(vec![status_payload(&other_chain)]).await),
+            DisconnectReason::UselessPeer
+        );
+
+        let mut stale = theirs.clone();
+        stale.fork_id = ForkId {
+            hash: ForkHash(hex!("a00bc324")),
+            next: 0,
+        };
+        assert_eq!(
+            reason(handshake(vec![status_payload(&stale)]).await),
+            DisconnectReason::UselessPeer
+        );
+
+        assert_eq!(
+            reason(
+                handshake(vec![status_payload(
+                    &ours.to_status_message(EthProtocolVersion::Eth67)
+                )])
+                .await
+            ),
+            DisconnectReason::ProtocolBreach
+        );
+
+        assert_eq!(
+            reason(
+                handshake(vec![Message {
+                    id: EthMessageId::Transactions as usize,
+                    data: rlp_encode(&theirs).freeze(),
+                }])
+                .await
+            ),
+            DisconnectReason::ProtocolBreach
+        );
+
+        assert_eq!(
+            reason(
+                handshake(vec![Message {
+                    id: EthMessageId::Status as usize,
+                    data: vec![0xc0].into(),
+                }])
+                .await
+            ),
+            DisconnectReason::ProtocolBreach
+        );
+    }
+
+    #[tokio::test]
+    async fn private_network_handshake() {
+        let node = |genesis: H256| {
+            FullStatusData::new(StatusData {
+                network_id: 1337,
+                total_difficulty: U256::ONE,
+                best_hash: genesis,
+                fork_data: Forks::private(genesis),
+                max_block: 0,
+            })
+        };
+        let handshake = |ours: FullStatusData, theirs: FullStatusData| async move {
+            let (mut peer, mut stream) = MockPeer::pair(EthProtocolVersion::Eth66);
+            peer.send(EthMessage::Status(
+                theirs.to_status_message(EthProtocolVersion::Eth66),
+            ))
+            .await
+            .unwrap();
+            perform_handshake(
+                &mut stream,
+                &ours,
+                PeerId::random(),
+                EthProtocolVersion::Eth66,
+            )
+            .await
+        };
+
+        let genesis = H256::repeat_byte(0xde);
+        let forks = Forks::private(genesis);
+        forks.validate().unwrap();
+        assert!(forks.forks.is_empty() && forks.time_forks.is_empty());
+        assert_eq!(
+            node(genesis)
+                .to_status_message(EthProtocolVersion::Eth66)
+                .fork_id
+                .next,
+            0
+        );
+
+        let status = handshake(node(genesis), node(genesis)).await.unwrap();
+        assert_eq!(status.genesis_hash, genesis);
+
+        let e = handshake(node(genesis), node(H256::repeat_byte(0xad)))
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            e.downcast_ref::<HandshakeError>(),
+            Some(HandshakeError::GenesisMismatch { .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn handshake_timeout() {
+        let ours = mainnet_petersburg();
+        let timeout = Duration::from_millis(50);
+
+        // Peer stays connected, but never sends its status
+        let (mut peer, mut stream) = MockPeer::pair(EthProtocolVersion::Eth66);
+        let e = perform_handshake_with_timeout(
+            &mut stream,
+            &ours,
+            PeerId::random(),
+            EthProtocolVersion::Eth66,
+            timeout,
+        )
+        .await
+        .unwrap_err();
+        let e = e.downcast_ref::<HandshakeError>().unwrap();
+        assert!(matches!(e, HandshakeError::Timeout(t) if *t == timeout));
+        assert_eq!(e.disconnect_reason(), DisconnectReason::PingTimeout);
+
+        // Our status was still sent
+        peer.expect(EthMessageId::Status).await.unwrap();
+    }
+
+    /// Collects values recorded on spans after their creation.
+    #[derive(Clone, Default)]
+    struct RecordedFields(std::sync::Arc<parking_lot::Mutex<Vec<(&'static str, String)>>>);
+
+    impl<S: tracing::Subscriber> tracing_subscriber::Layer<S> for RecordedFields {
+        fn on_record(
+            &self,
+            _: &tracing::span::Id,
+            values: &tracing::span::Record<'_>,
+            _: tracing_subscriber::layer::Context<'_, S>,
+        ) {
+            values.record(
+                &mut |field: &tracing::field::Field, value: &dyn fmt::Debug| {
+                    self.0.lock().push((field.name(), format!("{:?}", value)))
+                },
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn handshake_span_records_disconnect_reason() {
+        use tracing_subscriber::layer::SubscriberExt;
+
+        let recorded = RecordedFields::default();
+        let _guard =
+            tracing::subscriber::set_default(tracing_subscriber::registry().with(recorded.clone()));
+
+        let ours = mainnet_petersburg();
+        let mut theirs = ours.to_status_message(EthProtocolVersion::Eth66);
+        theirs.network_id = 3;
+
+        let (mut peer, mut stream) = MockPeer::pair(EthProtocolVersion::Eth66);
+        peer.send(EthMessage::Status(theirs)).await.unwrap();
+        perform_handshake(
+            &mut stream,
+            &ours,
+            PeerId::random(),
+            EthProtocolVersion::Eth66,
+        )
+        .await
+        .unwrap_err();
+
+        let recorded = recorded.0.lock().clone();
+        assert!(recorded.contains(&("outcome", "\"rejected\"".to_string())));
+        assert!(recorded.contains(&(
+            "disconnect_reason",
+            DisconnectReason::UselessPeer.to_string()
+        )));
+    }
+
+    #[test]
+    fn network_id_mismatch() {
+        check_network_id(1, 1).unwrap();
+        assert!(matches!(
+            check_network_id(1, 3),
+            Err(HandshakeError::NetworkIdMismatch { ours: 1, theirs: 3 })
+        ));
+    }
+
+    #[test]
+    fn check_peer_fork() {
+        let status = mainnet_petersburg();
+
+        status
+            .check_peer_fork(ForkId {
+                hash: ForkHash(hex!("668db0af")),
+                next: 0,
+            })
+            .unwrap();
+
+        // Remote announces Byzantium but is not aware of Petersburg.
+        assert!(matches!(
+            status.check_peer_fork(ForkId {
+                hash: ForkHash(hex!("a00bc324")),
+                next: 0,
+            }),
+            Err(ForkFilterError::RemoteStale { .. })
+        ));
+
+        // Remote is on Rinkeby Petersburg, a fork unknown to us.
+        assert!(matches!(
+            status.check_peer_fork(ForkId {
+                hash: ForkHash(hex!("afec6b27")),
+                next: 0,
+            }),
+            Err(ForkFilterError::LocalIncompatibleOrStale { .. })
+        ));
+    }
+
+    #[test]
+    fn status_message_from_status_data() {
+        let status = mainnet_petersburg();
+        let message = status.to_status_message(EthProtocolVersion::Eth66);
+
+        message.validate_against(EthProtocolVersion::Eth66).unwrap();
+        assert_eq!(message.network_id, status.status.network_id);
+        assert_eq!(message.genesis_hash, status.status.fork_data.genesis);
+        assert_eq!(
+            message.fork_id,
+            ForkId {
+                hash: ForkHash(hex!("668db0af")),
+                next: 0,
+            }
+        );
+        status.check_peer_fork(message.fork_id).unwrap();
+    }
+
+    #[test]
+    fn mainnet_fork_ids() {
+        let forks = Forks {
+            genesis: mainnet_petersburg().status.fork_data.genesis,
+            forks: MAINNET.gather_forks().into_iter().map(|f| *f).collect(),
+            time_forks: BTreeSet::new(),
+        };
+
+        for (head, hash, next) in [
+            (0, hex!("fc64ec04"), 1_150_000),
+            (1_149_999, hex!("fc64ec04"), 1_150_000),
+            (1_150_000, hex!("97c2c34c"), 1_920_000),
+            (7_280_000, hex!("668db0af"), 9_069_000),
+            (12_965_000, hex!("b715077d"), 13_773_000),
+            (15_050_000, hex!("f0afd0e3"), 0),
+        ] {
+            assert_eq!(
+                forks.current_fork_id(head, 0),
+                ForkId {
+                    hash: ForkHash(hash),
+                    next
+                },
+                "head {head}"
+            );
+        }
+    }
+
+    #[test]
+    fn fork_schedule_validation() {
+        let forks = Forks::from_chain_spec(&MAINNET).unwrap();
+        forks.validate().unwrap();
+
+        let with_time_forks = Forks {
+            time_forks: [1_681_338_455].into_iter().collect(),
+            ..forks.clone()
+        };
+        with_time_forks.validate().unwrap();
+
+        for malformed in [
+            Forks {
+                genesis: H256::zero(),
+                ..forks.clone()
+            },
+            Forks {
+                time_forks: [0, 1_681_338_455].into_iter().collect(),
+                ..forks.clone()
+            },
+            // Timestamp fork scheduled as if it was a block number
+            Forks {
+                time_forks: [15_000_000].into_iter().collect(),
+                ..forks.clone()
+            },
+            Forks {
+                forks: [1_150_000, MAX_NEXT_FORK + 1].into_iter().collect(),
+                ..forks.clone()
+            },
+        ] {
+            assert!(malformed.validate().is_err(), "{:?}", malformed);
+        }
+
+        let genesis = forks.genesis;
+        let status = |forks: Vec<u64>| {
+            FullStatusData::try_from(ethereum_interfaces::sentry::StatusData {
+                network_id: 1,
+                total_difficulty: Some(H256::zero().into()),
+                best_hash: Some(genesis.into()),
+                fork_data: Some(ethereum_interfaces::sentry::Forks {
+                    genesis: Some(genesis.into()),
+                    forks,
+                }),
+                max_block: 0,
+            })
+        };
+        let deduplicated = status(vec![1_150_000, 1_920_000]).unwrap();
+        assert_eq!(
+            status(vec![1_150_000, 1_920_000, 1_150_000])
+                .unwrap()
+                .status
+                .fork_data,
+            deduplicated.status.fork_data
+        );
+    }
+
+    #[test]
+    fn mainnet_forks_from_chain_spec() {
+        let forks = Forks::from_chain_spec(&MAINNET).unwrap();
+        assert_eq!(
+            forks,
+            Forks {
+                genesis: mainnet_petersburg().status.fork_data.genesis,
+                forks: [
+                    1_150_000, 1_920_000, 2_463_000, 2_675_000, 4_370_000, 7_280_000, 9_069_000,
+                    9_200_000, 12_244_000, 12_965_000, 13_773_000, 15_050_000,
+                ]
+                .into_iter()
+                .collect(),
+                time_forks: BTreeSet::new(),
+            }
+        );
+        assert_eq!(
+            forks.current_fork_id(15_050_000, 0),
+            ForkId {
+                hash: ForkHash(hex!("f0afd0e3")),
+                next: 0
+            }
+        );
+    }
+
+    #[test]
+    fn fork_hash_builder() {
+        let genesis = mainnet_petersburg().status.fork_data.genesis;
+        assert_eq!(
+            ForkHashBuilder::new(genesis).build(),
+            ForkHash(hex!("fc64ec04"))
+        );
+
+        let mut builder = ForkHashBuilder::new(genesis);
+        builder.add(1_150_000).add(1_920_000);
+        assert_eq!(builder.build(), ForkHash(hex!("91d1f948")));
+
+        let mut builder = ForkHashBuilder::new(genesis);
+        for fork in MAINNET
+            .gather_forks()
+            .into_iter()
+            .map(|f| *f)
+            .filter(|&f| f <= 12_965_000)
+        {
+            builder.add(fork);
+        }
+        assert_eq!(builder.build(), ForkHash(hex!("b715077d")));
+    }
+
+    #[test]
+    fn mainnet_time_fork_ids() {
+        let shanghai = 1_681_338_455;
+        let cancun = 1_710_338_135;
+        let forks = Forks {
+            genesis: mainnet_petersburg().status.fork_data.genesis,
+            forks: MAINNET.gather_forks().into_iter().map(|f| *f).collect(),
+            time_forks: [shanghai, cancun].into_iter().collect(),
+        };
+
+        for (head, head_timestamp, hash, next) in [
+            (15_050_000, 0, hex!("f0afd0e3"), shanghai),
+            (20_000_000, shanghai - 1, hex!("f0afd0e3"), shanghai),
+            (20_000_000, shanghai, hex!("dce96c2d"), cancun),
+            (20_000_000, cancun, hex!("9f3d2254"), 0),
+        ] {
+            assert_eq!(
+                forks.current_fork_id(head, head_timestamp),
+                ForkId {
+                    hash: ForkHash(hash),
+                    next
+                },
+                "head {head}, timestamp {head_timestamp}"
+            );
+        }
+    }
+
+    #[test]
+    fn protocol_version_negotiation() {
+        assert_eq!(
+            supported_capabilities().len(),
+            SUPPORTED_PROTOCOL_VERSIONS.len()
+        );
+        assert!(supported_capabilities()
+            .values()
+            .all(|&length| length == 17));
+
+        assert!(matches!(
+            negotiate_protocol_version(SUPPORTED_PROTOCOL_VERSIONS, [65, 66, 67, 68]),
+            Some(EthProtocolVersion::Eth67)
+        ));
+        assert!(matches!(
+            negotiate_protocol_version(SUPPORTED_PROTOCOL_VERSIONS, [65, 66]),
+            Some(EthProtocolVersion::Eth66)
+        ));
+
+        let with_eth65 = [
+            EthProtocolVersion::Eth65,
+            EthProtocolVersion::Eth66,
+            EthProtocolVersion::Eth67,
+        ];
+        assert!(matches!(
+            negotiate_protocol_version(&with_eth65, [65]),
+            Some(EthProtocolVersion::Eth65)
+        ));
+        assert!(negotiate_protocol_version(&with_eth65, [63, 64]).is_none());
+    }
+
+    #[cfg(not(feature = "eth65"))]
+    #[test]
+    fn eth65_is_not_advertised() {
+        assert!(!supported_capabilities()
+            .keys()
+            .any(|cap| cap.version == EthProtocolVersion::Eth65 as CapabilityVersion));
+        assert!(negotiate_protocol_version(SUPPORTED_PROTOCOL_VERSIONS, [65]).is_none());
+    }
+
+    #[cfg(feature = "eth65")]
+    #[test]
+    fn eth65_is_advertised() {
+        assert!(supported_capabilities()
+            .keys()
+            .any(|cap| cap.version == EthProtocolVersion::Eth65 as CapabilityVersion));
+        assert!(matches!(
+            negotiate_protocol_version(SUPPORTED_PROTOCOL_VERSIONS, [65]),
+            Some(EthProtocolVersion::Eth65)
+        ));
+    }
+
+    #[test]
+    fn display() {
+        for (id, expected) in [
+            (EthMessageId::Status, "Status (0x00)"),
+            (EthMessageId::NewBlockHashes, "NewBlockHashes (0x01)"),
+            (EthMessageId::Transactions, "Transactions (0x02)"),
+            (EthMessageId::GetBlockHeaders, "GetBlockHeaders (0x03)"),
+            (EthMessageId::BlockHeaders, "BlockHeaders (0x04)"),
+            (EthMessageId::GetBlockBodies, "GetBlockBodies (0x05)"),
+            (EthMessageId::BlockBodies, "BlockBodies (0x06)"),
+            (EthMessageId::NewBlock, "NewBlock (0x07)"),
+            (
+                EthMessageId::NewPooledTransactionHashes,
+                "NewPooledTransactionHashes (0x08)",
+            ),
+            (
+                EthMessageId::GetPooledTransactions,
+                "GetPooledTransactions (0x09)",
+            ),
+            (
+                EthMessageId::PooledTransactions,
+                "PooledTransactions (0x0a)",
+            ),
+            (EthMessageId::GetNodeData, "GetNodeData (0x0d)"),
+            (EthMessageId::NodeData, "NodeData (0x0e)"),
+            (EthMessageId::GetReceipts, "GetReceipts (0x0f)"),
+            (EthMessageId::Receipts, "Receipts (0x10)"),
+        ] {
+            assert_eq!(id.to_string(), expected);
+        }
+
+        for (version, expected) in [
+            (EthProtocolVersion::Eth65, "eth/65"),
+            (EthProtocolVersion::Eth66, "eth/66"),
+            (EthProtocolVersion::Eth67, "eth/67"),
+            (EthProtocolVersion::Eth68, "eth/68"),
+        ] {
+            assert_eq!(version.to_string(), expected);
+        }
+    }
+
+    #[test]
+    fn message_id_version_gating() {
+        let versions = [
+            EthProtocolVersion::Eth65,
+            EthProtocolVersion::Eth66,
+            EthProtocolVersion::Eth67,
+            EthProtocolVersion::Eth68,
+        ];
+        for id in (0..=16).filter_map(EthMessageId::from_usize) {
+            for version in versions {
+                let node_data = matches!(id, EthMessageId::GetNodeData | EthMessageId::NodeData);
+                let expected = !(node_data && version as usize >= 67);
+                assert_eq!(id.is_valid_for(version), expected, "{:?} {:?}", id, version);
+            }
+        }
+    }
+
+    #[test]
+    fn pending_requests() {
+        let timeout = Duration::from_secs(10);
+        let mut requests = PendingRequests::new(timeout);
+        assert!(requests.is_empty());
+
+        requests.register(1, EthMessageId::GetBlockHeaders).unwrap();
+        requests.register(2, EthMessageId::GetBlockBodies).unwrap();
+        requests.register(3, EthMessageId::GetReceipts).unwrap();
+        assert!(requests.register(3, EthMessageId::GetReceipts).is_err());
+        assert!(requests.register(4, EthMessageId::BlockHeaders).is_err());
+        assert_eq!(requests.len(), 3);
+
+        assert_eq!(
+            requests.resolve(1, EthMessageId::BlockHeaders).unwrap(),
+            EthMessageId::GetBlockHeaders
+        );
+        assert!(matches!(
+            requests.resolve(1, EthMessageId::BlockHeaders),
+            Err(ResponseError::Unsolicited { request_id: 1, .. })
+        ));
+        assert!(matches!(
+            requests.resolve(42, EthMessageId::Receipts),
+            Err(ResponseError::Unsolicited { request_id: 42, .. })
+        ));
+
+        assert!(matches!(
+            requests.resolve(2, EthMessageId::BlockHeaders),
+            Err(ResponseError::KindMismatch {
+                request_id: 2,
+                request: EthMessageId::GetBlockBodies,
+                response: EthMessageId::BlockHeaders,
+            })
+        ));
+        assert_eq!(requests.len(), 2);
+
+        assert!(requests.expire(Instant::now()).is_empty());
+        assert_eq!(
+            requests.expire(Instant::now() + timeout + Duration::from_secs(1)),
+            vec![
+                (2, EthMessageId::GetBlockBodies),
+                (3, EthMessageId::GetReceipts)
+            ]
+        );
+        assert!(requests.is_empty());
+        assert!(matches!(
+            requests.resolve(3, EthMessageId::Receipts),
+            Err(ResponseError::Unsolicited { .. })
+        ));
+    }
+
+    #[test]
+    fn request_latency() {
+        let metrics = Metrics::default();
+        let mut requests = PendingRequests::new(Duration::from_secs(10));
+        requests.register(1, EthMessageId::GetBlockHeaders).unwrap();
+
+        let (request, latency) = requests
+            .resolve_timed(1, EthMessageId::BlockHeaders)
+            .unwrap();
+        assert_eq!(request, EthMessageId::GetBlockHeaders);
+        assert!(latency < Duration::from_secs(10));
+        metrics.observe_latency(request, latency);
+
+        assert_eq!(metrics.answered(EthMessageId::GetBlockHeaders), 1);
+        assert!(requests
+            .resolve_timed(1, EthMessageId::BlockHeaders)
+            .is_err());
+        assert_eq!(metrics.answered(EthMessageId::GetBlockHeaders), 1);
+    }
+
+    #[test]
+    fn message_limits() {
+        let limits = MessageLimits::default();
+        for id in (0..=16).filter_map(EthMessageId::from_usize) {
+            let limit = limits.limit(id);
+            assert!(limit <= 16 * 1024 * 1024, "{:?}", id);
+            limits.check(id, limit).unwrap();
+            assert!(limits.check(id, limit + 1).is_err());
+        }
+
+        let status = rlp_encode(&status_message(EthProtocolVersion::Eth66));
+        limits.check(EthMessageId::Status, status.len()).unwrap();
+        assert!(limits
+            .check(EthMessageId::BlockBodies, 2 * 1024 * 1024)
+            .is_ok());
+        assert!(limits
+            .check(EthMessageId::GetBlockBodies, 2 * 1024 * 1024)
+            .is_err());
+    }
+
+    #[test]
+    fn pooled_transaction_hashes_versioned() {
+        let hashes = vec![H256::repeat_byte(0xaa), H256::repeat_byte(0xbb)];
+
+        let flat = rlp_encode(&hashes);
+        for version in [
+            EthProtocolVersion::Eth65,
+            EthProtocolVersion::Eth66,
+            EthProtocolVersion::Eth67,
+        ] {
+            assert_eq!(
+                PooledTransactionHashes::decode_versioned(version, &flat).unwrap(),
+                PooledTransactionHashes::Hashes(hashes.clone())
+            );
+        }
+        assert!(
+            PooledTransactionHashes::decode_versioned(EthProtocolVersion::Eth68, &flat).is_err()
+        );
+
+        let announcement = NewPooledTransactionHashes68 {
+            types: vec![0x00, 0x02],
+            sizes: vec![120, 4096],
+            hashes: hashes.clone(),
+        };
+        let encoded = rlp_encode(&announcement);
+        assert_eq!(encoded.len(), announcement.length());
+        assert_eq!(
+            PooledTransactionHashes::decode_versioned(EthProtocolVersion::Eth68, &encoded).unwrap(),
+            PooledTransactionHashes::Eth68(announcement)
+        );
+    }
+
+    #[test]
+    fn decode_typed_message() {
+        let request = GetBlockBodies {
+            request_id: 42,
+            hashes: vec![H256::repeat_byte(0x01), H256::repeat_byte(0x02)],
+        };
+        let encoded = rlp_encode(&request);
+
+        let message = decode_eth_message(
+            EthMessageId::GetBlockBodies,
+            EthProtocolVersion::Eth66,
+            &encoded,
+        )
+        .unwrap();
+        assert!(matches!(message.id(), EthMessageId::GetBlockBodies));
+        assert_eq!(
+            message,
+            EthMessage::GetBlockBodies(RequestPair {
+                request_id: request.request_id,
+                message: request.hashes,
+            })
+        );
+
+        // Truncated payload
+        assert!(decode_eth_message(
+            EthMessageId::GetBlockBodies,
+            EthProtocolVersion::Eth66,
+            &encoded[..encoded.len() - 1]
+        )
+        .is_err());
+
+        // Payload of a different message kind
+        assert!(decode_eth_message(
+            EthMessageId::BlockHeaders,
+            EthProtocolVersion::Eth66,
+            &encoded
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn request_pair_rlp() {
+        let request = RequestPair {
+            request_id: 1111,
+            message: GetBlockHeadersParams {
+                start: BlockId::Number(BlockNumber(9999)),
+                limit: 5,
+                skip: 5,
+                reverse: 0,
+            },
+        };
+        let encoded = rlp_encode(&request);
+        assert_eq!(&encoded[..], hex!("ca820457c682270f050580"));
+        assert_eq!(encoded.len(), request.length());
+        assert_eq!(RequestPair::decode(&mut &*encoded).unwrap(), request);
+        assert_eq!(peek_request_id(&encoded).unwrap(), 1111);
+        assert!(peek_request_id(&rlp_encode(&1111_u64)).is_err());
+
+        let response = RequestPair {
+            request_id: 2,
+            message: vec![
+                Bytes::from_static(b"node"),
+                Bytes::from_static(&[0xde, 0xad, 0xbe, 0xef]),
+            ],
+        };
+        let encoded = rlp_encode(&response);
+        assert_eq!(RequestPair::decode(&mut &*encoded).unwrap(), response);
+    }
+
+    #[test]
+    fn encode_eth_message_versions() {
+        let params = GetBlockHeadersParams {
+            start: BlockId::Number(BlockNumber(9999)),
+            limit: 5,
+            skip: 5,
+            reverse: 0,
+        };
+        let message = EthMessage::GetBlockHeaders(RequestPair {
+            request_id: 1111,
+            message: params.clone(),
+        });
+
+        let (id, payload) = encode_eth_message(&message, EthProtocolVersion::Eth65).unwrap();
+        assert_eq!(id, EthMessageId::GetBlockHeaders);
+        assert_eq!(&payload[..], &rlp_encode(&params)[..]);
+
+        let (id, payload) = encode_eth_message(&message, EthProtocolVersion::Eth66).unwrap();
+        assert_eq!(id, EthMessageId::GetBlockHeaders);
+        assert_eq!(&payload[..], hex!("ca820457c682270f050580"));
+        assert_eq!(
+            decode_eth_message(id, EthProtocolVersion::Eth66, &payload).unwrap(),
+            message
+        );
+
+        let message = EthMessage::GetNodeData(RequestPair {
+            request_id: 1,
+            message: vec![H256::repeat_byte(0x01)],
+        });
+        assert!(encode_eth_message(&message, EthProtocolVersion::Eth66).is_ok());
+        assert!(encode_eth_message(&message, EthProtocolVersion::Eth67).is_err());
+    }
+
+    #[test]
+    fn request_pair_versioned() {
+        let hashes = vec![H256::repeat_byte(0x01), H256::repeat_byte(0x02)];
+        let wrapped = rlp_encode(&RequestPair {
+            request_id: 7,
+            message: hashes.clone(),
+        });
+        let bare = rlp_encode(&hashes);
+
+        for version in [EthProtocolVersion::Eth66, EthProtocolVersion::Eth67] {
+            assert_eq!(
+                decode_eth_message(EthMessageId::GetReceipts, version, &wrapped).unwrap(),
+                EthMessage::GetReceipts(RequestPair {
+                    request_id: 7,
+                    message: hashes.clone(),
+                })
+            );
+            assert!(decode_eth_message(EthMessageId::GetReceipts, version, &bare).is_err());
+        }
+
+        assert_eq!(
+            decode_eth_message(EthMessageId::GetReceipts, EthProtocolVersion::Eth65, &bare)
+                .unwrap(),
+            EthMessage::GetReceipts(RequestPair {
+                request_id: 0,
+                message: hashes,
+            })
+        );
+        assert!(decode_eth_message(
+            EthMessageId::GetReceipts,
+            EthProtocolVersion::Eth65,
+            &wrapped
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn block_bodies_iter() {
+        let bodies = (1..=3)
+            .map(|number| BlockBody {
+                transactions: vec![],
+                ommers: std::iter::once(BlockHeader {
+                    number: BlockNumber(number),
+                    ..BlockHeader::empty()
+                })
+                .collect(),
+            })
+            .collect::<Vec<_>>();
+
+        let encoded = rlp_encode(&RequestPair {
+            request_id: 5,
+            message: bodies.clone(),
+        });
+        let (request_id, iter) = BlockBodiesIter::new(EthProtocolVersion::Eth66, &encoded).unwrap();
+        assert_eq!(request_id, 5);
+        assert_eq!(iter.collect::<anyhow::Result<Vec<_>>>().unwrap(), bodies);
+
+        let bare = rlp_encode(&bodies);
+        let (request_id, iter) = BlockBodiesIter::new(EthProtocolVersion::Eth65, &bare).unwrap();
+        assert_eq!(request_id, 0);
+        assert_eq!(iter.collect::<anyhow::Result<Vec<_>>>().unwrap(), bodies);
+
+        let (_, iter) = BlockBodiesIter::new(
+            EthProtocolVersion::Eth65,
+            &rlp_encode(&Vec::<BlockBody>::new()),
+        )
+        .unwrap();
+        assert_eq!(iter.count(), 0);
+
+        // Bodies preceding the cut are still yielded, followed by a single error
+        let mut iter =
+            BlockBodiesIter::new(EthProtocolVersion::Eth66, &encoded[..encoded.len() - 1])
+                .unwrap()
+                .1;
+        assert_eq!(iter.next().unwrap().unwrap(), bodies[0]);
+        assert_eq!(iter.next().unwrap().unwrap(), bodies[1]);
+        assert!(iter.next().unwrap().is_err());
+        assert!(iter.next().is_none());
+
+        let body_len = bodies[2].length();
+        let mut iter = BlockBodiesIter::new(
+            EthProtocolVersion::Eth66,
+            &encoded[..encoded.len() - body_len],
+        )
+        .unwrap()
+        .1;
+        assert_eq!(iter.next().unwrap().unwrap(), bodies[0]);
+        assert_eq!(iter.next().unwrap().unwrap(), bodies[1]);
+        assert!(iter.next().unwrap().is_err());
+        assert!(iter.next().is_none());
+    }
+
+    #[test]
+    fn request_pair_streaming() {
+        let headers = (1..=3)
+            .map(|number| BlockHeader {
+                number: BlockNumber(number),
+                ..BlockHeader::empty()
+            })
+            .collect::<Vec<_>>();
+        let encoded = rlp_encode(&RequestPair {
+            request_id: 1111,
+            message: headers.clone(),
+        });
+
+        let (request_id, mut iter) =
+            RequestPair::<Vec<BlockHeader>>::decode_streaming(&encoded).unwrap();
+        assert_eq!(request_id, 1111);
+        assert_eq!(iter.next().unwrap().unwrap(), headers[0]);
+        assert_eq!(
+            iter.collect::<anyhow::Result<Vec<_>>>().unwrap(),
+            headers[1..]
+        );
+
+        let (request_id, iter) =
+            RequestPair::<Vec<BlockHeader>>::decode_streaming(&rlp_encode(&RequestPair {
+                request_id: 7,
+                message: Vec::<BlockHeader>::new(),
+            }))
+            .unwrap();
+        assert_eq!(request_id, 7);
+        assert_eq!(iter.count(), 0);
+
+        assert!(RequestPair::<Vec<BlockHeader>>::decode_streaming(&rlp_encode(&headers)).is_err());
+    }
+
+    #[test]
+    fn fork_id_text() {
+        for (text, fork_id) in [
+            (
+                "fc64ec04/1150000",
+                ForkId {
+                    hash: ForkHash(hex!("fc64ec04")),
+                    next: 1_150_000,
+                },
+            ),
+            (
+                "f0afd0e3/0",
+                ForkId {
+                    hash: ForkHash(hex!("f0afd0e3")),
+                    next: 0,
+                },
+            ),
+        ] {
+            assert_eq!(ForkIdText(fork_id).to_string(), text);
+            assert_eq!(text.parse::<ForkIdText>().unwrap(), ForkIdText(fork_id));
+        }
+
+        for invalid in [
+            "fc64ec04",
+            "fc64ec/0",
+            "fc64ec04xx/0",
+            "zz64ec04/0",
+            "fc64ec04/-1",
+            "",
+        ] {
+            assert!(invalid.parse::<ForkIdText>().is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn message_id_conversion() {
+        for id in (0..=16).filter_map(EthMessageId::from_u8) {
+            assert_eq!(EthMessageId::try_from_id(id.as_id()), Ok(id));
+            assert_eq!(u8::from(id), id as u8);
+        }
+
+        for id in [11, 12, 17, 0xff] {
+            assert_eq!(EthMessageId::try_from_id(id), Err(UnknownMessageId(id)));
+            assert_eq!(EthMessageId::try_from(id), Err(UnknownMessageId(id)));
+        }
+        assert_eq!(
+            UnknownMessageId(17).to_string(),
+            "unknown eth message id 0x11"
+        );
+    }
+
+    #[test]
+    fn get_node_data_is_dropped_in_eth67() {
+        assert!(EthMessageId::from_usize(11).is_none());
+
+        let request = GetNodeData {
+            request_id: 1,
+            hashes: vec![H256::repeat_byte(0x01)],
+        };
+        let encoded = rlp_encode(&request);
+        assert_eq!(
+            decode_eth_message(
+                EthMessageId::GetNodeData,
+                EthProtocolVersion::Eth66,
+                &encoded
+            )
+            .unwrap(),
+            EthMessage::GetNodeData(RequestPair {
+                request_id: request.request_id,
+                message: request.hashes,
+            })
+        );
+        assert!(decode_eth_message(
+            EthMessageId::GetNodeData,
+            EthProtocolVersion::Eth67,
+            &encoded
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn pooled_transaction_hashes_length_mismatch() {
+        let announcement = NewPooledTransactionHashes68 {
+            types: vec![0x02],
+            sizes: vec![120, 4096],
+            hashes: vec![H256::repeat_byte(0xaa), H256::repeat_byte(0xbb)],
+        };
+        let encoded = rlp_encode(&announcement);
+        assert!(
+            PooledTransactionHashes::decode_versioned(EthProtocolVersion::Eth68, &encoded).is_err()
+        );
+    }
+
+    #[test]
+    fn new_block_rlp() {
+        // Mainnet block 1 as announced by peers: the header, no transactions nor ommers, and the
+        // total difficulty of blocks 0 and 1
+        let encoded = hex!(
+            "f9021ff90216f90211"
+            "a0d4e56740f876aef8c010b86a40d5f56745a118d0906a34e69aec8c0db1cb8fa3"
+            "a01dcc4de8dec75d7aab85b567b6ccd41ad312451b948a7413f0a142fd40d49347"
+            "9405a56e2d52c817161883f50c441c3228cfe54d9f"
+            "a0d67e4d450343046425ae4271474353857ab860dbc0a1dde64b41b5cd3a532bf3"
+            "a056e81f171bcc55a6ff8345e692c0f86e5b48e01b996cadc001622fb5e363b421"
+            "a056e81f171bcc55a6ff8345e692c0f86e5b48e01b996cadc001622fb5e363b421"
+            "b90100"
+            "0000000000000000000000000000000000000000000000000000000000000000"
+            "0000000000000000000000000000000000000000000000000000000000000000"
+            "0000000000000000000000000000000000000000000000000000000000000000"
+            "0000000000000000000000000000000000000000000000000000000000000000"
+            "0000000000000000000000000000000000000000000000000000000000000000"
+            "0000000000000000000000000000000000000000000000000000000000000000"
+            "0000000000000000000000000000000000000000000000000000000000000000"
+            "0000000000000000000000000000000000000000000000000000000000000000"
+            "8503ff80000001821388808455ba4224"
+            "99476574682f76312e302e302f6c696e75782f676f312e342e32"
+            "a0969b900de27b6ac6a67742365dd65f55a0526c41fd18e1b16f1a1215c2e66f59"
+            "88539bd4979fef1ec4"
+            "c0c0"
+            "8507ff800000"
+        );
+
+        let decoded =
+            decode_eth_message(EthMessageId::NewBlock, EthProtocolVersion::Eth66, &encoded)
+                .unwrap();
+        let new_block = match &decoded {
+            EthMessage::NewBlock(new_block) => new_block,
+            other => panic!("unexpected message {:?}", other),
+        };
+        assert_eq!(
+            new_block.block.header.hash(),
+            H256(hex!(
+                "88e96d4537bea4d9c05d12549907b32561d3bf31f45aae734cdc119f13406cb6"
+            ))
+        );
+        assert_eq!(new_block.block.header.number, BlockNumber(1));
+        assert!(new_block.block.transactions.is_empty());
+        assert!(new_block.block.ommers.is_empty());
+        assert_eq!(new_block.total_difficulty, U256::from(34_351_349_760_u64));
+
+        assert_eq!(new_block.length(), encoded.len());
+        assert_eq!(
+            &decoded.encode_versioned(EthProtocolVersion::Eth66)[..],
+            &encoded[..]
+        );
+    }
+
+    #[test]
+    fn new_block_hashes_rlp() {
+        // Announcement of mainnet blocks 1 and 2
+        let encoded = hex!(
+            "f846"
+            "e2a088e96d4537bea4d9c05d12549907b32561d3bf31f45aae734cdc119f13406cb601"
+            "e2a0b495a1d7e6663152ae92708da4843337b958146015a2802f4193a410044698c902"
+        );
+        let announcement = NewBlockHashes::new(vec![
+            (
+                H256(hex!(
+                    "88e96d4537bea4d9c05d12549907b32561d3bf31f45aae734cdc119f13406cb6"
+                )),
+                BlockNumber(1),
+            ),
+            (
+                H256(hex!(
+                    "b495a1d7e6663152ae92708da4843337b958146015a2802f4193a410044698c9"
+                )),
+                BlockNumber(2),
+            ),
+        ]);
+
+        assert_eq!(
+            decode_eth_message(
+                EthMessageId::NewBlockHashes,
+                EthProtocolVersion::Eth66,
+                &encoded
+            )
+            .unwrap(),
+            EthMessage::NewBlockHashes(announcement.clone())
+        );
+        assert_eq!(&*rlp_encode(&announcement), &encoded[..]);
+
+        assert_eq!(
+            NewBlockHashes::decode(&mut &hex!("c0")[..]).unwrap(),
+            NewBlockHashes(vec![])
+        );
+    }
+
+    #[test]
+    fn new_block_acceptance() {
+        assert!(!accepts_new_block(&MAINNET));
+        assert!(accepts_new_block(&RINKEBY));
+    }
+
+    fn pooled_transactions() -> Vec<TransactionEnvelope> {
+        (0..3)
+            .map(|nonce| {
+                MessageWithSignature {
+                    message: crate::models::Message::Legacy {
+                        chain_id: Some(ChainId(1)),
+                        nonce,
+                        gas_price: 20_000_000_000_u64.into(),
+                        gas_limit: 21000,
+                        action: TransactionAction::Create,
+                        value: 0.as_u256(),
+                        input: Bytes::new(),
+                    },
+                    signature: MessageSignature::new(
+                        false,
+                        hex!("36b241b061a36a32ab7fe86c7aa9eb592dd59018cd0443adc0903590c16b02b0"),
+                        hex!("5edcc541b4741c5cc6dd347c5ed9577ef293a62787b4510465fadbfe39ee4094"),
+                    )
+                    .unwrap(),
+                }
+                .into()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn pooled_response_validation() {
+        let txs = pooled_transactions();
+        let hashes = txs
+            .iter()
+            .map(TransactionEnvelope::hash)
+            .collect::<Vec<_>>();
+
+        validate_pooled_response(&hashes, &txs).unwrap();
+        validate_pooled_response(&hashes, &txs[1..]).unwrap();
+        validate_pooled_response(&hashes, &[]).unwrap();
+
+        // Order of the response does not matter
+        let reversed = txs.iter().rev().cloned().collect::<Vec<_>>();
+        validate_pooled_response(&hashes, &reversed).unwrap();
+
+        // Unrequested transaction
+        assert!(validate_pooled_response(&hashes[..2], &txs[1..]).is_err());
+        assert!(validate_pooled_response(&[], &txs[..1]).is_err());
+
+        // Duplicate transaction
+        let duplicated = vec![txs[0].clone(), txs[0].clone()];
+        assert!(validate_pooled_response(&hashes, &duplicated).is_err());
+        assert!(validate_pooled_response(&hashes[..1], &duplicated).is_err());
+    }
+
+    #[test]
+    fn snappy_status_message() {
+        // eth/67 mainnet status, as sent by a synced peer
+        let rlp = hex!("f85643018a07aac59dabcdd74bc567a0feb27336ca7923f8fab3bd617fcb6e75841538f71c1bcfc267d7838489d9e13da0d4e56740f876aef8c010b86a40d5f56745a118d0906a34e69aec8c0db1cb8fa3c684b715077d80");
+        // Uncompressed length, then all of it as one literal
+        let mut compressed = vec![0x58, 0xf0, 0x57];
+        compressed.extend_from_slice(&rlp);
+
+        let mut snappy = Snappy::default();
+        let decompressed = snappy.decompress(&compressed).unwrap();
+        assert_eq!(&decompressed[..], &rlp[..]);
+
+        let status = StatusMessage::decode(&mut &*decompressed).unwrap();
+        assert_eq!(status.protocol_version, EthProtocolVersion::Eth67 as usize);
+        assert_eq!(status.network_id, 1);
+        assert_eq!(
+            status.genesis_hash,
+            mainnet_petersburg().status.fork_data.genesis
+        );
+        assert_eq!(
+            status.fork_id,
+            ForkId {
+                hash: ForkHash(hex!("b715077d")),
+                next: 0
+            }
+        );
+
+        let mut recompressed = BytesMut::new();
+        snappy.compress(&rlp, &mut recompressed).unwrap();
+        assert_eq!(&snappy.decompress(&recompressed).unwrap()[..], &rlp[..]);
+
+        // Declared size beyond the limit is refused before decompressing
+        let mut bounded = Snappy::new(rlp.len() - 1);
+        assert!(bounded.decompress(&compressed).is_err());
+        assert!(bounded.compress(&rlp, &mut BytesMut::new()).is_err());
+        let bomb = [0x80, 0x80, 0x80, 0x10, 0x00];
+        assert!(snappy.decompress(&bomb).is_err());
+    }
 }
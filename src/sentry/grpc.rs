@@ -53,7 +53,10 @@ impl From<EthProtocolVersion> for sentry::Protocol {
     fn from(version: EthProtocolVersion) -> Self {
         match version {
             EthProtocolVersion::Eth65 => Self::Eth65,
-            EthProtocolVersion::Eth66 => Self::Eth66,
+            // Sentry protocol only distinguishes eth/65 and eth/66 style message id sets.
+            EthProtocolVersion::Eth66 | EthProtocolVersion::Eth67 | EthProtocolVersion::Eth68 => {
+                Self::Eth66
+            }
         }
     }
 }
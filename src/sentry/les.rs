@@ -0,0 +1,264 @@
+//! Status handshake of the `les` light client protocol.
+//!
+//! Only the handshake is supported: we announce no `serve*` keys, so peers know not to request
+//! any data from us.
+
+use super::{devp2p::*, eth::*};
+use crate::models::*;
+use arrayvec::ArrayString;
+use bytes::BytesMut;
+use enum_primitive_derive::*;
+use ethereum_forkid::ForkId;
+use fastrlp::*;
+use std::collections::BTreeMap;
+
+pub fn les_capability_name() -> CapabilityName {
+    CapabilityName(ArrayString::from("les").unwrap())
+}
+
+/// The only les version we speak, the first one to carry fork ids.
+pub const LES_PROTOCOL_VERSION: CapabilityVersion = 4;
+
+/// Number of message ids reserved by les/4, up to `ResumeMsg` at 0x17.
+pub const LES_MESSAGE_ID_SPACE: CapabilityLength = 24;
+
+/// Les capability for the devp2p hello message.
+pub fn les_capabilities() -> BTreeMap<CapabilityId, CapabilityLength> {
+    [(
+        CapabilityId {
+            name: les_capability_name(),
+            version: LES_PROTOCOL_VERSION,
+        },
+        LES_MESSAGE_ID_SPACE,
+    )]
+    .into_iter()
+    .collect()
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Primitive)]
+pub enum LesMessageId {
+    Status = 0,
+}
+
+/// Les status, encoded as a list of key-value pairs rather than a fixed list of fields.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LesStatus {
+    pub protocol_version: u64,
+    pub network_id: u64,
+    pub head_td: U256,
+    pub head_hash: H256,
+    pub head_number: u64,
+    pub genesis_hash: H256,
+    pub fork_id: ForkId,
+    /// How the peer wants new heads announced, if it said.
+    pub announce_type: Option<u64>,
+}
+
+fn encode_pair<V: Encodable>(out: &mut dyn BufMut, key: &str, value: &V) {
+    Header {
+        list: true,
+        payload_length: key.as_bytes().length() + value.length(),
+    }
+    .encode(out);
+    key.as_bytes().encode(out);
+    value.encode(out);
+}
+
+impl LesStatus {
+    /// Status we announce, from the same status data as the eth one.
+    pub fn new(status: &FullStatusData) -> Self {
+        Self {
+            protocol_version: LES_PROTOCOL_VERSION as u64,
+            network_id: status.status.network_id,
+            head_td: status.status.total_difficulty,
+            head_hash: status.status.best_hash,
+            head_number: status.status.max_block,
+            genesis_hash: status.status.fork_data.genesis,
+            fork_id: status.fork_filter.current(),
+            // We do not follow the chain through les, so need no announcements.
+            announce_type: Some(0),
+        }
+    }
+
+    /// Checks that the peer's status describes the same network and a compatible chain.
+    pub fn validate_against(&self, ours: &FullStatusData) -> Result<(), HandshakeError> {
+        if self.protocol_version != LES_PROTOCOL_VERSION as u64 {
+            return Err(HandshakeError::ProtocolVersionMismatch {
+                negotiated: LES_PROTOCOL_VERSION,
+                theirs: self.protocol_version as usize,
+            });
+        }
+        check_network_id(ours.status.network_id, self.network_id)?;
+        check_genesis(ours.status.fork_data.genesis, self.genesis_hash)?;
+
+        Ok(ours.check_peer_fork(self.fork_id)?)
+    }
+
+    fn payload(&self) -> BytesMut {
+        let mut out = BytesMut::new();
+        encode_pair(&mut out, "protocolVersion", &self.protocol_version);
+        encode_pair(&mut out, "networkId", &self.network_id);
+        encode_pair(&mut out, "headTd", &self.head_td);
+        encode_pair(&mut out, "headHash", &self.head_hash);
+        encode_pair(&mut out, "headNum", &self.head_number);
+        encode_pair(&mut out, "genesisHash", &self.genesis_hash);
+        encode_pair(&mut out, "forkID", &self.fork_id);
+        if let Some(announce_type) = self.announce_type {
+            encode_pair(&mut out, "announceType", &announce_type);
+        }
+        out
+    }
+}
+
+impl Encodable for LesStatus {
+    fn encode(&self, out: &mut dyn BufMut) {
+        let payload = self.payload();
+        Header {
+            list: true,
+            payload_length: payload.len(),
+        }
+        .encode(out);
+        out.put_slice(&payload);
+    }
+
+    fn length(&self) -> usize {
+        let payload_length = self.payload().len();
+        length_of_length(payload_length) + payload_length
+    }
+}
+
+impl Decodable for LesStatus {
+    fn decode(buf: &mut &[u8]) -> Result<Self, DecodeError> {
+        fn split_list<'a>(buf: &mut &'a [u8]) -> Result<&'a [u8], DecodeError> {
+            let header = Header::decode(buf)?;
+            if !header.list {
+                return Err(DecodeError::UnexpectedString);
+            }
+            if buf.len() < header.payload_length {
+                return Err(DecodeError::InputTooShort);
+            }
+            let (payload, rest) = buf.split_at(header.payload_length);
+            *buf = rest;
+            Ok(payload)
+        }
+
+        let mut payload = split_list(buf)?;
+
+        let mut protocol_version = None;
+        let mut network_id = None;
+        let mut head_td = None;
+        let mut head_hash = None;
+        let mut head_number = None;
+        let mut genesis_hash = None;
+        let mut fork_id = None;
+        let mut announce_type = None;
+        while !payload.is_empty() {
+            let value = &mut split_list(&mut payload)?;
+            match &*BytesMut::decode(value)? {
+                b"protocolVersion" => protocol_version = Some(u64::decode(value)?),
+                b"networkId" => network_id = Some(u64::decode(value)?),
+                b"headTd" => head_td = Some(U256::decode(value)?),
+                b"headHash" => head_hash = Some(H256::decode(value)?),
+                b"headNum" => head_number = Some(u64::decode(value)?),
+                b"genesisHash" => genesis_hash = Some(H256::decode(value)?),
+                b"forkID" => fork_id = Some(ForkId::decode(value)?),
+                b"announceType" => announce_type = Some(u64::decode(value)?),
+                // Serving and flow control parameters, of no use to a peer serving nothing.
+                _ => {}
+            }
+        }
+
+        Ok(Self {
+            protocol_version: protocol_version
+                .ok_or(DecodeError::Custom("les status has no protocolVersion"))?,
+            network_id: network_id.ok_or(DecodeError::Custom("les status has no networkId"))?,
+            head_td: head_td.ok_or(DecodeError::Custom("les status has no headTd"))?,
+            head_hash: head_hash.ok_or(DecodeError::Custom("les status has no headHash"))?,
+            head_number: head_number.ok_or(DecodeError::Custom("les status has no headNum"))?,
+            genesis_hash: genesis_hash
+                .ok_or(DecodeError::Custom("les status has no genesisHash"))?,
+            fork_id: fork_id.ok_or(DecodeError::Custom("les status has no forkID"))?,
+            announce_type,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ethereum_forkid::ForkHash;
+    use hex_literal::hex;
+    use num_traits::{FromPrimitive, ToPrimitive};
+
+    fn rlp_encode<T: Encodable>(v: &T) -> BytesMut {
+        let mut out = BytesMut::new();
+        v.encode(&mut out);
+        out
+    }
+
+    fn rlp_list(payload: &[u8]) -> BytesMut {
+        let mut out = BytesMut::new();
+        Header {
+            list: true,
+            payload_length: payload.len(),
+        }
+        .encode(&mut out);
+        out.put_slice(payload);
+        out
+    }
+
+    fn les_status() -> LesStatus {
+        LesStatus {
+            protocol_version: 4,
+            network_id: 1,
+            head_td: 0x1234.as_u256(),
+            head_hash: H256::repeat_byte(1),
+            head_number: 7_987_396,
+            genesis_hash: H256::repeat_byte(2),
+            fork_id: ForkId {
+                hash: ForkHash(hex!("668db0af")),
+                next: 0,
+            },
+            announce_type: Some(1),
+        }
+    }
+
+    #[test]
+    fn les_capability() {
+        assert_eq!(les_capability_name().0.as_str(), "les");
+        assert_eq!(LesMessageId::Status.to_usize(), Some(0));
+        assert_eq!(LesMessageId::from_usize(1), None);
+        assert_eq!(les_capabilities().len(), 1);
+    }
+
+    #[test]
+    fn les_status_rlp() {
+        let status = les_status();
+        let encoded = rlp_encode(&status);
+        assert_eq!(encoded.len(), status.length());
+        assert_eq!(decode_strict::<LesStatus>(&encoded).unwrap(), status);
+
+        // Unknown keys are skipped, whatever their value
+        let mut payload = status.payload();
+        encode_pair(&mut payload, "serveHeaders", &BytesMut::new());
+        encode_pair(&mut payload, "flowControl/MRC", &vec![vec![2_u64, 1, 1]]);
+        let encoded = rlp_list(&payload);
+        assert_eq!(decode_strict::<LesStatus>(&encoded).unwrap(), status);
+
+        // Announce type is optional, the rest is not
+        let status = LesStatus {
+            announce_type: None,
+            ..les_status()
+        };
+        let encoded = rlp_encode(&status);
+        assert_eq!(decode_strict::<LesStatus>(&encoded).unwrap(), status);
+
+        let mut payload = BytesMut::new();
+        encode_pair(&mut payload, "protocolVersion", &4_u64);
+        let encoded = rlp_list(&payload);
+        assert!(matches!(
+            decode_strict::<LesStatus>(&encoded),
+            Err(DecodeError::Custom("les status has no networkId"))
+        ));
+    }
+}
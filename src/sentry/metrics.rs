@@ -0,0 +1,237 @@
+use super::eth::{EthMessageId, ETH_MESSAGE_ID_SPACE};
+use num_traits::FromPrimitive;
+use std::{
+    fmt::Write,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpListener,
+};
+
+/// Upper bounds of the request latency histogram buckets, in milliseconds.
+pub const LATENCY_BUCKETS_MS: [u64; 8] = [10, 50, 100, 250, 500, 1000, 2500, 5000];
+
+#[derive(Debug, Default)]
+struct Histogram {
+    /// Cumulative counts, one per bucket of [`LATENCY_BUCKETS_MS`].
+    buckets: [AtomicU64; LATENCY_BUCKETS_MS.len()],
+    count: AtomicU64,
+    sum_ms: AtomicU64,
+}
+
+impl Histogram {
+    fn observe(&self, value: Duration) {
+        let ms = value.as_millis().try_into().unwrap_or(u64::MAX);
+        for (bucket, &le) in self.buckets.iter().zip(&LATENCY_BUCKETS_MS) {
+            if ms <= le {
+                bucket.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum_ms.fetch_add(ms, Ordering::Relaxed);
+    }
+}
+
+#[derive(Debug, Default)]
+struct MetricsInner {
+    received: [AtomicU64; ETH_MESSAGE_ID_SPACE],
+    sent: [AtomicU64; ETH_MESSAGE_ID_SPACE],
+    latency: [Histogram; ETH_MESSAGE_ID_SPACE],
+}
+
+/// Eth message counters and request latencies, shared by all clones of the handle.
+#[derive(Clone, Debug, Default)]
+pub struct Metrics(Arc<MetricsInner>);
+
+impl Metrics {
+    pub fn record_received(&self, id: EthMessageId) {
+        self.0.received[id as usize].fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_sent(&self, id: EthMessageId) {
+        self.0.sent[id as usize].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Records the time it took for the request to be answered.
+    pub fn observe_latency(&self, request: EthMessageId, latency: Duration) {
+        self.0.latency[request as usize].observe(latency);
+    }
+
+    pub fn received(&self, id: EthMessageId) -> u64 {
+        self.0.received[id as usize].load(Ordering::Relaxed)
+    }
+
+    pub fn sent(&self, id: EthMessageId) -> u64 {
+        self.0.sent[id as usize].load(Ordering::Relaxed)
+    }
+
+    /// Number of answered requests of this kind.
+    pub fn answered(&self, request: EthMessageId) -> u64 {
+        self.0.latency[request as usize]
+            .count
+            .load(Ordering::Relaxed)
+    }
+
+    /// Renders the metrics in the Prometheus text exposition format.
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        let ids = (0..ETH_MESSAGE_ID_SPACE)
+            .filter_map(EthMessageId::from_usize)
+            .collect::<Vec<_>>();
+
+        for (name, help, counters) in [
+            (
+                "eth_messages_received_total",
+                "Eth messages received from peers.",
+                &self.0.received,
+            ),
+            (
+                "eth_messages_sent_total",
+                "Eth messages sent to peers.",
+                &self.0.sent,
+            ),
+        ] {
+            let _ = writeln!(out, "# HELP {} {}", name, help);
+            let _ = writeln!(out, "# TYPE {} counter", name);
+            for &id in &ids {
+                let _ = writeln!(
+                    out,
+                    "{}{{message=\"{:?}\"}} {}",
+                    name,
+                    id,
+                    counters[id as usize].load(Ordering::Relaxed)
+                );
+            }
+        }
+
+        let name = "eth_request_latency_ms";
+        let _ = writeln!(out, "# HELP {} Time for requests to be answered.", name);
+        let _ = writeln!(out, "# TYPE {} histogram", name);
+        for id in ids.into_iter().filter(|id| id.response_id().is_some()) {
+            let histogram = &self.0.latency[id as usize];
+            let count = histogram.count.load(Ordering::Relaxed);
+            for (bucket, le) in histogram.buckets.iter().zip(LATENCY_BUCKETS_MS) {
+                let _ = writeln!(
+                    out,
+                    "{}_bucket{{message=\"{:?}\",le=\"{}\"}} {}",
+                    name,
+                    id,
+                    le,
+                    bucket.load(Ordering::Relaxed)
+                );
+            }
+            let _ = writeln!(
+                out,
+                "{}_bucket{{message=\"{:?}\",le=\"+Inf\"}} {}",
+                name, id, count
+            );
+            let _ = writeln!(
+                out,
+                "{}_sum{{message=\"{:?}\"}} {}",
+                name,
+                id,
+                histogram.sum_ms.load(Ordering::Relaxed)
+            );
+            let _ = writeln!(out, "{}_count{{message=\"{:?}\"}} {}", name, id, count);
+        }
+
+        out
+    }
+
+    /// Answers every connection to the listener with [`Self::render`] over HTTP, whatever the
+    /// request, which is all a Prometheus scraper needs.
+    pub async fn serve(self, listener: TcpListener) -> anyhow::Result<()> {
+        loop {
+            let (mut stream, _) = listener.accept().await?;
+            let metrics = self.clone();
+            tokio::spawn(async move {
+                let mut request = [0; 1024];
+                let _ = stream.read(&mut request).await;
+
+                let body = metrics.render();
+                let _ = stream
+                    .write_all(
+                        format!(
+                            "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                            body.len(),
+                            body
+                        )
+                        .as_bytes(),
+                    )
+                    .await;
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counters() {
+        let metrics = Metrics::default();
+        let handle = metrics.clone();
+
+        handle.record_received(EthMessageId::Status);
+        handle.record_received(EthMessageId::Status);
+        handle.record_sent(EthMessageId::GetBlockHeaders);
+
+        assert_eq!(metrics.received(EthMessageId::Status), 2);
+        assert_eq!(metrics.sent(EthMessageId::Status), 0);
+        assert_eq!(metrics.sent(EthMessageId::GetBlockHeaders), 1);
+        assert_eq!(metrics.received(EthMessageId::Receipts), 0);
+    }
+
+    #[test]
+    fn latency_histogram() {
+        let metrics = Metrics::default();
+        for ms in [5, 40, 3000, 10_000] {
+            metrics.observe_latency(EthMessageId::GetBlockBodies, Duration::from_millis(ms));
+        }
+        assert_eq!(metrics.answered(EthMessageId::GetBlockBodies), 4);
+        assert_eq!(metrics.answered(EthMessageId::GetReceipts), 0);
+
+        let rendered = metrics.render();
+        for line in [
+            "eth_messages_received_total{message=\"Status\"} 0",
+            "eth_request_latency_ms_bucket{message=\"GetBlockBodies\",le=\"10\"} 1",
+            "eth_request_latency_ms_bucket{message=\"GetBlockBodies\",le=\"50\"} 2",
+            "eth_request_latency_ms_bucket{message=\"GetBlockBodies\",le=\"5000\"} 3",
+            "eth_request_latency_ms_bucket{message=\"GetBlockBodies\",le=\"+Inf\"} 4",
+            "eth_request_latency_ms_sum{message=\"GetBlockBodies\"} 13045",
+            "eth_request_latency_ms_count{message=\"GetBlockBodies\"} 4",
+        ] {
+            assert!(rendered.lines().any(|l| l == line), "{}", line);
+        }
+        assert!(!rendered.contains("eth_request_latency_ms_count{message=\"Status\"}"));
+    }
+
+    #[tokio::test]
+    async fn metrics_endpoint() {
+        let metrics = Metrics::default();
+        metrics.record_received(EthMessageId::Status);
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(metrics.serve(listener));
+
+        let mut stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+        stream
+            .write_all(b"GET /metrics HTTP/1.1\r\n\r\n")
+            .await
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response
+            .lines()
+            .any(|l| l == "eth_messages_received_total{message=\"Status\"} 1"));
+    }
+}
@@ -0,0 +1,109 @@
+//! In-memory eth peer, for testing handshakes and request flows without sockets.
+
+use super::{devp2p::Message, eth::*};
+use anyhow::{anyhow, bail};
+use futures::{
+    channel::mpsc::{unbounded, UnboundedReceiver, UnboundedSender},
+    Sink, SinkExt, Stream, StreamExt,
+};
+use num_traits::ToPrimitive;
+use std::{
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+/// Our end of the connection to a [`MockPeer`], to be handed to the code under test.
+#[derive(Debug)]
+pub struct MockStream {
+    tx: UnboundedSender<Message>,
+    rx: UnboundedReceiver<Message>,
+}
+
+impl Stream for MockStream {
+    type Item = anyhow::Result<Message>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.get_mut().rx.poll_next_unpin(cx).map(|v| v.map(Ok))
+    }
+}
+
+impl Sink<Message> for MockStream {
+    type Error = anyhow::Error;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<anyhow::Result<()>> {
+        self.get_mut().tx.poll_ready_unpin(cx).map_err(From::from)
+    }
+
+    fn start_send(self: Pin<&mut Self>, item: Message) -> anyhow::Result<()> {
+        Ok(self.get_mut().tx.start_send_unpin(item)?)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<anyhow::Result<()>> {
+        self.get_mut().tx.poll_flush_unpin(cx).map_err(From::from)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<anyhow::Result<()>> {
+        self.get_mut().tx.poll_close_unpin(cx).map_err(From::from)
+    }
+}
+
+/// Remote peer speaking eth at a fixed protocol version.
+#[derive(Debug)]
+pub struct MockPeer {
+    protocol_version: EthProtocolVersion,
+    tx: UnboundedSender<Message>,
+    rx: UnboundedReceiver<Message>,
+}
+
+impl MockPeer {
+    /// Connected peer and our end of the connection.
+    pub fn pair(protocol_version: EthProtocolVersion) -> (Self, MockStream) {
+        let (our_tx, peer_rx) = unbounded();
+        let (peer_tx, our_rx) = unbounded();
+
+        (
+            Self {
+                protocol_version,
+                tx: peer_tx,
+                rx: peer_rx,
+            },
+            MockStream {
+                tx: our_tx,
+                rx: our_rx,
+            },
+        )
+    }
+
+    pub async fn send(&mut self, message: EthMessage) -> anyhow::Result<()> {
+        self.send_raw(Message {
+            id: message.id().to_usize().unwrap(),
+            data: message.encode_versioned(self.protocol_version),
+        })
+        .await
+    }
+
+    /// Sends a message as is, valid or not.
+    pub async fn send_raw(&mut self, message: Message) -> anyhow::Result<()> {
+        Ok(self.tx.send(message).await?)
+    }
+
+    /// Receives the next message, failing if it is not of the expected kind.
+    pub async fn expect(&mut self, id: EthMessageId) -> anyhow::Result<EthMessage> {
+        let message = self
+            .rx
+            .next()
+            .await
+            .ok_or_else(|| anyhow!("connection closed while expecting {:?}", id))?;
+
+        if message.id != id.to_usize().unwrap() {
+            bail!("expected {:?}, got message {}", id, message.id);
+        }
+
+        decode_eth_message(id, self.protocol_version, &message.data)
+    }
+
+    /// Stops sending, so that our end sees the stream finish.
+    pub fn close(&mut self) {
+        self.tx.close_channel();
+    }
+}
@@ -1,12 +1,24 @@
 #![allow(dead_code, clippy::upper_case_acronyms)]
 
-use self::eth::*;
+use self::{
+    eth::*,
+    les::*,
+    metrics::Metrics,
+    reputation::{
+        PeerBanList, PeerReputation, ReputationEvent, DEFAULT_BAN_DURATION, DEFAULT_BAN_THRESHOLD,
+        DEFAULT_VIOLATION_WINDOW,
+    },
+};
 use crate::{
-    binutil::AkulaDataDir, models::P2PParams, sentry::services::SentryService, version_string,
+    binutil::AkulaDataDir,
+    models::{P2PParams, H256, U256},
+    sentry::services::SentryService,
+    version_string,
 };
 use anyhow::{format_err, Context};
 use async_stream::stream;
 use async_trait::async_trait;
+use bytes::BytesMut;
 use cidr::IpCidr;
 use clap::Parser;
 use derive_more::FromStr;
@@ -14,9 +26,8 @@ use devp2p::*;
 use disc::dns::Resolver;
 use educe::Educe;
 use ethereum_interfaces::sentry::{self, sentry_server::SentryServer, InboundMessage, PeerEvent};
-use fastrlp::Decodable;
+use fastrlp::Encodable;
 use futures::stream::BoxStream;
-use maplit::btreemap;
 use num_traits::{FromPrimitive, ToPrimitive};
 use parking_lot::RwLock;
 use secp256k1::{PublicKey, SecretKey, SECP256K1};
@@ -30,12 +41,12 @@ use std::{
         atomic::{AtomicBool, Ordering},
         Arc,
     },
-    time::Duration,
+    time::{Duration, Instant},
 };
 use task_group::TaskGroup;
 use tokio::sync::{
     broadcast::{channel as broadcast_channel, Sender as BroadcastSender},
-    mpsc::{channel, Sender},
+    mpsc::{channel, error::TrySendError, Sender},
     Mutex as AsyncMutex,
 };
 use tokio_stream::StreamExt;
@@ -46,7 +57,13 @@ use trust_dns_resolver::TokioAsyncResolver;
 pub mod devp2p;
 pub mod eth;
 pub mod grpc;
+pub mod les;
+pub mod metrics;
+#[cfg(test)]
+mod mock;
+pub mod reputation;
 pub mod services;
+pub mod snap;
 
 type OutboundSender = Sender<OutboundEvent>;
 type OutboundReceiver = Arc<AsyncMutex<BoxStream<'static, OutboundEvent>>>;
@@ -76,6 +93,8 @@ pub struct Pipes {
 struct BlockTracker {
     block_by_peer: HashMap<PeerId, u64>,
     peers_by_block: BTreeMap<u64, HashSet<PeerId>>,
+    /// When the peer was set up or last moved to a higher block.
+    advanced_at: HashMap<PeerId, Instant>,
 }
 
 impl BlockTracker {
@@ -89,7 +108,7 @@ impl BlockTracker {
                 }
             }
             HashMapEntry::Occupied(mut e) => {
-                if *e.get() > block {
+                if *e.get() >= block {
                     return;
                 }
                 let old_block = std::mem::replace(e.get_mut(), block);
@@ -104,9 +123,11 @@ impl BlockTracker {
         }
 
         self.peers_by_block.entry(block).or_default().insert(peer);
+        self.advanced_at.insert(peer, Instant::now());
     }
 
     fn remove_peer(&mut self, peer: PeerId) {
+        self.advanced_at.remove(&peer);
         if let Some(block) = self.block_by_peer.remove(&peer) {
             if let Entry::Occupied(mut entry) = self.peers_by_block.entry(block) {
                 entry.get_mut().remove(&peer);
@@ -125,6 +146,102 @@ impl BlockTracker {
             .copied()
             .collect()
     }
+
+    /// Whether the peer has not moved to a higher block for longer than `max_idle`.
+    fn is_stalled(&self, peer: PeerId, max_idle: Duration) -> bool {
+        self.advanced_at
+            .get(&peer)
+            .map_or(false, |advanced_at| advanced_at.elapsed() > max_idle)
+    }
+
+    /// One of the peers furthest ahead, if any has reached the block.
+    fn best_peer_for(&self, block: u64) -> Option<PeerId> {
+        self.peers_by_block
+            .range(block..)
+            .next_back()
+            .and_then(|(_, peers)| peers.iter().next().copied())
+    }
+}
+
+/// Block number of a `NewBlock` message, read off its header without decoding the whole block.
+fn new_block_number(mut data: &[u8]) -> Result<u64, fastrlp::DecodeError> {
+    fn item(buf: &mut &[u8]) -> Result<fastrlp::Header, fastrlp::DecodeError> {
+        let h = fastrlp::Header::decode(buf)?;
+        if buf.len() < h.payload_length {
+            return Err(fastrlp::DecodeError::InputTooShort);
+        }
+        Ok(h)
+    }
+
+    // Message, block and header lists
+    for _ in 0..3 {
+        if !item(&mut data)?.list {
+            return Err(fastrlp::DecodeError::UnexpectedString);
+        }
+    }
+    // Header fields up to the difficulty
+    for _ in 0..8 {
+        let h = item(&mut data)?;
+        data = &data[h.payload_length..];
+    }
+
+    <u64 as fastrlp::Decodable>::decode(&mut data)
+}
+
+/// Highest block number announced in a `NewBlockHashes` or `NewBlock` message.
+fn announced_block(
+    id: EthMessageId,
+    protocol_version: EthProtocolVersion,
+    data: &[u8],
+) -> Option<u64> {
+    match id {
+        // Blocks are relayed as is, so there is no point in decoding more than their number.
+        EthMessageId::NewBlock => match new_block_number(data) {
+            Ok(block) => Some(block),
+            Err(e) => {
+                debug!("Failed to decode {:?} announcement: {}", id, e);
+                None
+            }
+        },
+        EthMessageId::NewBlockHashes => match decode_eth_message(id, protocol_version, data) {
+            Ok(EthMessage::NewBlockHashes(announcement)) => {
+                announcement.0.iter().map(|block| block.number.0).max()
+            }
+            Ok(_) => None,
+            Err(e) => {
+                debug!("Failed to decode {:?} announcement: {}", id, e);
+                None
+            }
+        },
+        _ => None,
+    }
+}
+
+/// Reason to disconnect a peer after an inbound event.
+#[derive(Clone, Copy, Debug)]
+struct InboundError {
+    reason: DisconnectReason,
+    /// Whether the event was already counted against the peer in the ban list, so that it is not
+    /// counted twice.
+    violation_recorded: bool,
+}
+
+impl From<DisconnectReason> for InboundError {
+    fn from(reason: DisconnectReason) -> Self {
+        Self {
+            reason,
+            violation_recorded: false,
+        }
+    }
+}
+
+/// Progress of the eth handshake with a peer.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HandshakeState {
+    /// Peer has not sent a valid `Status` yet.
+    Pending,
+    /// Peer's `Status` was accepted, any further one is a protocol breach.
+    Done,
 }
 
 #[derive(Educe)]
@@ -134,9 +251,28 @@ pub struct CapabilityServerImpl {
     pub peer_pipes: Arc<RwLock<HashMap<PeerId, Pipes>>>,
     block_tracker: Arc<RwLock<BlockTracker>>,
 
+    capability_name: CapabilityName,
     status_message: Arc<RwLock<Option<FullStatusData>>>,
     protocol_version: EthProtocolVersion,
+    peer_protocol_versions: Arc<RwLock<HashMap<PeerId, EthProtocolVersion>>>,
     valid_peers: Arc<RwLock<HashSet<PeerId>>>,
+    /// Total difficulty and best hash each peer sent in its status.
+    peer_heads: Arc<RwLock<HashMap<PeerId, (U256, H256)>>>,
+    message_limits: MessageLimits,
+    handshake_timeout: Duration,
+    /// When each peer still in the handshake is disconnected, unless it completes it first.
+    handshake_deadlines: Arc<RwLock<HashMap<PeerId, Instant>>>,
+    accept_new_block: bool,
+    les: bool,
+    /// Peers sharing only les with us, which never get past the status handshake. They are kept
+    /// apart from `peer_pipes`, so that eth messages are never sent to them.
+    les_peers: Arc<RwLock<HashMap<PeerId, (Pipes, HandshakeState)>>>,
+    metrics: Metrics,
+    /// Requests sent to each eth/66+ peer that are awaiting a response.
+    pending_requests: Arc<RwLock<HashMap<PeerId, PendingRequests>>>,
+    ban_list: Arc<RwLock<PeerBanList>>,
+    reputation: Arc<RwLock<PeerReputation>>,
+    fork_monitor: Arc<RwLock<ForkMonitor>>,
 
     data_sender: BroadcastSender<InboundMessage>,
     peers_status_sender: BroadcastSender<PeerEvent>,
@@ -149,25 +285,114 @@ impl CapabilityServerImpl {
         Self {
             peer_pipes: Default::default(),
             block_tracker: Default::default(),
+            capability_name: capability_name(),
             status_message: Default::default(),
             protocol_version,
+            peer_protocol_versions: Default::default(),
             valid_peers: Default::default(),
+            peer_heads: Default::default(),
+            message_limits: Default::default(),
+            handshake_timeout: DEFAULT_HANDSHAKE_TIMEOUT,
+            handshake_deadlines: Default::default(),
+            accept_new_block: true,
+            les: false,
+            les_peers: Default::default(),
+            metrics: Default::default(),
+            pending_requests: Default::default(),
+            ban_list: Default::default(),
+            reputation: Default::default(),
+            fork_monitor: Default::default(),
             data_sender: broadcast_channel(max_peers.get() * BUFFERING_FACTOR).0,
             peers_status_sender: broadcast_channel(max_peers.get()).0,
             no_new_peers: Arc::new(AtomicBool::new(true)),
         }
     }
 
+    pub fn with_message_limits(mut self, message_limits: MessageLimits) -> Self {
+        self.message_limits = message_limits;
+        self
+    }
+
+    /// Time peers have to complete the eth or les status handshake before they are disconnected.
+    pub fn with_handshake_timeout(mut self, handshake_timeout: Duration) -> Self {
+        self.handshake_timeout = handshake_timeout;
+        self
+    }
+
+    /// Ban peers according to the given list's threshold and durations.
+    pub fn with_ban_list(mut self, ban_list: PeerBanList) -> Self {
+        self.ban_list = Arc::new(RwLock::new(ban_list));
+        self
+    }
+
+    /// Disconnect peers once their score falls to the given reputation's threshold.
+    pub fn with_reputation(mut self, reputation: PeerReputation) -> Self {
+        self.reputation = Arc::new(RwLock::new(reputation));
+        self
+    }
+
+    pub fn is_banned(&self, peer: PeerId) -> bool {
+        self.ban_list.read().is_banned(peer, Instant::now())
+    }
+
+    /// Set whether inbound `NewBlock` announcements are accepted, see [`accepts_new_block`].
+    pub fn with_new_block_acceptance(mut self, accept_new_block: bool) -> Self {
+        self.accept_new_block = accept_new_block;
+        self
+    }
+
+    /// Serve eth under another capability name than `eth`, so that it can be told apart from
+    /// other capabilities hosted on the same node.
+    pub fn with_capability_name(mut self, capability_name: CapabilityName) -> Self {
+        self.capability_name = capability_name;
+        self
+    }
+
+    pub fn capability_name(&self) -> CapabilityName {
+        self.capability_name
+    }
+
+    /// Set whether les is advertised too. Peers sharing only les with us complete the les status
+    /// handshake, but are not served any data.
+    pub fn with_les(mut self, les: bool) -> Self {
+        self.les = les;
+        self
+    }
+
+    /// Capabilities to advertise in the devp2p hello message, to be merged with those of any
+    /// other capability servers.
+    pub fn capabilities(&self) -> BTreeMap<CapabilityId, CapabilityLength> {
+        let mut capabilities = supported_capabilities_named(self.capability_name);
+        if self.les {
+            capabilities.extend(les_capabilities());
+        }
+        capabilities
+    }
+
+    /// Count eth messages into the given handle, for sharing it with other components.
+    pub fn with_metrics(mut self, metrics: Metrics) -> Self {
+        self.metrics = metrics;
+        self
+    }
+
+    pub fn metrics(&self) -> &Metrics {
+        &self.metrics
+    }
+
     pub fn no_new_peers_handle(&self) -> Arc<AtomicBool> {
         self.no_new_peers.clone()
     }
 
-    fn setup_peer(&self, peer: PeerId, p: Pipes) {
+    fn setup_peer(&self, peer: PeerId, p: Pipes, protocol_version: EthProtocolVersion) {
         let mut pipes = self.peer_pipes.write();
         let mut block_tracker = self.block_tracker.write();
+        let mut peer_protocol_versions = self.peer_protocol_versions.write();
+        let mut pending_requests = self.pending_requests.write();
 
         assert!(pipes.insert(peer, p).is_none());
         block_tracker.set_block_number(peer, 0, true);
+        peer_protocol_versions.insert(peer, protocol_version);
+        pending_requests.insert(peer, PendingRequests::new(DEFAULT_REQUEST_TIMEOUT));
     }
 
     fn get_pipes(&self, peer: PeerId) -> Option<Pipes> {
@@ -182,21 +407,41 @@ impl CapabilityServerImpl {
     }
 
     fn receiver(&self, peer: PeerId) -> Option<OutboundReceiver> {
-        self.peer_pipes
-            .read()
-            .get(&peer)
-            .map(|pipes| pipes.receiver.clone())
+        self.any_pipes(peer).map(|pipes| pipes.receiver)
+    }
+
+    /// Pipes of a peer, eth or les-only.
+    fn any_pipes(&self, peer: PeerId) -> Option<Pipes> {
+        self.get_pipes(peer).or_else(|| {
+            self.les_peers
+                .read()
+                .get(&peer)
+                .map(|(pipes, _)| pipes.clone())
+        })
     }
 
     #[instrument(name = "CapabilityServerImpl.teardown_peer", skip(self))]
     fn teardown_peer(&self, peer: PeerId) {
+        self.handshake_deadlines.write().remove(&peer);
+        if self.les_peers.write().remove(&peer).is_some() {
+            // Never reported as connected, nor set up for eth.
+            return;
+        }
+
         let mut pipes = self.peer_pipes.write();
         let mut block_tracker = self.block_tracker.write();
         let mut valid_peers = self.valid_peers.write();
+        let mut peer_protocol_versions = self.peer_protocol_versions.write();
+        let mut pending_requests = self.pending_requests.write();
 
         pipes.remove(&peer);
         block_tracker.remove_peer(peer);
         valid_peers.remove(&peer);
+        self.peer_heads.write().remove(&peer);
+        peer_protocol_versions.remove(&peer);
+        pending_requests.remove(&peer);
+        self.reputation.write().remove(peer);
+        self.fork_monitor.write().remove_peer(peer);
 
         let send_status_result =
             self.peers_status_sender
@@ -218,30 +463,260 @@ impl CapabilityServerImpl {
         self.valid_peers.read().len()
     }
 
+    /// Number of peers that completed the handshake, by the protocol version they speak.
+    pub fn capability_stats(&self) -> BTreeMap<EthProtocolVersion, usize> {
+        let valid_peers = self.valid_peers.read();
+        let peer_protocol_versions = self.peer_protocol_versions.read();
+
+        let mut stats = BTreeMap::new();
+        for peer in valid_peers.iter() {
+            if let Some(&version) = peer_protocol_versions.get(peer) {
+                *stats.entry(version).or_default() += 1;
+            }
+        }
+        stats
+    }
+
+    /// Connected peer that announced the block or a later one, to fetch it from.
+    pub fn best_peer_for(&self, block: u64) -> Option<PeerId> {
+        self.block_tracker.read().best_peer_for(block)
+    }
+
+    /// Peer to sync from: the one with the highest total difficulty on proof-of-work chains, and
+    /// the one that announced the highest block since the handshake after the Merge, as total
+    /// difficulty no longer grows then. Ties are broken by best hash.
+    pub fn best_sync_peer(&self, mode: ChainMode) -> Option<PeerId> {
+        // Locked in the same order as on teardown
+        let block_tracker = self.block_tracker.read();
+        let valid_peers = self.valid_peers.read();
+        let peer_heads = self.peer_heads.read();
+        match mode {
+            ChainMode::PoW => peer_heads
+                .iter()
+                .filter(|(peer, _)| valid_peers.contains(peer))
+                .max_by_key(|(_, &head)| head)
+                .map(|(&peer, _)| peer),
+            ChainMode::PoS => block_tracker
+                .peers_by_block
+                .values()
+                .rev()
+                .find_map(|peers| {
+                    peers
+                        .iter()
+                        .filter(|peer| valid_peers.contains(peer))
+                        .max_by_key(|peer| peer_heads.get(peer).map(|&(_, best_hash)| best_hash))
+                })
+                .copied(),
+        }
+    }
+
+    /// Whether the peer's announced head has not advanced for longer than `max_idle`, so that it
+    /// may be dropped in favor of active peers. Unknown peers are never stalled.
+    pub fn is_stalled(&self, peer: PeerId, max_idle: Duration) -> bool {
+        self.block_tracker.read().is_stalled(peer, max_idle)
+    }
+
+    pub fn handshake_state(&self, peer: PeerId) -> HandshakeState {
+        if self.valid_peers.read().contains(&peer) {
+            HandshakeState::Done
+        } else {
+            HandshakeState::Pending
+        }
+    }
+
+    /// Progress of the les handshake, `None` unless the peer shares only les with us.
+    pub fn les_handshake_state(&self, peer: PeerId) -> Option<HandshakeState> {
+        self.les_peers.read().get(&peer).map(|&(_, state)| state)
+    }
+
+    fn peer_protocol_version(&self, peer: PeerId) -> EthProtocolVersion {
+        self.peer_protocol_versions
+            .read()
+            .get(&peer)
+            .copied()
+            .unwrap_or(self.protocol_version)
+    }
+
+    /// Applies the event to the peer's score, failing once the peer should be disconnected.
+    fn record_reputation(
+        &self,
+        peer: PeerId,
+        event: ReputationEvent,
+    ) -> Result<(), DisconnectReason> {
+        if self.reputation.write().record_event(peer, event) {
+            debug!("Peer reputation exhausted after {:?}! Kicking peer.", event);
+
+            return Err(DisconnectReason::UselessPeer);
+        }
+
+        Ok(())
+    }
+
+    /// Starts tracking an outbound request, so that the latency of its response is measured.
+    ///
+    /// Requests that went unanswered for too long count against the peer.
+    fn register_request(
+        &self,
+        peer: PeerId,
+        id: EthMessageId,
+        data: &[u8],
+    ) -> Result<(), DisconnectReason> {
+        if id.response_id().is_none()
+            || self.peer_protocol_version(peer) == EthProtocolVersion::Eth65
+        {
+            return Ok(());
+        }
+
+        let request_id = match peek_request_id(data) {
+            Ok(request_id) => request_id,
+            Err(e) => {
+                debug!("Failed to read request id of outbound {:?}: {}", id, e);
+                return Ok(());
+            }
+        };
+
+        if let Some(requests) = self.pending_requests.write().get_mut(&peer) {
+            for (request_id, request) in requests.expire(Instant::now()) {
+                debug!("{:?} with request id {} timed out", request, request_id);
+                self.record_reputation(peer, ReputationEvent::Timeout)?;
+            }
+            if let Err(e) = requests.register(request_id, id) {
+                debug!("Not tracking request: {}", e);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Matches an inbound response to its request, recording how long the peer took to answer.
+    /// Returns whether the response answered a tracked request.
+    ///
+    /// Responses that cannot be matched, as on eth/65, are let through.
+    fn resolve_response(
+        &self,
+        peer: PeerId,
+        id: EthMessageId,
+        data: &[u8],
+    ) -> Result<bool, ResponseError> {
+        if !id.is_response() || self.peer_protocol_version(peer) == EthProtocolVersion::Eth65 {
+            return Ok(false);
+        }
+
+        let request_id = match peek_request_id(data) {
+            Ok(request_id) => request_id,
+            Err(_) => return Ok(false),
+        };
+
+        if let Some(requests) = self.pending_requests.write().get_mut(&peer) {
+            let (request, latency) = requests.resolve_timed(request_id, id)?;
+            self.metrics.observe_latency(request, latency);
+
+            return Ok(true);
+        }
+
+        Ok(false)
+    }
+
     pub fn set_status(&self, message: FullStatusData) {
         *self.status_message.write() = Some(message);
         self.no_new_peers.store(false, Ordering::SeqCst);
     }
 
+    /// Like [`Self::set_status`], but keeps our fork filter if only our head moved.
+    pub fn update_status(&self, status: StatusData) {
+        let mut status_message = self.status_message.write();
+        match &mut *status_message {
+            Some(current) => current.update(status),
+            None => *status_message = Some(FullStatusData::new(status)),
+        }
+        self.no_new_peers.store(false, Ordering::SeqCst);
+    }
+
     #[instrument(name = "CapabilityServerImpl.handle_event", skip(self, event))]
     fn handle_event(&self, peer: PeerId, event: InboundEvent) -> Result<(), DisconnectReason> {
+        self.handle_event_inner(peer, event).map_err(
+            |InboundError {
+                 reason,
+                 violation_recorded,
+             }| {
+                if !violation_recorded
+                    && matches!(
+                        reason,
+                        DisconnectReason::ProtocolBreach | DisconnectReason::UselessPeer
+                    )
+                    && self.ban_list.write().record_violation(peer, Instant::now())
+                {
+                    debug!("Banning peer for repeated protocol violations");
+                }
+
+                reason
+            },
+        )
+    }
+
+    fn handle_event_inner(&self, peer: PeerId, event: InboundEvent) -> Result<(), InboundError> {
         match event {
             InboundEvent::Disconnect { reason } => {
                 debug!("Peer disconnect (reason: {:?}), tearing down peer.", reason);
                 self.teardown_peer(peer);
             }
+            InboundEvent::Message {
+                capability_name,
+                message,
+            } if capability_name == les_capability_name()
+                && self.les_handshake_state(peer).is_some() =>
+            {
+                self.handle_les_message(peer, message)?;
+            }
+            InboundEvent::Message {
+                capability_name, ..
+            } if capability_name != self.capability_name => {
+                debug!("Ignoring message for capability {}", capability_name);
+            }
             InboundEvent::Message {
                 message: Message { id, data },
                 ..
             } => {
-                let valid_peer = self.valid_peers.read().contains(&peer);
-                let message_id = EthMessageId::from_usize(id);
+                let valid_peer = self.handshake_state(peer) == HandshakeState::Done;
+                let message_id = match u8::try_from(id).ok().map(EthMessageId::try_from_id) {
+                    Some(Ok(id)) => id,
+                    _ => {
+                        debug!("Unknown message id {}! Kicking peer.", id);
+
+                        return Err(DisconnectReason::ProtocolBreach.into());
+                    }
+                };
+                self.metrics.record_received(message_id);
                 match message_id {
-                    None => {
-                        debug!("Unknown message");
+                    id if !id.is_valid_for(self.peer_protocol_version(peer)) => {
+                        debug!(
+                            "{:?} is not part of the negotiated protocol version! Kicking peer.",
+                            id
+                        );
+
+                        return Err(DisconnectReason::ProtocolBreach.into());
+                    }
+                    id if self.message_limits.check(id, data.len()).is_err() => {
+                        debug!(
+                            "{:?} message of {} bytes is too large! Kicking peer.",
+                            id,
+                            data.len()
+                        );
+
+                        return Err(DisconnectReason::ProtocolBreach.into());
+                    }
+                    EthMessageId::NewBlock if !self.accept_new_block => {
+                        debug!("NewBlock is not accepted on this chain! Kicking peer.");
+
+                        return Err(DisconnectReason::ProtocolBreach.into());
+                    }
+                    EthMessageId::Status if valid_peer => {
+                        debug!("Status message after completed handshake! Kicking peer.");
+
+                        return Err(DisconnectReason::ProtocolBreach.into());
                     }
-                    Some(EthMessageId::Status) => {
-                        let v = StatusMessage::decode(&mut &*data).map_err(|e| {
+                    EthMessageId::Status => {
+                        let v = decode_strict::<StatusMessage>(&data).map_err(|e| {
                             debug!("Failed to decode status message: {}! Kicking peer.", e);
 
                             DisconnectReason::ProtocolBreach
@@ -249,15 +724,46 @@ impl CapabilityServerImpl {
 
                         debug!("Decoded status message: {:?}", v);
 
-                        let status_data = &*(self.status_message.read());
-                        if let Some(FullStatusData { fork_filter, .. }) = status_data {
-                            fork_filter.validate(v.fork_id).map_err(|reason| {
-                                debug!("Kicking peer with incompatible fork ID: {:?}", reason);
+                        v.validate_against(self.peer_protocol_version(peer))
+                            .map_err(|e| {
+                                debug!("Invalid status message: {}! Kicking peer.", e);
 
-                                DisconnectReason::UselessPeer
+                                e.disconnect_reason()
                             })?;
 
+                        let status_data = &*(self.status_message.read());
+                        if let Some(status_data) = status_data {
+                            let fork_event = self.fork_monitor.write().observe(
+                                peer,
+                                status_data.status.max_block,
+                                status_data.fork_filter.current(),
+                                v.fork_id,
+                            );
+                            if let Some(ForkEvent::LocalStale { next_fork_block }) = fork_event {
+                                warn!(
+                                    "Peers announce a fork at {} that is not in our schedule, upgrade the node to stay on the chain",
+                                    next_fork_block
+                                );
+                            }
+
+                            match status_data.validate_peer_status(&v) {
+                                Err(e) if e.is_fatal() => {
+                                    debug!("Kicking incompatible peer: {}", e);
+
+                                    return Err(e.disconnect_reason().into());
+                                }
+                                Err(e) => {
+                                    debug!("Keeping peer: {}", e);
+                                    self.record_reputation(peer, (&e).into())?;
+                                }
+                                Ok(()) => {}
+                            }
+
                             self.valid_peers.write().insert(peer);
+                            self.peer_heads
+                                .write()
+                                .insert(peer, (v.total_difficulty, v.best_hash));
+                            self.handshake_deadlines.write().remove(&peer);
 
                             let _ = self
                                 .peers_status_sender
@@ -269,7 +775,41 @@ impl CapabilityServerImpl {
                             });
                         }
                     }
-                    Some(inbound_id) if valid_peer => {
+                    inbound_id if valid_peer => {
+                        match self.resolve_response(peer, inbound_id, &data) {
+                            Ok(true) => {
+                                self.record_reputation(peer, ReputationEvent::ValidResponse)?
+                            }
+                            Ok(false) => {}
+                            Err(e) => {
+                                debug!("{}", e);
+
+                                let res = if self
+                                    .ban_list
+                                    .write()
+                                    .record_violation(peer, Instant::now())
+                                {
+                                    debug!("Banning peer for unsolicited responses! Kicking peer.");
+
+                                    Err(DisconnectReason::UselessPeer)
+                                } else {
+                                    self.record_reputation(peer, (&e).into())
+                                };
+                                res.map_err(|reason| InboundError {
+                                    reason,
+                                    violation_recorded: true,
+                                })?;
+                            }
+                        }
+
+                        if let Some(block) =
+                            announced_block(inbound_id, self.peer_protocol_version(peer), &data)
+                        {
+                            self.block_tracker
+                                .write()
+                                .set_block_number(peer, block, false);
+                        }
+
                         let _ = self.data_sender.send(InboundMessage {
                             id: sentry::MessageId::from(inbound_id) as i32,
                             data,
@@ -283,35 +823,102 @@ impl CapabilityServerImpl {
 
         Ok(())
     }
+
+    fn handle_les_message(
+        &self,
+        peer: PeerId,
+        Message { id, data }: Message,
+    ) -> Result<(), DisconnectReason> {
+        match (LesMessageId::from_usize(id), self.les_handshake_state(peer)) {
+            (Some(LesMessageId::Status), Some(HandshakeState::Pending)) => {
+                let v = decode_strict::<LesStatus>(&data).map_err(|e| {
+                    debug!("Failed to decode les status message: {}! Kicking peer.", e);
+
+                    DisconnectReason::ProtocolBreach
+                })?;
+
+                debug!("Decoded les status message: {:?}", v);
+
+                if let Some(status_data) = &*self.status_message.read() {
+                    match v.validate_against(status_data) {
+                        Err(e) if e.is_fatal() => {
+                            debug!("Kicking incompatible les peer: {}", e);
+
+                            return Err(e.disconnect_reason());
+                        }
+                        Err(e) => debug!("Keeping les peer: {}", e),
+                        Ok(()) => {}
+                    }
+
+                    if let Some((_, state)) = self.les_peers.write().get_mut(&peer) {
+                        *state = HandshakeState::Done;
+                    }
+                    self.handshake_deadlines.write().remove(&peer);
+                }
+            }
+            (_, Some(HandshakeState::Pending)) => {
+                debug!("Les message {} before status! Kicking peer.", id);
+
+                return Err(DisconnectReason::ProtocolBreach);
+            }
+            (Some(LesMessageId::Status), _) => {
+                debug!("Les status message after completed handshake! Kicking peer.");
+
+                return Err(DisconnectReason::ProtocolBreach);
+            }
+            _ => debug!("Not serving les message {}", id),
+        }
+
+        Ok(())
+    }
 }
 
 #[async_trait]
 impl CapabilityServer for CapabilityServerImpl {
     #[instrument(skip(self, peer), level = "debug", fields(peer=&*peer.to_string()))]
     fn on_peer_connect(&self, peer: PeerId, caps: HashMap<CapabilityName, CapabilityVersion>) {
-        let first_events = if let Some(FullStatusData {
-            status,
-            fork_filter,
-        }) = &*self.status_message.read()
-        {
-            let status_message = StatusMessage {
-                protocol_version: *caps
-                    .get(&capability_name())
-                    .expect("peer without this cap would have been disconnected"),
-                network_id: status.network_id,
-                total_difficulty: status.total_difficulty,
-                best_hash: status.best_hash,
-                genesis_hash: status.fork_data.genesis,
-                fork_id: fork_filter.current(),
-            };
+        // Peers sharing only les with us get no eth state at all.
+        let protocol_version = if self.les && !caps.contains_key(&self.capability_name) {
+            None
+        } else {
+            Some(
+                EthProtocolVersion::from_usize(
+                    *caps
+                        .get(&self.capability_name)
+                        .expect("peer without this cap would have been disconnected"),
+                )
+                .expect("we only advertise known protocol versions"),
+            )
+        };
+        let first_events = if self.is_banned(peer) {
+            debug!("Refusing banned peer");
 
-            vec![OutboundEvent::Message {
-                capability_name: capability_name(),
-                message: Message {
-                    id: EthMessageId::Status.to_usize().unwrap(),
-                    data: fastrlp::encode_fixed_size(&status_message).to_vec().into(),
-                },
+            vec![OutboundEvent::Disconnect {
+                reason: DisconnectReason::UselessPeer,
             }]
+        } else if let Some(status_data) = &*self.status_message.read() {
+            if let Some(protocol_version) = protocol_version {
+                let status_message = status_data.to_status_message(protocol_version);
+
+                vec![OutboundEvent::Message {
+                    capability_name: self.capability_name,
+                    message: Message {
+                        id: EthMessageId::Status.to_usize().unwrap(),
+                        data: fastrlp::encode_fixed_size(&status_message).to_vec().into(),
+                    },
+                }]
+            } else {
+                let mut data = BytesMut::new();
+                LesStatus::new(status_data).encode(&mut data);
+
+                vec![OutboundEvent::Message {
+                    capability_name: les_capability_name(),
+                    message: Message {
+                        id: LesMessageId::Status.to_usize().unwrap(),
+                        data: data.freeze(),
+                    },
+                }]
+            }
         } else {
             vec![OutboundEvent::Disconnect {
                 reason: DisconnectReason::DisconnectRequested,
@@ -319,21 +926,29 @@ impl CapabilityServer for CapabilityServerImpl {
         };
 
         let (sender, mut receiver) = channel(1);
-        self.setup_peer(
-            peer,
-            Pipes {
-                sender,
-                receiver: Arc::new(AsyncMutex::new(Box::pin(stream! {
-                    for event in first_events {
-                        yield event;
-                    }
+        let pipes = Pipes {
+            sender,
+            receiver: Arc::new(AsyncMutex::new(Box::pin(stream! {
+                for event in first_events {
+                    yield event;
+                }
 
-                    while let Some(event) = receiver.recv().await {
-                        yield event;
-                    }
-                }))),
-            },
-        );
+                while let Some(event) = receiver.recv().await {
+                    yield event;
+                }
+            }))),
+        };
+        self.handshake_deadlines
+            .write()
+            .insert(peer, Instant::now() + self.handshake_timeout);
+        match protocol_version {
+            Some(protocol_version) => self.setup_peer(peer, pipes, protocol_version),
+            None => {
+                self.les_peers
+                    .write()
+                    .insert(peer, (pipes, HandshakeState::Pending));
+            }
+        }
     }
 
     #[instrument(skip_all, level = "debug", fields(peer=&*peer.to_string(), event=&*event.to_string()))]
@@ -341,9 +956,19 @@ impl CapabilityServer for CapabilityServerImpl {
         debug!("Received message");
 
         if let Err(reason) = self.handle_event(peer, event) {
-            match self.sender(peer) {
+            match self.any_pipes(peer).map(|pipes| pipes.sender) {
                 Some(sender) => {
-                    let _ = sender.send(OutboundEvent::Disconnect { reason }).await;
+                    // Egress stops draining the pipe after the first disconnect, and waits for
+                    // ingress to finish, so ingress must not wait for room in the pipe. The
+                    // queued event may be a message though, so the disconnect is queued behind
+                    // it in the background, which ends once the pipe is torn down.
+                    if let Err(TrySendError::Full(event)) =
+                        sender.try_send(OutboundEvent::Disconnect { reason })
+                    {
+                        tokio::spawn(async move {
+                            let _ = sender.send(event).await;
+                        });
+                    }
                 }
                 None => {
                     self.teardown_peer(peer);
@@ -352,15 +977,47 @@ impl CapabilityServer for CapabilityServerImpl {
         }
     }
     async fn next(&self, peer: PeerId) -> OutboundEvent {
-        self.receiver(peer)
-            .unwrap()
-            .lock()
-            .await
-            .next()
-            .await
-            .unwrap_or(OutboundEvent::Disconnect {
-                reason: DisconnectReason::DisconnectRequested,
-            })
+        let receiver = self.receiver(peer).unwrap();
+        let mut receiver = receiver.lock().await;
+        let event = loop {
+            let deadline = self.handshake_deadlines.read().get(&peer).copied();
+            let deadline = match deadline {
+                Some(deadline) => deadline,
+                None => break receiver.next().await,
+            };
+
+            if let Ok(event) = tokio::time::timeout_at(deadline.into(), receiver.next()).await {
+                break event;
+            }
+            // The handshake may have completed while we were waiting.
+            if self.handshake_deadlines.read().contains_key(&peer) {
+                debug!("Peer did not complete the handshake in time! Kicking peer.");
+
+                break Some(OutboundEvent::Disconnect {
+                    reason: HandshakeError::Timeout(self.handshake_timeout).disconnect_reason(),
+                });
+            }
+        }
+        .unwrap_or(OutboundEvent::Disconnect {
+            reason: DisconnectReason::DisconnectRequested,
+        });
+
+        if let OutboundEvent::Message {
+            capability_name,
+            message,
+        } = &event
+        {
+            if *capability_name == self.capability_name {
+                if let Some(id) = EthMessageId::from_usize(message.id) {
+                    self.metrics.record_sent(id);
+                    if let Err(reason) = self.register_request(peer, id, &message.data) {
+                        return OutboundEvent::Disconnect { reason };
+                    }
+                }
+            }
+        }
+
+        event
     }
 }
 
@@ -403,6 +1060,28 @@ pub struct Opts {
     /// Disable DNS discovery
     #[clap(long, num_args = 0)]
     pub no_dns_discovery: bool,
+    /// Messages buffered per peer before we stop reading from it.
+    #[clap(long, default_value = "64")]
+    pub ingress_capacity: NonZeroUsize,
+    /// Advertise the les light client capability, completing its handshake without serving data.
+    #[clap(long, num_args = 0)]
+    pub les: bool,
+    /// Serve eth message metrics for Prometheus at this address.
+    #[clap(long)]
+    pub metrics_addr: Option<SocketAddr>,
+    /// Disconnect peers announcing full blocks, as is required once the chain moved to beacon
+    /// consensus.
+    #[clap(long, num_args = 0)]
+    pub no_new_block: bool,
+    /// Protocol violations within the violation window that get a peer banned.
+    #[clap(long, default_value_t = DEFAULT_BAN_THRESHOLD)]
+    pub ban_threshold: usize,
+    /// Window in seconds over which protocol violations are counted.
+    #[clap(long, default_value_t = DEFAULT_VIOLATION_WINDOW.as_secs())]
+    pub ban_violation_window: u64,
+    /// Seconds a banned peer is refused for.
+    #[clap(long, default_value_t = DEFAULT_BAN_DURATION.as_secs())]
+    pub ban_duration: u64,
 }
 
 pub async fn run(
@@ -535,9 +1214,34 @@ pub async fn run(
 
     let tasks = Arc::new(TaskGroup::new());
 
-    let protocol_version = EthProtocolVersion::Eth66;
+    let protocol_version = EthProtocolVersion::Eth67;
+
+    let metrics = Metrics::default();
+    if let Some(metrics_addr) = opts.metrics_addr {
+        let listener = tokio::net::TcpListener::bind(metrics_addr)
+            .await
+            .with_context(|| format!("Failed to bind metrics server to {}", metrics_addr))?;
+        info!("Metrics server listening at {}", metrics_addr);
 
-    let capability_server = Arc::new(CapabilityServerImpl::new(protocol_version, opts.max_peers));
+        let metrics = metrics.clone();
+        tasks.spawn_with_name("metrics server", async move {
+            if let Err(e) = metrics.serve(listener).await {
+                warn!("Metrics server failed: {}", e);
+            }
+        });
+    }
+
+    let capability_server = Arc::new(
+        CapabilityServerImpl::new(protocol_version, opts.max_peers)
+            .with_new_block_acceptance(!opts.no_new_block)
+            .with_ban_list(PeerBanList::new(
+                opts.ban_threshold,
+                Duration::from_secs(opts.ban_violation_window),
+                Duration::from_secs(opts.ban_duration),
+            ))
+            .with_les(opts.les)
+            .with_metrics(metrics),
+    );
 
     let no_new_peers = capability_server.no_new_peers_handle();
 
@@ -551,11 +1255,12 @@ pub async fn run(
             opts.cidr,
             no_new_peers,
         ))
+        .with_ingress_options(IngressOptions {
+            capacity: opts.ingress_capacity,
+        })
         .with_client_version(version_string())
         .build(
-            btreemap! {
-                CapabilityId { name: capability_name(), version: protocol_version as CapabilityVersion } => 17,
-            },
+            capability_server.capabilities(),
             capability_server.clone(),
             secret_key,
         )
@@ -590,3 +1295,955 @@ pub async fn run(
 
     Ok(swarm)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        models::{Block, BlockHeader, BlockNumber, H256, U256},
+        p2p::types::{NewBlock, NewBlockHashes},
+    };
+    use ethereum_forkid::{ForkHash, ForkId};
+    use hex_literal::hex;
+
+    fn peer_with_version(server: &CapabilityServerImpl, version: EthProtocolVersion) -> PeerId {
+        let peer = PeerId::random();
+        server.on_peer_connect(
+            peer,
+            [(capability_name(), version as CapabilityVersion)]
+                .into_iter()
+                .collect(),
+        );
+        peer
+    }
+
+    fn message(id: EthMessageId) -> InboundEvent {
+        message_with_data(id, vec![0xc0])
+    }
+
+    fn message_with_data(id: EthMessageId, data: Vec<u8>) -> InboundEvent {
+        InboundEvent::Message {
+            capability_name: capability_name(),
+            message: Message {
+                id: id.to_usize().unwrap(),
+                data: data.into(),
+            },
+        }
+    }
+
+    #[test]
+    fn status_with_wrong_protocol_version_is_rejected() {
+        let server =
+            CapabilityServerImpl::new(EthProtocolVersion::Eth66, NonZeroUsize::new(1).unwrap());
+        let peer = peer_with_version(&server, EthProtocolVersion::Eth66);
+
+        let status = StatusMessage {
+            protocol_version: EthProtocolVersion::Eth65 as usize,
+            network_id: 1,
+            total_difficulty: U256::ZERO,
+            best_hash: H256::zero(),
+            genesis_hash: H256::zero(),
+            fork_id: ForkId {
+                hash: ForkHash([0; 4]),
+                next: 0,
+            },
+        };
+
+        assert!(matches!(
+            server.handle_event(
+                peer,
+                InboundEvent::Message {
+                    capability_name: capability_name(),
+                    message: Message {
+                        id: EthMessageId::Status.to_usize().unwrap(),
+                        data: fastrlp::encode_fixed_size(&status).to_vec().into(),
+                    },
+                },
+            ),
+            Err(DisconnectReason::ProtocolBreach)
+        ));
+    }
+
+    fn mainnet_status() -> FullStatusData {
+        mainnet_status_at(0)
+    }
+
+    fn mainnet_status_at(max_block: u64) -> FullStatusData {
+        FullStatusData::try_from(ethereum_interfaces::sentry::StatusData {
+            network_id: 1,
+            total_difficulty: Some(H256::zero().into()),
+            best_hash: Some(MAINNET_GENESIS.into()),
+            fork_data: Some(ethereum_interfaces::sentry::Forks {
+                genesis: Some(MAINNET_GENESIS.into()),
+                forks: vec![1_150_000, 1_920_000],
+            }),
+            max_block,
+        })
+        .unwrap()
+    }
+
+    const MAINNET_GENESIS: H256 = H256(hex!(
+        "d4e56740f876aef8c010b86a40d5f56745a118d0906a34e69aec8c0db1cb8fa3"
+    ));
+
+    #[test]
+    fn status_from_another_chain_is_rejected() {
+        let server =
+            CapabilityServerImpl::new(EthProtocolVersion::Eth66, NonZeroUsize::new(1).unwrap());
+        server.set_status(mainnet_status());
+        let peer = peer_with_version(&server, EthProtocolVersion::Eth66);
+
+        let ropsten_genesis = H256(hex!(
+            "41941023680923e0fe4d74a34bdac8141f2540e3ae90623718e47d66d1ca4a2d"
+        ));
+        let status = StatusMessage {
+            protocol_version: EthProtocolVersion::Eth66 as usize,
+            network_id: 1,
+            total_difficulty: U256::ZERO,
+            best_hash: ropsten_genesis,
+            genesis_hash: ropsten_genesis,
+            fork_id: ForkId {
+                hash: ForkHash(hex!("30c7ddbc")),
+                next: 10,
+            },
+        };
+
+        assert!(matches!(
+            server.handle_event(
+                peer,
+                InboundEvent::Message {
+                    capability_name: capability_name(),
+                    message: Message {
+                        id: EthMessageId::Status.to_usize().unwrap(),
+                        data: fastrlp::encode_fixed_size(&status).to_vec().into(),
+                    },
+                },
+            ),
+            Err(DisconnectReason::UselessPeer)
+        ));
+        assert_eq!(server.connected_peers(), 0);
+    }
+
+    #[test]
+    fn stale_peer_is_kept() {
+        let server =
+            CapabilityServerImpl::new(EthProtocolVersion::Eth66, NonZeroUsize::new(1).unwrap());
+        let status = mainnet_status_at(2_000_000);
+        assert_eq!(status.status.max_block, 2_000_000);
+        server.set_status(status);
+        let peer = peer_with_version(&server, EthProtocolVersion::Eth66);
+
+        // Peer is on Homestead and unaware of the DAO fork we have passed
+        let status = StatusMessage {
+            protocol_version: EthProtocolVersion::Eth66 as usize,
+            network_id: 1,
+            total_difficulty: U256::ZERO,
+            best_hash: MAINNET_GENESIS,
+            genesis_hash: MAINNET_GENESIS,
+            fork_id: ForkId {
+                hash: ForkHash(hex!("97c2c34c")),
+                next: 0,
+            },
+        };
+
+        server
+            .handle_event(
+                peer,
+                message_with_data(
+                    EthMessageId::Status,
+                    fastrlp::encode_fixed_size(&status).to_vec(),
+                ),
+            )
+            .unwrap();
+        assert_eq!(server.connected_peers(), 1);
+    }
+
+    #[test]
+    fn capability_stats() {
+        let server =
+            CapabilityServerImpl::new(EthProtocolVersion::Eth66, NonZeroUsize::new(3).unwrap());
+        let status = mainnet_status();
+        let handshake = |version| {
+            let peer = peer_with_version(&server, version);
+            let status = status.to_status_message(version);
+            server
+                .handle_event(
+                    peer,
+                    message_with_data(
+                        EthMessageId::Status,
+                        fastrlp::encode_fixed_size(&status).to_vec(),
+                    ),
+                )
+                .unwrap();
+            peer
+        };
+        server.set_status(status.clone());
+
+        let peers = [
+            EthProtocolVersion::Eth66,
+            EthProtocolVersion::Eth66,
+            EthProtocolVersion::Eth65,
+        ]
+        .map(handshake);
+        // Peers still in the handshake are not counted
+        peer_with_version(&server, EthProtocolVersion::Eth67);
+        assert_eq!(
+            server.capability_stats(),
+            [
+                (EthProtocolVersion::Eth65, 1),
+                (EthProtocolVersion::Eth66, 2)
+            ]
+            .into_iter()
+            .collect()
+        );
+
+        server
+            .handle_event(peers[2], InboundEvent::Disconnect { reason: None })
+            .unwrap();
+        assert_eq!(
+            server.capability_stats(),
+            [(EthProtocolVersion::Eth66, 2)].into_iter().collect()
+        );
+    }
+
+    #[tokio::test]
+    async fn repeated_bad_messages_do_not_block() {
+        let server =
+            CapabilityServerImpl::new(EthProtocolVersion::Eth66, NonZeroUsize::new(1).unwrap());
+        server.set_status(mainnet_status());
+        let peer = peer_with_version(&server, EthProtocolVersion::Eth66);
+        assert!(matches!(
+            server.next(peer).await,
+            OutboundEvent::Message { .. }
+        ));
+
+        // Nothing drains the pipe after the first disconnect, as with a disconnecting egress
+        for _ in 0..4 {
+            tokio::time::timeout(
+                Duration::from_secs(1),
+                server.on_peer_event(peer, message(EthMessageId::Status)),
+            )
+            .await
+            .expect("ingress must not wait for egress");
+        }
+        assert!(matches!(
+            server.next(peer).await,
+            OutboundEvent::Disconnect {
+                reason: DisconnectReason::ProtocolBreach
+            }
+        ));
+    }
+
+    #[tokio::test]
+    async fn les_only_peer_handshake() {
+        let server =
+            CapabilityServerImpl::new(EthProtocolVersion::Eth66, NonZeroUsize::new(1).unwrap())
+                .with_les(true);
+        assert!(server.capabilities().contains_key(&CapabilityId {
+            name: les_capability_name(),
+            version: LES_PROTOCOL_VERSION,
+        }));
+        let status = mainnet_status();
+        let ours = LesStatus::new(&status);
+        server.set_status(status);
+
+        let peer = PeerId::random();
+        server.on_peer_connect(
+            peer,
+            [(les_capability_name(), LES_PROTOCOL_VERSION)]
+                .into_iter()
+                .collect(),
+        );
+        assert_eq!(
+            server.les_handshake_state(peer),
+            Some(HandshakeState::Pending)
+        );
+        // Eth messages from sentry clients never reach the peer
+        assert!(!server.all_peers().contains(&peer));
+        assert!(server.sender(peer).is_none());
+        match server.next(peer).await {
+            OutboundEvent::Message {
+                capability_name,
+                message,
+            } => {
+                assert_eq!(capability_name, les_capability_name());
+                assert_eq!(message.id, LesMessageId::Status as usize);
+                assert_eq!(decode_strict::<LesStatus>(&message.data).unwrap(), ours);
+            }
+            other => panic!("expected les status, got {:?}", other),
+        }
+        assert_eq!(server.metrics().sent(EthMessageId::Status), 0);
+
+        let les_message = |id: usize, status: &LesStatus| {
+            let mut data = BytesMut::new();
+            status.encode(&mut data);
+            InboundEvent::Message {
+                capability_name: les_capability_name(),
+                message: Message {
+                    id,
+                    data: data.freeze(),
+                },
+            }
+        };
+        let theirs = LesStatus {
+            head_number: 0,
+            announce_type: Some(1),
+            ..ours.clone()
+        };
+        server
+            .handle_event(peer, les_message(LesMessageId::Status as usize, &theirs))
+            .unwrap();
+        assert_eq!(server.les_handshake_state(peer), Some(HandshakeState::Done));
+        // Peer is not an eth peer
+        assert_eq!(server.connected_peers(), 0);
+        assert_eq!(server.handshake_state(peer), HandshakeState::Pending);
+
+        // Requests go unserved, another status is a breach
+        server
+            .handle_event(peer, les_message(0x02, &theirs))
+            .unwrap();
+        assert!(matches!(
+            server.handle_event(peer, les_message(LesMessageId::Status as usize, &theirs)),
+            Err(DisconnectReason::ProtocolBreach)
+        ));
+        server
+            .handle_event(peer, InboundEvent::Disconnect { reason: None })
+            .unwrap();
+        assert_eq!(server.les_handshake_state(peer), None);
+
+        // Les peers on another chain are turned away
+        let peer = PeerId::random();
+        server.on_peer_connect(
+            peer,
+            [(les_capability_name(), LES_PROTOCOL_VERSION)]
+                .into_iter()
+                .collect(),
+        );
+        let other_chain = LesStatus {
+            genesis_hash: H256::repeat_byte(0xab),
+            ..ours
+        };
+        assert!(matches!(
+            server.handle_event(
+                peer,
+                les_message(LesMessageId::Status as usize, &other_chain)
+            ),
+            Err(DisconnectReason::UselessPeer)
+        ));
+    }
+
+    #[tokio::test]
+    async fn custom_capability_name() {
+        let name = CapabilityName(arrayvec::ArrayString::from("test").unwrap());
+        let server =
+            CapabilityServerImpl::new(EthProtocolVersion::Eth66, NonZeroUsize::new(1).unwrap())
+                .with_capability_name(name);
+        assert_eq!(server.capability_name(), name);
+
+        let capabilities = server.capabilities();
+        assert_eq!(capabilities.len(), supported_capabilities().len());
+        assert!(capabilities.keys().all(|cap| cap.name == name));
+        assert!(capabilities.contains_key(&CapabilityId {
+            name,
+            version: EthProtocolVersion::Eth66 as CapabilityVersion,
+        }));
+
+        server.set_status(mainnet_status());
+        let peer = PeerId::random();
+        server.on_peer_connect(
+            peer,
+            [(name, EthProtocolVersion::Eth66 as CapabilityVersion)]
+                .into_iter()
+                .collect(),
+        );
+        assert!(matches!(
+            server.next(peer).await,
+            OutboundEvent::Message { capability_name, .. } if capability_name == name
+        ));
+
+        // Messages of other capabilities hosted alongside are not ours to judge
+        assert!(server
+            .handle_event(
+                peer,
+                InboundEvent::Message {
+                    capability_name: capability_name(),
+                    message: Message {
+                        id: 0xff,
+                        data: vec![0xc0].into(),
+                    },
+                },
+            )
+            .is_ok());
+    }
+
+    #[test]
+    fn duplicate_status_is_rejected() {
+        let server =
+            CapabilityServerImpl::new(EthProtocolVersion::Eth66, NonZeroUsize::new(1).unwrap());
+        let status = mainnet_status();
+        let data = fastrlp::encode_fixed_size(&status.to_status_message(EthProtocolVersion::Eth66))
+            .to_vec();
+        server.set_status(status);
+        let peer = peer_with_version(&server, EthProtocolVersion::Eth66);
+        assert_eq!(server.handshake_state(peer), HandshakeState::Pending);
+
+        server
+            .handle_event(peer, message_with_data(EthMessageId::Status, data.clone()))
+            .unwrap();
+        assert_eq!(server.handshake_state(peer), HandshakeState::Done);
+
+        assert!(matches!(
+            server.handle_event(peer, message_with_data(EthMessageId::Status, data)),
+            Err(DisconnectReason::ProtocolBreach)
+        ));
+    }
+
+    #[test]
+    fn new_block_number_from_header() {
+        let mut block = Block {
+            header: BlockHeader::empty(),
+            transactions: vec![],
+            ommers: Default::default(),
+        };
+        block.header.number = BlockNumber(15_537_394);
+        block.header.difficulty = U256::from(11_055_787_484_078_698_u64);
+        block.header.gas_limit = 30_000_000;
+        block.header.extra_data = vec![0xab; 32].into();
+        let encoded = EthMessage::NewBlock(Box::new(NewBlock {
+            block,
+            total_difficulty: U256::from(58_750_003_716_598_352_816_469_u128),
+        }))
+        .encode_versioned(EthProtocolVersion::Eth66);
+
+        assert_eq!(new_block_number(&encoded).unwrap(), 15_537_394);
+        assert!(new_block_number(&encoded[..encoded.len() - 1]).is_err());
+        assert!(new_block_number(&[0x82, 0x04, 0x00]).is_err());
+    }
+
+    #[test]
+    fn announced_blocks_are_tracked() {
+        let server =
+            CapabilityServerImpl::new(EthProtocolVersion::Eth66, NonZeroUsize::new(2).unwrap());
+        let status = mainnet_status();
+        let data = fastrlp::encode_fixed_size(&status.to_status_message(EthProtocolVersion::Eth66))
+            .to_vec();
+        server.set_status(status);
+        let [peer_a, peer_b] = [(); 2].map(|_| {
+            let peer = peer_with_version(&server, EthProtocolVersion::Eth66);
+            server
+                .handle_event(peer, message_with_data(EthMessageId::Status, data.clone()))
+                .unwrap();
+            peer
+        });
+        assert_eq!(server.best_peer_for(1), None);
+
+        let announce = |peer, message: EthMessage| {
+            server
+                .handle_event(
+                    peer,
+                    message_with_data(
+                        message.id(),
+                        message.encode_versioned(EthProtocolVersion::Eth66).to_vec(),
+                    ),
+                )
+                .unwrap()
+        };
+        announce(
+            peer_a,
+            EthMessage::NewBlockHashes(NewBlockHashes::new(vec![
+                (H256::repeat_byte(1), BlockNumber(12)),
+                (H256::repeat_byte(2), BlockNumber(10)),
+            ])),
+        );
+        assert_eq!(server.best_peer_for(11), Some(peer_a));
+        assert_eq!(server.best_peer_for(13), None);
+
+        let mut block = Block {
+            header: BlockHeader::empty(),
+            transactions: vec![],
+            ommers: Default::default(),
+        };
+        block.header.number = BlockNumber(20);
+        announce(
+            peer_b,
+            EthMessage::NewBlock(Box::new(NewBlock {
+                block,
+                total_difficulty: U256::from(20_u64),
+            })),
+        );
+        assert_eq!(server.best_peer_for(11), Some(peer_b));
+        assert_eq!(server.best_peer_for(20), Some(peer_b));
+
+        // Announcements never move a peer back
+        announce(
+            peer_b,
+            EthMessage::NewBlockHashes(NewBlockHashes::new(vec![(
+                H256::repeat_byte(3),
+                BlockNumber(5),
+            )])),
+        );
+        assert_eq!(server.best_peer_for(20), Some(peer_b));
+
+        server.teardown_peer(peer_b);
+        assert_eq!(server.best_peer_for(11), Some(peer_a));
+    }
+
+    #[tokio::test]
+    async fn stalled_peer() {
+        let max_idle = Duration::from_millis(100);
+        let server =
+            CapabilityServerImpl::new(EthProtocolVersion::Eth66, NonZeroUsize::new(1).unwrap());
+        let status = mainnet_status();
+        let data = fastrlp::encode_fixed_size(&status.to_status_message(EthProtocolVersion::Eth66))
+            .to_vec();
+        server.set_status(status);
+        let peer = peer_with_version(&server, EthProtocolVersion::Eth66);
+        server
+            .handle_event(peer, message_with_data(EthMessageId::Status, data))
+            .unwrap();
+        assert!(!server.is_stalled(peer, max_idle));
+        assert!(!server.is_stalled(PeerId::random(), Duration::ZERO));
+
+        let announce = |number| {
+            let message = EthMessage::NewBlockHashes(NewBlockHashes::new(vec![(
+                H256::repeat_byte(1),
+                BlockNumber(number),
+            )]));
+            server
+                .handle_event(
+                    peer,
+                    message_with_data(
+                        message.id(),
+                        message.encode_versioned(EthProtocolVersion::Eth66).to_vec(),
+                    ),
+                )
+                .unwrap();
+        };
+
+        announce(10);
+        tokio::time::sleep(Duration::from_millis(60)).await;
+        // Announcing the same head again does not count as progress
+        announce(10);
+        tokio::time::sleep(Duration::from_millis(60)).await;
+        assert!(server.is_stalled(peer, max_idle));
+
+        announce(11);
+        assert!(!server.is_stalled(peer, max_idle));
+    }
+
+    #[test]
+    fn sync_peer_selection() {
+        let server =
+            CapabilityServerImpl::new(EthProtocolVersion::Eth66, NonZeroUsize::new(3).unwrap());
+        let status = mainnet_status();
+        server.set_status(status.clone());
+        assert_eq!(server.best_sync_peer(ChainMode::PoW), None);
+        assert_eq!(server.best_sync_peer(ChainMode::PoS), None);
+
+        // (total difficulty, announced block)
+        let peers = [(300_u64, 10_u64), (200, 30), (100, 20)].map(|(total_difficulty, number)| {
+            let mut v = status.to_status_message(EthProtocolVersion::Eth66);
+            v.total_difficulty = U256::from(total_difficulty);
+            v.best_hash = H256::repeat_byte(number as u8);
+            let peer = peer_with_version(&server, EthProtocolVersion::Eth66);
+            server
+                .handle_event(
+                    peer,
+                    message_with_data(
+                        EthMessageId::Status,
+                        fastrlp::encode_fixed_size(&v).to_vec(),
+                    ),
+                )
+                .unwrap();
+
+            let message = EthMessage::NewBlockHashes(NewBlockHashes::new(vec![(
+                v.best_hash,
+                BlockNumber(number),
+            )]));
+            server
+                .handle_event(
+                    peer,
+                    message_with_data(
+                        message.id(),
+                        message.encode_versioned(EthProtocolVersion::Eth66).to_vec(),
+                    ),
+                )
+                .unwrap();
+            peer
+        });
+
+        assert_eq!(server.best_sync_peer(ChainMode::PoW), Some(peers[0]));
+        assert_eq!(server.best_sync_peer(ChainMode::PoS), Some(peers[1]));
+
+        server.teardown_peer(peers[1]);
+        assert_eq!(server.best_sync_peer(ChainMode::PoS), Some(peers[2]));
+        server.teardown_peer(peers[0]);
+        assert_eq!(server.best_sync_peer(ChainMode::PoW), Some(peers[2]));
+    }
+
+    #[test]
+    fn new_block_is_rejected_when_disabled() {
+        let server =
+            CapabilityServerImpl::new(EthProtocolVersion::Eth66, NonZeroUsize::new(1).unwrap());
+        let peer = peer_with_version(&server, EthProtocolVersion::Eth66);
+        assert!(server
+            .handle_event(peer, message(EthMessageId::NewBlock))
+            .is_ok());
+
+        let server =
+            CapabilityServerImpl::new(EthProtocolVersion::Eth66, NonZeroUsize::new(1).unwrap())
+                .with_new_block_acceptance(false);
+        let peer = peer_with_version(&server, EthProtocolVersion::Eth66);
+        assert!(matches!(
+            server.handle_event(peer, message(EthMessageId::NewBlock)),
+            Err(DisconnectReason::ProtocolBreach)
+        ));
+        assert!(server
+            .handle_event(peer, message(EthMessageId::NewBlockHashes))
+            .is_ok());
+    }
+
+    #[test]
+    fn unknown_message_id_is_rejected() {
+        let server =
+            CapabilityServerImpl::new(EthProtocolVersion::Eth66, NonZeroUsize::new(1).unwrap());
+        let peer = peer_with_version(&server, EthProtocolVersion::Eth66);
+
+        for id in [11, 12, 17] {
+            assert!(matches!(
+                server.handle_event(
+                    peer,
+                    InboundEvent::Message {
+                        capability_name: capability_name(),
+                        message: Message {
+                            id,
+                            data: vec![0xc0].into(),
+                        },
+                    },
+                ),
+                Err(DisconnectReason::ProtocolBreach)
+            ));
+        }
+    }
+
+    #[test]
+    fn node_data_is_rejected_on_eth67() {
+        let server =
+            CapabilityServerImpl::new(EthProtocolVersion::Eth67, NonZeroUsize::new(1).unwrap());
+
+        let peer = peer_with_version(&server, EthProtocolVersion::Eth67);
+        for id in [EthMessageId::GetNodeData, EthMessageId::NodeData] {
+            assert!(matches!(
+                server.handle_event(peer, message(id)),
+                Err(DisconnectReason::ProtocolBreach)
+            ));
+        }
+
+        let peer = peer_with_version(&server, EthProtocolVersion::Eth66);
+        for id in [EthMessageId::GetNodeData, EthMessageId::NodeData] {
+            assert!(server.handle_event(peer, message(id)).is_ok());
+        }
+    }
+
+    #[test]
+    fn oversized_message_is_rejected() {
+        let limits = MessageLimits {
+            status: 16,
+            ..Default::default()
+        };
+        let server =
+            CapabilityServerImpl::new(EthProtocolVersion::Eth66, NonZeroUsize::new(1).unwrap())
+                .with_message_limits(limits);
+        server.set_status(mainnet_status());
+        let peer = peer_with_version(&server, EthProtocolVersion::Eth66);
+
+        let status = server
+            .status_message
+            .read()
+            .as_ref()
+            .unwrap()
+            .to_status_message(EthProtocolVersion::Eth66);
+        let data = fastrlp::encode_fixed_size(&status).to_vec();
+        assert!(data.len() > limits.status);
+
+        assert!(matches!(
+            server.handle_event(peer, message_with_data(EthMessageId::Status, data.clone())),
+            Err(DisconnectReason::ProtocolBreach)
+        ));
+        assert_eq!(server.connected_peers(), 0);
+
+        let server =
+            CapabilityServerImpl::new(EthProtocolVersion::Eth66, NonZeroUsize::new(1).unwrap());
+        server.set_status(mainnet_status());
+        let peer = peer_with_version(&server, EthProtocolVersion::Eth66);
+        server
+            .handle_event(peer, message_with_data(EthMessageId::Status, data))
+            .unwrap();
+        assert_eq!(server.connected_peers(), 1);
+
+        // Payload is never decoded, so garbage is rejected for its size alone
+        let oversized = vec![0xff; limits.request + 1];
+        assert!(matches!(
+            server.handle_event(
+                peer,
+                message_with_data(EthMessageId::GetBlockBodies, oversized)
+            ),
+            Err(DisconnectReason::ProtocolBreach)
+        ));
+    }
+
+    #[tokio::test]
+    async fn repeated_violations_ban_peer() {
+        let server =
+            CapabilityServerImpl::new(EthProtocolVersion::Eth66, NonZeroUsize::new(1).unwrap())
+                .with_ban_list(PeerBanList::new(
+                    2,
+                    Duration::from_secs(60),
+                    Duration::from_secs(600),
+                ));
+        server.set_status(mainnet_status());
+        let caps: HashMap<_, _> = [(
+            capability_name(),
+            EthProtocolVersion::Eth66 as CapabilityVersion,
+        )]
+        .into_iter()
+        .collect();
+        let peer = peer_with_version(&server, EthProtocolVersion::Eth66);
+
+        // Malformed status
+        assert!(server
+            .handle_event(peer, message(EthMessageId::Status))
+            .is_err());
+        assert!(!server.is_banned(peer));
+        // Messages ahead of the handshake are dropped, not held against the peer
+        assert!(server
+            .handle_event(peer, message(EthMessageId::GetNodeData))
+            .is_ok());
+        assert!(server
+            .handle_event(peer, message_with_data(EthMessageId::Status, vec![0x80]))
+            .is_err());
+        assert!(server.is_banned(peer));
+
+        // Reconnects are refused until the ban expires
+        server.teardown_peer(peer);
+        server.on_peer_connect(peer, caps.clone());
+        assert!(matches!(
+            server.next(peer).await,
+            OutboundEvent::Disconnect {
+                reason: DisconnectReason::UselessPeer
+            }
+        ));
+        server.teardown_peer(peer);
+
+        server
+            .ban_list
+            .write()
+            .expire(Instant::now() + Duration::from_secs(600));
+        assert!(!server.is_banned(peer));
+        server.on_peer_connect(peer, caps);
+        assert!(matches!(
+            server.next(peer).await,
+            OutboundEvent::Message { .. }
+        ));
+    }
+
+    /// Peer that completed the eth/66 handshake on a mainnet server, our status already taken.
+    async fn handshaken_peer(server: &CapabilityServerImpl) -> PeerId {
+        let status = mainnet_status();
+        let data = fastrlp::encode_fixed_size(&status.to_status_message(EthProtocolVersion::Eth66))
+            .to_vec();
+        server.set_status(status);
+        let peer = peer_with_version(server, EthProtocolVersion::Eth66);
+        server
+            .handle_event(peer, message_with_data(EthMessageId::Status, data))
+            .unwrap();
+        assert!(matches!(
+            server.next(peer).await,
+            OutboundEvent::Message { .. }
+        ));
+        peer
+    }
+
+    #[tokio::test]
+    async fn silent_peer_times_out() {
+        let server =
+            CapabilityServerImpl::new(EthProtocolVersion::Eth66, NonZeroUsize::new(2).unwrap())
+                .with_handshake_timeout(Duration::from_millis(50));
+        let peer = handshaken_peer(&server).await;
+        let silent = peer_with_version(&server, EthProtocolVersion::Eth66);
+
+        assert!(matches!(
+            server.next(silent).await,
+            OutboundEvent::Message { .. }
+        ));
+        assert!(matches!(
+            tokio::time::timeout(Duration::from_secs(5), server.next(silent))
+                .await
+                .unwrap(),
+            OutboundEvent::Disconnect {
+                reason: DisconnectReason::PingTimeout
+            }
+        ));
+
+        // Peers that completed the handshake are left alone past the deadline
+        assert!(
+            tokio::time::timeout(Duration::from_millis(200), server.next(peer))
+                .await
+                .is_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn request_latency() {
+        let server =
+            CapabilityServerImpl::new(EthProtocolVersion::Eth66, NonZeroUsize::new(1).unwrap());
+        let peer = handshaken_peer(&server).await;
+
+        // Request id 7, no block headers asked for or returned
+        let request_pair = vec![0xc2, 0x07, 0xc0];
+        server
+            .sender(peer)
+            .unwrap()
+            .send(OutboundEvent::Message {
+                capability_name: capability_name(),
+                message: Message {
+                    id: EthMessageId::GetBlockHeaders.to_usize().unwrap(),
+                    data: request_pair.clone().into(),
+                },
+            })
+            .await
+            .unwrap();
+        server.next(peer).await;
+        assert_eq!(server.metrics().answered(EthMessageId::GetBlockHeaders), 0);
+
+        for _ in 0..2 {
+            server
+                .handle_event(
+                    peer,
+                    message_with_data(EthMessageId::BlockHeaders, request_pair.clone()),
+                )
+                .unwrap();
+        }
+        // The repeated response matches no request anymore
+        assert_eq!(server.metrics().answered(EthMessageId::GetBlockHeaders), 1);
+        assert_eq!(
+            server.reputation.read().score(peer),
+            ReputationEvent::ValidResponse.score_delta()
+                + ReputationEvent::ProtocolBreach.score_delta()
+        );
+    }
+
+    #[tokio::test]
+    async fn exhausted_reputation_disconnects_peer() {
+        let server =
+            CapabilityServerImpl::new(EthProtocolVersion::Eth66, NonZeroUsize::new(1).unwrap())
+                .with_reputation(PeerReputation::new(-10));
+        let peer = handshaken_peer(&server).await;
+
+        assert!(matches!(
+            server.handle_event(
+                peer,
+                message_with_data(EthMessageId::BlockHeaders, vec![0xc2, 0x07, 0xc0])
+            ),
+            Err(DisconnectReason::UselessPeer)
+        ));
+        assert!(!server.is_banned(peer));
+
+        server.teardown_peer(peer);
+        assert_eq!(
+            server.reputation.read().score(peer),
+            reputation::INITIAL_SCORE
+        );
+    }
+
+    #[tokio::test]
+    async fn unsolicited_responses_ban_peer() {
+        let server =
+            CapabilityServerImpl::new(EthProtocolVersion::Eth66, NonZeroUsize::new(1).unwrap())
+                .with_ban_list(PeerBanList::new(
+                    2,
+                    Duration::from_secs(60),
+                    Duration::from_secs(600),
+                ));
+        let peer = handshaken_peer(&server).await;
+
+        // Request id 7 was never asked for
+        let response = message_with_data(EthMessageId::BlockHeaders, vec![0xc2, 0x07, 0xc0]);
+        assert!(server.handle_event(peer, response.clone()).is_ok());
+        assert!(!server.is_banned(peer));
+        assert!(matches!(
+            server.handle_event(peer, response),
+            Err(DisconnectReason::UselessPeer)
+        ));
+        assert!(server.is_banned(peer));
+    }
+
+    #[tokio::test]
+    async fn unsolicited_response_counts_once() {
+        // Every unsolicited response exhausts the reputation, so the peer is kicked right away
+        let server =
+            CapabilityServerImpl::new(EthProtocolVersion::Eth66, NonZeroUsize::new(1).unwrap())
+                .with_ban_list(PeerBanList::new(
+                    2,
+                    Duration::from_secs(60),
+                    Duration::from_secs(600),
+                ))
+                .with_reputation(PeerReputation::new(
+                    ReputationEvent::ProtocolBreach.score_delta(),
+                ));
+        let peer = handshaken_peer(&server).await;
+
+        let response = message_with_data(EthMessageId::BlockHeaders, vec![0xc2, 0x07, 0xc0]);
+        assert!(matches!(
+            server.handle_event(peer, response.clone()),
+            Err(DisconnectReason::UselessPeer)
+        ));
+        // A single violation, short of the threshold of two
+        assert!(!server.is_banned(peer));
+
+        assert!(matches!(
+            server.handle_event(peer, response),
+            Err(DisconnectReason::UselessPeer)
+        ));
+        assert!(server.is_banned(peer));
+    }
+
+    #[tokio::test]
+    async fn message_metrics() {
+        let metrics = Metrics::default();
+        let server =
+            CapabilityServerImpl::new(EthProtocolVersion::Eth66, NonZeroUsize::new(1).unwrap())
+                .with_metrics(metrics.clone());
+        let status = mainnet_status();
+        let data = fastrlp::encode_fixed_size(&status.to_status_message(EthProtocolVersion::Eth66))
+            .to_vec();
+        server.set_status(status);
+        let peer = peer_with_version(&server, EthProtocolVersion::Eth66);
+
+        server
+            .handle_event(peer, message_with_data(EthMessageId::Status, data.clone()))
+            .unwrap();
+        assert_eq!(metrics.received(EthMessageId::Status), 1);
+        assert_eq!(metrics.sent(EthMessageId::Status), 0);
+
+        server
+            .sender(peer)
+            .unwrap()
+            .send(OutboundEvent::Message {
+                capability_name: capability_name(),
+                message: Message {
+                    id: EthMessageId::Status.to_usize().unwrap(),
+                    data: data.into(),
+                },
+            })
+            .await
+            .unwrap();
+        assert!(matches!(
+            server.next(peer).await,
+            OutboundEvent::Message { .. }
+        ));
+        assert_eq!(metrics.sent(EthMessageId::Status), 1);
+        assert_eq!(metrics.received(EthMessageId::Status), 1);
+        assert_eq!(server.metrics().sent(EthMessageId::GetBlockHeaders), 0);
+    }
+}
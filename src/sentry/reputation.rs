@@ -0,0 +1,304 @@
+use super::{
+    devp2p::PeerId,
+    eth::{HandshakeError, ResponseError},
+};
+use std::{
+    collections::{HashMap, VecDeque},
+    time::{Duration, Instant},
+};
+
+/// Score peers start with.
+pub const INITIAL_SCORE: i32 = 0;
+/// Upper bound on score, so that a long good record does not excuse arbitrary misbehaviour.
+pub const MAX_SCORE: i32 = 100;
+/// Default score at or below which the peer should be disconnected.
+pub const DEFAULT_DISCONNECT_THRESHOLD: i32 = -100;
+/// Default number of protocol violations within [`DEFAULT_VIOLATION_WINDOW`] that get a peer
+/// banned.
+pub const DEFAULT_BAN_THRESHOLD: usize = 3;
+pub const DEFAULT_VIOLATION_WINDOW: Duration = Duration::from_secs(10 * 60);
+pub const DEFAULT_BAN_DURATION: Duration = Duration::from_secs(30 * 60);
+
+/// Peer behaviour observed by the eth layer.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ReputationEvent {
+    /// Valid response to one of our requests, received in time.
+    ValidResponse,
+    /// Request timed out without a response.
+    Timeout,
+    /// Message was malformed or not allowed by the protocol.
+    ProtocolBreach,
+    /// Peer's status does not match our chain.
+    InvalidForkId,
+    /// Peer is on our chain, but has not caught up with our forks.
+    StaleForkId,
+}
+
+impl ReputationEvent {
+    pub const fn score_delta(self) -> i32 {
+        match self {
+            Self::ValidResponse => 1,
+            Self::Timeout => -10,
+            Self::ProtocolBreach => -50,
+            Self::InvalidForkId => -100,
+            Self::StaleForkId => -10,
+        }
+    }
+}
+
+impl From<&HandshakeError> for ReputationEvent {
+    fn from(e: &HandshakeError) -> Self {
+        match e {
+            HandshakeError::GenesisMismatch { .. }
+            | HandshakeError::NetworkIdMismatch { .. }
+            | HandshakeError::IncompatibleFork(_)
+            | HandshakeError::DaoForkRejected(_) => Self::InvalidForkId,
+            HandshakeError::StaleFork(_) => Self::StaleForkId,
+            HandshakeError::Timeout(_) => Self::Timeout,
+            HandshakeError::ProtocolVersionMismatch { .. }
+            | HandshakeError::UnexpectedMessage(_)
+            | HandshakeError::MalformedStatus(_)
+            | HandshakeError::ContradictoryStatus(_)
+            | HandshakeError::ImplausibleTotalDifficulty { .. } => Self::ProtocolBreach,
+        }
+    }
+}
+
+impl From<&ResponseError> for ReputationEvent {
+    fn from(_: &ResponseError) -> Self {
+        Self::ProtocolBreach
+    }
+}
+
+/// Running score of each peer, updated on every [`ReputationEvent`].
+#[derive(Debug)]
+pub struct PeerReputation {
+    disconnect_threshold: i32,
+    scores: HashMap<PeerId, i32>,
+}
+
+impl Default for PeerReputation {
+    fn default() -> Self {
+        Self::new(DEFAULT_DISCONNECT_THRESHOLD)
+    }
+}
+
+impl PeerReputation {
+    pub fn new(disconnect_threshold: i32) -> Self {
+        Self {
+            disconnect_threshold,
+            scores: Default::default(),
+        }
+    }
+
+    pub fn score(&self, peer: PeerId) -> i32 {
+        self.scores.get(&peer).copied().unwrap_or(INITIAL_SCORE)
+    }
+
+    /// Applies the event to the peer's score, returning whether the peer should now be
+    /// disconnected.
+    pub fn record_event(&mut self, peer: PeerId, event: ReputationEvent) -> bool {
+        let score = self.scores.entry(peer).or_insert(INITIAL_SCORE);
+        *score = score.saturating_add(event.score_delta()).min(MAX_SCORE);
+
+        *score <= self.disconnect_threshold
+    }
+
+    pub fn should_disconnect(&self, peer: PeerId) -> bool {
+        self.score(peer) <= self.disconnect_threshold
+    }
+
+    /// Forgets the peer, to be called once it disconnects.
+    pub fn remove(&mut self, peer: PeerId) {
+        self.scores.remove(&peer);
+    }
+}
+
+/// Peers banned for repeated protocol violations, refused until their ban expires.
+#[derive(Debug)]
+pub struct PeerBanList {
+    threshold: usize,
+    window: Duration,
+    ban_duration: Duration,
+    /// Times of recent violations, oldest first.
+    violations: HashMap<PeerId, VecDeque<Instant>>,
+    /// End of each active ban.
+    banned_until: HashMap<PeerId, Instant>,
+}
+
+impl Default for PeerBanList {
+    fn default() -> Self {
+        Self::new(
+            DEFAULT_BAN_THRESHOLD,
+            DEFAULT_VIOLATION_WINDOW,
+            DEFAULT_BAN_DURATION,
+        )
+    }
+}
+
+impl PeerBanList {
+    pub fn new(threshold: usize, window: Duration, ban_duration: Duration) -> Self {
+        Self {
+            threshold,
+            window,
+            ban_duration,
+            violations: Default::default(),
+            banned_until: Default::default(),
+        }
+    }
+
+    /// Records a violation at `now`, returning whether the peer is banned as a result.
+    ///
+    /// Expired bans and violations of all peers are dropped on the way, so that the list does not
+    /// grow with every peer that ever misbehaved.
+    pub fn record_violation(&mut self, peer: PeerId, now: Instant) -> bool {
+        self.expire(now);
+
+        let violations = self.violations.entry(peer).or_default();
+        violations.push_back(now);
+
+        if violations.len() >= self.threshold {
+            self.violations.remove(&peer);
+            self.banned_until.insert(peer, now + self.ban_duration);
+        }
+
+        self.is_banned(peer, now)
+    }
+
+    pub fn is_banned(&self, peer: PeerId, now: Instant) -> bool {
+        matches!(self.banned_until.get(&peer), Some(&until) if now < until)
+    }
+
+    /// Forgets expired bans and violations too old to count.
+    pub fn expire(&mut self, now: Instant) {
+        let window = self.window;
+        self.banned_until.retain(|_, &mut until| now < until);
+        self.violations.retain(|_, violations| {
+            violations.retain(|&at| now.saturating_duration_since(at) <= window);
+            !violations.is_empty()
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{models::H256, sentry::eth::EthMessageId};
+
+    #[test]
+    fn score_transitions() {
+        let peer = PeerId::random();
+        let other = PeerId::random();
+        let mut reputation = PeerReputation::default();
+        assert_eq!(reputation.score(peer), INITIAL_SCORE);
+
+        assert!(!reputation.record_event(peer, ReputationEvent::ValidResponse));
+        assert!(!reputation.record_event(peer, ReputationEvent::ValidResponse));
+        assert_eq!(reputation.score(peer), 2);
+
+        assert!(!reputation.record_event(peer, ReputationEvent::Timeout));
+        assert_eq!(reputation.score(peer), -8);
+        assert_eq!(reputation.score(other), INITIAL_SCORE);
+
+        for _ in 0..1000 {
+            reputation.record_event(peer, ReputationEvent::ValidResponse);
+        }
+        assert_eq!(reputation.score(peer), MAX_SCORE);
+
+        reputation.remove(peer);
+        assert_eq!(reputation.score(peer), INITIAL_SCORE);
+    }
+
+    #[test]
+    fn disconnect_threshold() {
+        let peer = PeerId::random();
+        let mut reputation = PeerReputation::default();
+
+        assert!(!reputation.record_event(peer, ReputationEvent::ProtocolBreach));
+        assert!(!reputation.should_disconnect(peer));
+        assert!(reputation.record_event(peer, ReputationEvent::ProtocolBreach));
+        assert!(reputation.should_disconnect(peer));
+
+        // A perfect record absorbs a single invalid fork id, but not two
+        let peer = PeerId::random();
+        for _ in 0..MAX_SCORE {
+            reputation.record_event(peer, ReputationEvent::ValidResponse);
+        }
+        assert!(!reputation.record_event(peer, ReputationEvent::InvalidForkId));
+        assert!(reputation.record_event(peer, ReputationEvent::InvalidForkId));
+
+        let mut strict = PeerReputation::new(-10);
+        let peer = PeerId::random();
+        assert!(strict.record_event(peer, ReputationEvent::Timeout));
+    }
+
+    #[test]
+    fn ban_list() {
+        let window = Duration::from_secs(60);
+        let ban_duration = Duration::from_secs(600);
+        let mut bans = PeerBanList::new(3, window, ban_duration);
+        let peer = PeerId::random();
+        let other = PeerId::random();
+        let start = Instant::now();
+
+        assert!(!bans.record_violation(peer, start));
+        assert!(!bans.record_violation(peer, start + Duration::from_secs(10)));
+        // First violation is out of the window by now
+        assert!(!bans.record_violation(peer, start + Duration::from_secs(61)));
+        assert!(!bans.is_banned(peer, start + Duration::from_secs(61)));
+
+        let banned_at = start + Duration::from_secs(62);
+        assert!(bans.record_violation(peer, banned_at));
+        assert!(bans.is_banned(peer, banned_at + ban_duration - Duration::from_secs(1)));
+        assert!(!bans.is_banned(other, banned_at));
+
+        // Ban expires, and the violations leading to it are not held against the peer anymore
+        let expired = banned_at + ban_duration;
+        assert!(!bans.is_banned(peer, expired));
+        bans.expire(expired);
+        assert!(!bans.record_violation(peer, expired));
+        assert!(!bans.is_banned(peer, expired));
+    }
+
+    #[test]
+    fn ban_list_prunes_on_violation() {
+        let window = Duration::from_secs(60);
+        let ban_duration = Duration::from_secs(600);
+        let mut bans = PeerBanList::new(1, window, ban_duration);
+        let start = Instant::now();
+
+        let banned = PeerId::random();
+        assert!(bans.record_violation(banned, start));
+        let mut once = PeerBanList::new(2, window, ban_duration);
+        once.record_violation(banned, start);
+
+        // Another peer misbehaving later sweeps out what has expired in the meantime
+        let later = start + ban_duration;
+        bans.record_violation(PeerId::random(), later);
+        once.record_violation(PeerId::random(), later);
+        assert!(!bans.banned_until.contains_key(&banned));
+        assert!(!once.violations.contains_key(&banned));
+    }
+
+    #[test]
+    fn event_from_errors() {
+        assert_eq!(
+            ReputationEvent::from(&HandshakeError::GenesisMismatch {
+                ours: H256::zero(),
+                theirs: H256::repeat_byte(1),
+            }),
+            ReputationEvent::InvalidForkId
+        );
+        assert_eq!(
+            ReputationEvent::from(&HandshakeError::UnexpectedMessage(3)),
+            ReputationEvent::ProtocolBreach
+        );
+        assert_eq!(
+            ReputationEvent::from(&ResponseError::Unsolicited {
+                request_id: 1,
+                response: EthMessageId::BlockHeaders,
+            }),
+            ReputationEvent::ProtocolBreach
+        );
+    }
+}
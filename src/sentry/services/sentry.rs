@@ -84,7 +84,7 @@ impl SentryService {
             ProtoMessageId::from_i32(request.id)
                 .ok_or_else(|| anyhow::anyhow!("Invalid message id: {}", request.id))?,
         )?;
-        let capability_name = capability_name();
+        let capability_name = self.capability_server.capability_name();
         let id = eth_id.to_usize().unwrap();
         let message = OutboundEvent::Message {
             capability_name,
@@ -266,10 +266,10 @@ impl Sentry for SentryService {
         &self,
         request: tonic::Request<ethereum_interfaces::sentry::StatusData>,
     ) -> Result<Response<SetStatusReply>, tonic::Status> {
-        let s = FullStatusData::try_from(request.into_inner())
+        let s = StatusData::try_from(request.into_inner())
             .map_err(|e| tonic::Status::invalid_argument(e.to_string()))?;
 
-        self.capability_server.set_status(s);
+        self.capability_server.update_status(s);
 
         Ok(Response::new(SetStatusReply {}))
     }
@@ -0,0 +1,109 @@
+//! Messages of the `snap` protocol, served alongside `eth` for snapshot sync.
+
+use super::devp2p::*;
+use crate::models::*;
+use arrayvec::ArrayString;
+use bytes::Bytes;
+use enum_primitive_derive::*;
+use fastrlp::*;
+
+pub fn snap_capability_name() -> CapabilityName {
+    CapabilityName(ArrayString::from("snap").unwrap())
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Primitive)]
+pub enum SnapMessageId {
+    GetAccountRange = 0,
+    AccountRange = 1,
+}
+
+/// Requests the accounts of the state trie at `root` with hashes between `starting_hash` and
+/// `limit_hash`, along with the proofs of the range.
+#[derive(Debug, Clone, PartialEq, Eq, RlpEncodable, RlpDecodable)]
+pub struct GetAccountRange {
+    pub request_id: u64,
+    pub root: H256,
+    pub starting_hash: H256,
+    pub limit_hash: H256,
+    /// Soft limit on the size of the response.
+    pub response_bytes: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, RlpEncodable, RlpDecodable)]
+pub struct AccountRangeEntry {
+    pub hash: H256,
+    /// Account in the slim RLP format, with empty storage root and code hash left out.
+    pub body: Bytes,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, RlpEncodable, RlpDecodable)]
+pub struct AccountRange {
+    pub request_id: u64,
+    pub accounts: Vec<AccountRangeEntry>,
+    /// Trie nodes proving the first and last account of the range.
+    pub proof: Vec<Bytes>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::BytesMut;
+    use hex_literal::hex;
+    use num_traits::{FromPrimitive, ToPrimitive};
+
+    fn rlp_round_trip<T: Encodable + Decodable + PartialEq + std::fmt::Debug>(v: &T) {
+        let mut encoded = BytesMut::new();
+        v.encode(&mut encoded);
+        assert_eq!(encoded.len(), v.length());
+
+        let buf = &mut &*encoded;
+        assert_eq!(&T::decode(buf).unwrap(), v);
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn snap_capability() {
+        assert_eq!(snap_capability_name().0.as_str(), "snap");
+        assert_eq!(SnapMessageId::AccountRange.to_usize(), Some(1));
+        assert_eq!(SnapMessageId::from_usize(2), None);
+    }
+
+    #[test]
+    fn get_account_range_rlp() {
+        rlp_round_trip(&GetAccountRange {
+            request_id: 1111,
+            root: H256(hex!(
+                "d7f8974fb5ac78d9ac099b9ad5018bedc2ce0a72dad1827a1709da30580f0544"
+            )),
+            starting_hash: H256::zero(),
+            limit_hash: H256::repeat_byte(0xff),
+            response_bytes: 512 * 1024,
+        });
+    }
+
+    #[test]
+    fn account_range_rlp() {
+        rlp_round_trip(&AccountRange {
+            request_id: 1111,
+            accounts: vec![],
+            proof: vec![],
+        });
+        rlp_round_trip(&AccountRange {
+            request_id: 1111,
+            accounts: vec![
+                AccountRangeEntry {
+                    hash: H256::repeat_byte(1),
+                    body: Bytes::from_static(&hex!("c4808080c0")),
+                },
+                AccountRangeEntry {
+                    hash: H256::repeat_byte(2),
+                    body: Bytes::from_static(&hex!("c8018405f5e1008080")),
+                },
+            ],
+            proof: vec![
+                Bytes::from_static(&hex!("c0")),
+                Bytes::from_static(&[0xde; 40]),
+            ],
+        });
+    }
+}
@@ -4,7 +4,7 @@ use crate::{
     accessors,
     consensus::{fork_choice_graph::ForkChoiceGraph, Consensus, ForkChoiceMode},
     kv::{mdbx::*, tables},
-    models::{BlockHeader, BlockNumber, H256},
+    models::{add_difficulty, BlockHeader, BlockNumber, H256},
     p2p::{
         node::{Node, NodeStream},
         types::{BlockHeaders, BlockId, HeaderRequest, Message, Status},
@@ -246,7 +246,7 @@ where
                 }
 
                 let block_number = header.number;
-                td += header.difficulty;
+                td = add_difficulty(td, header.difficulty)?;
 
                 cursor_header_number.put(hash, block_number)?;
                 cursor_header.append(block_number, header)?;
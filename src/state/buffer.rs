@@ -3,22 +3,355 @@ use crate::{
     kv::{
         mdbx::*,
         tables::{self, AccountChange, StorageChange, StorageChangeKey},
+        traits::{TableDecode, TableEncode},
     },
     models::*,
     state::database::*,
     u256_to_h256, BlockReader, HeaderReader, StateReader, StateWriter,
 };
+use anyhow::bail;
 use bytes::Bytes;
-use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::collections::{btree_map::Entry, BTreeMap, BTreeSet, HashMap};
 use tokio::pin;
 use tracing::*;
 
 // address -> storage-encoded initial value
+//
+// Change sets record the value before the change, `None` if the account did not exist.
 pub type AccountChanges = BTreeMap<Address, Option<Account>>;
 
 // address -> location -> zeroless initial value
 pub type StorageChanges = BTreeMap<Address, BTreeMap<U256, U256>>;
 
+/// How to resolve entries present in both change sets being merged.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MergeStrategy {
+    /// Existing entries are kept. Merging the change sets of a block's transactions in order
+    /// thus keeps the values from before the block, as change sets are to record: use this to
+    /// aggregate them.
+    FirstWriterWins,
+    /// Entries of the merged in change set replace existing ones.
+    ///
+    /// The result no longer records the values before the earlier changes, so this is not fit
+    /// for aggregating consecutive change sets.
+    LastWriterWins,
+    /// Merging fails if an entry is present in both change sets with different values.
+    RejectConflicts,
+}
+
+/// Merges `other` into `changes`. Nothing is merged if a conflict is rejected.
+pub fn merge_account_changes(
+    changes: &mut AccountChanges,
+    other: AccountChanges,
+    strategy: MergeStrategy,
+) -> anyhow::Result<()> {
+    if strategy == MergeStrategy::RejectConflicts {
+        for (address, account) in &other {
+            if matches!(changes.get(address), Some(existing) if existing != account) {
+                bail!("conflicting changes for account {:?}", address);
+            }
+        }
+    }
+
+    if strategy == MergeStrategy::FirstWriterWins {
+        for (address, account) in other {
+            changes.entry(address).or_insert(account);
+        }
+    } else {
+        changes.extend(other);
+    }
+
+    Ok(())
+}
+
+/// Merges `other` into `changes`. Nothing is merged if a conflict is rejected.
+pub fn merge_storage_changes(
+    changes: &mut StorageChanges,
+    other: StorageChanges,
+    strategy: MergeStrategy,
+) -> anyhow::Result<()> {
+    if strategy == MergeStrategy::RejectConflicts {
+        for (address, slots) in &other {
+            if let Some(existing_slots) = changes.get(address) {
+                for (location, value) in slots {
+                    if matches!(existing_slots.get(location), Some(existing) if existing != value) {
+                        bail!(
+                            "conflicting changes for storage location {} of account {:?}",
+                            location,
+                            address
+                        );
+                    }
+                }
+            }
+        }
+    }
+
+    for (address, slots) in other {
+        match changes.entry(address) {
+            Entry::Vacant(entry) => {
+                entry.insert(slots);
+            }
+            Entry::Occupied(mut entry) => {
+                let existing_slots = entry.get_mut();
+                if strategy == MergeStrategy::FirstWriterWins {
+                    for (location, value) in slots {
+                        existing_slots.entry(location).or_insert(value);
+                    }
+                } else {
+                    existing_slots.extend(slots);
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Differences between two change sets, from the first one to the second one.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ChangeSetDiff<K, V> {
+    /// Entries only present in the second change set.
+    pub added: BTreeMap<K, V>,
+    /// Entries only present in the first change set.
+    pub removed: BTreeMap<K, V>,
+    /// Entries present in both with different values, first value first.
+    pub changed: BTreeMap<K, (V, V)>,
+}
+
+impl<K, V> Default for ChangeSetDiff<K, V> {
+    fn default() -> Self {
+        Self {
+            added: Default::default(),
+            removed: Default::default(),
+            changed: Default::default(),
+        }
+    }
+}
+
+impl<K, V> ChangeSetDiff<K, V>
+where
+    K: Ord + Clone,
+    V: PartialEq + Clone,
+{
+    fn new<'a>(
+        changes: impl IntoIterator<Item = (K, &'a V)>,
+        other: impl IntoIterator<Item = (K, &'a V)>,
+    ) -> Self
+    where
+        V: 'a,
+    {
+        let mut diff = Self::default();
+        let mut other = other.into_iter().collect::<BTreeMap<_, _>>();
+        for (key, value) in changes {
+            match other.remove(&key) {
+                None => {
+                    diff.removed.insert(key, value.clone());
+                }
+                Some(other_value) if other_value != value => {
+                    diff.changed
+                        .insert(key, (value.clone(), other_value.clone()));
+                }
+                Some(_) => {}
+            }
+        }
+        diff.added = other
+            .into_iter()
+            .map(|(key, value)| (key, value.clone()))
+            .collect();
+
+        diff
+    }
+
+    /// Whether both change sets are identical.
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.changed.is_empty()
+    }
+}
+
+/// Compares two account change sets, e.g. produced by independent executions of the same block.
+pub fn diff_account_changes(
+    changes: &AccountChanges,
+    other: &AccountChanges,
+) -> ChangeSetDiff<Address, Option<Account>> {
+    ChangeSetDiff::new(
+        changes.iter().map(|(&address, account)| (address, account)),
+        other.iter().map(|(&address, account)| (address, account)),
+    )
+}
+
+/// Compares two storage change sets, keyed by account and location.
+pub fn diff_storage_changes(
+    changes: &StorageChanges,
+    other: &StorageChanges,
+) -> ChangeSetDiff<(Address, U256), U256> {
+    fn flatten(changes: &StorageChanges) -> impl Iterator<Item = ((Address, U256), &U256)> {
+        changes.iter().flat_map(|(&address, slots)| {
+            slots
+                .iter()
+                .map(move |(&location, value)| ((address, location), value))
+        })
+    }
+
+    ChangeSetDiff::new(flatten(changes), flatten(other))
+}
+
+/// Writes accounts to an in-memory state, `None` deleting the account.
+///
+/// `updates` hold the values to write, not the initial values a block's change set records.
+/// Returns the change set of the update, to be passed to [`revert_account_changes`].
+pub fn apply_account_changes(
+    state: &mut HashMap<Address, Account>,
+    updates: &AccountChanges,
+) -> AccountChanges {
+    updates
+        .iter()
+        .map(|(&address, &account)| {
+            let initial = match account {
+                Some(account) => state.insert(address, account),
+                None => state.remove(&address),
+            };
+            (address, initial)
+        })
+        .collect()
+}
+
+/// Rolls an in-memory state back over a change set, restoring the initial value of each account.
+pub fn revert_account_changes(state: &mut HashMap<Address, Account>, changes: &AccountChanges) {
+    apply_account_changes(state, changes);
+}
+
+/// Encodes block's account changes into raw `AccountChangeSet` entries, in address order.
+pub fn encode_account_changes(
+    block_number: BlockNumber,
+    changes: &AccountChanges,
+) -> Vec<(Bytes, Bytes)> {
+    changes
+        .iter()
+        .map(|(&address, &account)| {
+            (
+                Bytes::copy_from_slice(&block_number.encode()),
+                Bytes::copy_from_slice(&AccountChange { address, account }.encode()),
+            )
+        })
+        .collect()
+}
+
+/// Decodes a raw `AccountChangeSet` entry, checking that it belongs to the expected block.
+pub fn decode_account_change(
+    expected_block: BlockNumber,
+    k: &[u8],
+    v: &[u8],
+) -> anyhow::Result<AccountChange> {
+    let block_number = <BlockNumber as TableDecode>::decode(k)?;
+    if block_number != expected_block {
+        bail!(
+            "account change recorded for block {} while block {} was expected",
+            block_number,
+            expected_block
+        );
+    }
+
+    <AccountChange as TableDecode>::decode(v)
+}
+
+/// Rebuilds block's account changes from raw `AccountChangeSet` entries, as produced by
+/// [`encode_account_changes`].
+pub fn decode_account_changes<K, V>(
+    expected_block: BlockNumber,
+    entries: impl IntoIterator<Item = (K, V)>,
+) -> anyhow::Result<AccountChanges>
+where
+    K: AsRef<[u8]>,
+    V: AsRef<[u8]>,
+{
+    let mut changes = AccountChanges::new();
+    for (k, v) in entries {
+        let AccountChange { address, account } =
+            decode_account_change(expected_block, k.as_ref(), v.as_ref())?;
+        if changes.insert(address, account).is_some() {
+            bail!("duplicate change for account {:?}", address);
+        }
+    }
+
+    Ok(changes)
+}
+
+/// Total length of keys and values produced by [`encode_account_changes`], without allocating them.
+pub fn account_changes_encoded_size(block_number: BlockNumber, changes: &AccountChanges) -> usize {
+    changes
+        .values()
+        .map(|account| {
+            block_number.encode().len()
+                + ADDRESS_LENGTH
+                + account.map_or(0, |account| account.encode_for_storage().len())
+        })
+        .sum()
+}
+
+/// Version of the format produced by [`account_changes_to_snapshot`].
+pub const ACCOUNT_CHANGES_SNAPSHOT_VERSION: u8 = 1;
+
+/// Serializes account changes for fixtures and debugging, independently of the database encoding.
+///
+/// The snapshot is the format version, the number of entries as big-endian `u32`, then for every
+/// entry the address followed by the big-endian `u32` length of the storage encoding of the
+/// account and the encoding itself, empty if the account did not exist.
+pub fn account_changes_to_snapshot(changes: &AccountChanges) -> Vec<u8> {
+    let mut out = vec![ACCOUNT_CHANGES_SNAPSHOT_VERSION];
+    out.extend_from_slice(&u32::try_from(changes.len()).unwrap().to_be_bytes());
+    for (address, account) in changes {
+        let account = account.map(|account| account.encode_for_storage());
+        let account = account.as_ref().map_or(&[][..], |account| account.as_ref());
+
+        out.extend_from_slice(address.as_bytes());
+        out.extend_from_slice(&(account.len() as u32).to_be_bytes());
+        out.extend_from_slice(account);
+    }
+    out
+}
+
+/// Parses a snapshot produced by [`account_changes_to_snapshot`].
+pub fn account_changes_from_snapshot(mut snapshot: &[u8]) -> anyhow::Result<AccountChanges> {
+    fn take<'a>(snapshot: &mut &'a [u8], len: usize) -> anyhow::Result<&'a [u8]> {
+        if snapshot.len() < len {
+            bail!("snapshot truncated");
+        }
+        let (taken, rest) = snapshot.split_at(len);
+        *snapshot = rest;
+        Ok(taken)
+    }
+
+    fn take_u32(snapshot: &mut &[u8]) -> anyhow::Result<usize> {
+        Ok(u32::from_be_bytes(take(snapshot, 4)?.try_into().unwrap()) as usize)
+    }
+
+    let version = take(&mut snapshot, 1)?[0];
+    if version != ACCOUNT_CHANGES_SNAPSHOT_VERSION {
+        bail!(
+            "unsupported snapshot version {}, expected {}",
+            version,
+            ACCOUNT_CHANGES_SNAPSHOT_VERSION
+        );
+    }
+
+    let mut changes = AccountChanges::new();
+    for _ in 0..take_u32(&mut snapshot)? {
+        let address = Address::from_slice(take(&mut snapshot, ADDRESS_LENGTH)?);
+        let len = take_u32(&mut snapshot)?;
+        let account = Account::decode_for_storage(take(&mut snapshot, len)?)?;
+
+        if changes.insert(address, account).is_some() {
+            bail!("duplicate entry for account {:?}", address);
+        }
+    }
+
+    if !snapshot.is_empty() {
+        bail!("{} trailing bytes after snapshot", snapshot.len());
+    }
+
+    Ok(changes)
+}
+
 #[derive(Default, Debug)]
 struct OverlayStorage {
     erased: bool,
@@ -60,12 +393,21 @@ where
     pub fn new(
         txn: &'tx MdbxTransaction<'db, K, E>,
         historical_block: Option<BlockNumber>,
+    ) -> Self {
+        Self::with_capacity(txn, historical_block, 0)
+    }
+
+    /// Buffer pre-sized for changes to about `accounts` accounts.
+    pub fn with_capacity(
+        txn: &'tx MdbxTransaction<'db, K, E>,
+        historical_block: Option<BlockNumber>,
+        accounts: usize,
     ) -> Self {
         Self {
             txn,
             historical_block,
-            accounts: Default::default(),
-            storage: Default::default(),
+            accounts: HashMap::with_capacity(accounts),
+            storage: HashMap::with_capacity(accounts),
             account_changes: Default::default(),
             storage_changes: Default::default(),
             hash_to_code: Default::default(),
@@ -217,7 +559,8 @@ where
                 .or_default();
 
             for (slot, value) in overlay_storage.slots.drain() {
-                storage_changes.insert(slot, value);
+                // Slots already written in this block keep their value from before the block.
+                storage_changes.entry(slot).or_insert(value);
             }
         }
 
@@ -288,12 +631,15 @@ where
         current: U256,
     ) -> anyhow::Result<()> {
         if initial != current {
+            // Repeated writes within a block collapse into a single change, holding the value
+            // before the first write.
             self.storage_changes
                 .entry(self.block_number)
                 .or_default()
                 .entry(address)
                 .or_default()
-                .insert(location, initial);
+                .entry(location)
+                .or_insert(initial);
 
             self.storage
                 .entry(address)
@@ -491,6 +837,527 @@ mod tests {
         assert_eq!(db_value_b, value_b);
     }
 
+    #[test]
+    fn account_changes_encoding() {
+        let block_number = BlockNumber(13);
+        let changes: AccountChanges = [
+            (
+                hex!("be00000000000000000000000000000000000000").into(),
+                None,
+            ),
+            (
+                hex!("a000000000000000000000000000000000000000").into(),
+                Some(Account {
+                    nonce: 7,
+                    balance: 0x1234.as_u256(),
+                    ..Default::default()
+                }),
+            ),
+            (
+                hex!("ff00000000000000000000000000000000000000").into(),
+                Some(Account::default()),
+            ),
+        ]
+        .into_iter()
+        .collect();
+
+        let encoded = encode_account_changes(block_number, &changes);
+        assert_eq!(encoded.len(), changes.len());
+        assert_eq!(
+            account_changes_encoded_size(block_number, &changes),
+            encoded
+                .iter()
+                .map(|(k, v)| k.len() + v.len())
+                .sum::<usize>()
+        );
+        assert_eq!(
+            account_changes_encoded_size(block_number, &Default::default()),
+            0
+        );
+
+        assert_eq!(
+            decode_account_changes(block_number, encoded.clone()).unwrap(),
+            changes
+        );
+        assert!(decode_account_changes(BlockNumber(12), encoded.clone()).is_err());
+        assert!(
+            decode_account_changes(block_number, [encoded[0].clone(), encoded[0].clone()]).is_err()
+        );
+    }
+
+    #[test]
+    fn account_changes_snapshot() {
+        let changes: AccountChanges = [
+            (
+                hex!("be00000000000000000000000000000000000000").into(),
+                None,
+            ),
+            (
+                hex!("a000000000000000000000000000000000000000").into(),
+                Some(Account {
+                    nonce: 7,
+                    balance: 0x1234.as_u256(),
+                    code_hash: H256::repeat_byte(0xcd),
+                }),
+            ),
+            (
+                hex!("ff00000000000000000000000000000000000000").into(),
+                Some(Account::default()),
+            ),
+        ]
+        .into_iter()
+        .collect();
+
+        let snapshot = account_changes_to_snapshot(&changes);
+        assert_eq!(snapshot[0], ACCOUNT_CHANGES_SNAPSHOT_VERSION);
+        assert_eq!(account_changes_from_snapshot(&snapshot).unwrap(), changes);
+        assert_eq!(
+            account_changes_from_snapshot(&account_changes_to_snapshot(&Default::default()))
+                .unwrap(),
+            AccountChanges::new()
+        );
+
+        assert!(account_changes_from_snapshot(&snapshot[..snapshot.len() - 1]).is_err());
+        assert!(account_changes_from_snapshot(&[snapshot.as_slice(), &[0]].concat()).is_err());
+        assert!(account_changes_from_snapshot(&[]).is_err());
+    }
+
+    #[test]
+    fn truncated_account_in_snapshot() {
+        let address = hex!("a000000000000000000000000000000000000000");
+        let account = Account {
+            nonce: 7,
+            balance: 0x1234.as_u256(),
+            code_hash: H256::repeat_byte(0xcd),
+        }
+        .encode_for_storage();
+
+        // Entry lengths agree with the snapshot, but the encoded accounts themselves are malformed
+        for truncated in [&account[..3], &[0x0f; 4][..]] {
+            let mut snapshot = vec![ACCOUNT_CHANGES_SNAPSHOT_VERSION];
+            snapshot.extend_from_slice(&1_u32.to_be_bytes());
+            snapshot.extend_from_slice(&address);
+            snapshot.extend_from_slice(&(truncated.len() as u32).to_be_bytes());
+            snapshot.extend_from_slice(truncated);
+
+            assert!(account_changes_from_snapshot(&snapshot).is_err());
+        }
+    }
+
+    #[test]
+    fn account_changes_snapshot_version_mismatch() {
+        let mut snapshot = account_changes_to_snapshot(
+            &[(
+                hex!("be00000000000000000000000000000000000000").into(),
+                None,
+            )]
+            .into_iter()
+            .collect(),
+        );
+        snapshot[0] = ACCOUNT_CHANGES_SNAPSHOT_VERSION + 1;
+
+        let e = account_changes_from_snapshot(&snapshot).unwrap_err();
+        assert!(e.to_string().contains("unsupported snapshot version"));
+    }
+
+    #[test]
+    fn change_set_diff() {
+        let a = hex!("a000000000000000000000000000000000000000").into();
+        let b = hex!("be00000000000000000000000000000000000000").into();
+        let changes: AccountChanges = [
+            (a, None),
+            (
+                b,
+                Some(Account {
+                    nonce: 7,
+                    ..Default::default()
+                }),
+            ),
+        ]
+        .into_iter()
+        .collect();
+        assert!(diff_account_changes(&changes, &changes.clone()).is_empty());
+
+        let mut other = changes.clone();
+        other.insert(
+            b,
+            Some(Account {
+                nonce: 8,
+                ..Default::default()
+            }),
+        );
+        let diff = diff_account_changes(&changes, &other);
+        assert!(!diff.is_empty());
+        assert!(diff.added.is_empty());
+        assert!(diff.removed.is_empty());
+        assert_eq!(
+            diff.changed,
+            [(b, (changes[&b], other[&b]))].into_iter().collect()
+        );
+
+        other.remove(&a);
+        let diff = diff_account_changes(&changes, &other);
+        assert_eq!(diff.removed, [(a, None)].into_iter().collect());
+        let diff = diff_account_changes(&other, &changes);
+        assert_eq!(diff.added, [(a, None)].into_iter().collect());
+        assert_eq!(diff.changed.len(), 1);
+
+        let storage: StorageChanges = [(a, [(1.as_u256(), 2.as_u256())].into_iter().collect())]
+            .into_iter()
+            .collect();
+        let mut other_storage = storage.clone();
+        other_storage
+            .entry(a)
+            .or_default()
+            .insert(3.as_u256(), 4.as_u256());
+        assert!(diff_storage_changes(&storage, &storage).is_empty());
+        let diff = diff_storage_changes(&storage, &other_storage);
+        assert_eq!(
+            diff.added,
+            [((a, 3.as_u256()), 4.as_u256())].into_iter().collect()
+        );
+        assert!(diff.removed.is_empty() && diff.changed.is_empty());
+    }
+
+    #[test]
+    fn decode_account_change_block_check() {
+        let change = AccountChange {
+            address: hex!("be00000000000000000000000000000000000000").into(),
+            account: Some(Account {
+                nonce: 3,
+                ..Default::default()
+            }),
+        };
+        let changes = [(change.address, change.account)].into_iter().collect();
+        let encoded = encode_account_changes(BlockNumber(2), &changes);
+        let (k, v) = &encoded[0];
+
+        assert_eq!(decode_account_change(BlockNumber(2), k, v).unwrap(), change);
+        assert!(decode_account_change(BlockNumber(1), k, v).is_err());
+    }
+
+    #[test]
+    fn account_changes_order() {
+        let changes: AccountChanges = [
+            hex!("ff00000000000000000000000000000000000000"),
+            hex!("0000000000000000000000000000000000000001"),
+            hex!("a000000000000000000000000000000000000000"),
+            hex!("00ff000000000000000000000000000000000000"),
+        ]
+        .into_iter()
+        .map(|address| (Address::from(address), None))
+        .collect();
+
+        assert_eq!(changes.len(), 4);
+        assert!(!changes.is_empty());
+        assert!(AccountChanges::new().is_empty());
+
+        // Changes are appended as sorted duplicates, so iteration must follow encoded order
+        let addresses = changes.keys().copied().collect::<Vec<_>>();
+        let encoded = encode_account_changes(BlockNumber(1), &changes);
+        let mut sorted = encoded.clone();
+        sorted.sort();
+        assert_eq!(encoded, sorted);
+        assert_eq!(
+            encoded
+                .iter()
+                .map(|(_, v)| Address::from_slice(&v[..ADDRESS_LENGTH]))
+                .collect::<Vec<_>>(),
+            addresses
+        );
+    }
+
+    #[test]
+    fn buffer_with_capacity() {
+        let db = new_mem_chaindata().unwrap();
+        let txn = db.begin_mutable().unwrap();
+
+        let n = 100;
+        let mut buffer = Buffer::with_capacity(&txn, None, n);
+        assert!(buffer.accounts.capacity() >= n);
+
+        buffer.begin_block(BlockNumber(1));
+        // Touch accounts in reverse address order
+        for i in (0..n as u64).rev() {
+            buffer.update_account(
+                Address::from_low_u64_be(i),
+                None,
+                Some(Account {
+                    nonce: i,
+                    ..Default::default()
+                }),
+            );
+        }
+
+        let changes = &buffer.account_changes[&BlockNumber(1)];
+        assert_eq!(changes.len(), n);
+        assert_eq!(
+            changes.keys().copied().collect::<Vec<_>>(),
+            (0..n as u64)
+                .map(Address::from_low_u64_be)
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(
+            buffer.read_account(Address::from_low_u64_be(7)).unwrap(),
+            Some(Account {
+                nonce: 7,
+                ..Default::default()
+            })
+        );
+    }
+
+    #[test]
+    fn apply_changes() {
+        let address1: Address = hex!("a000000000000000000000000000000000000000").into();
+        let address2: Address = hex!("b000000000000000000000000000000000000000").into();
+        let address3: Address = hex!("c000000000000000000000000000000000000000").into();
+        let account = |nonce| Account {
+            nonce,
+            ..Default::default()
+        };
+
+        let mut state = HashMap::new();
+        apply_account_changes(
+            &mut state,
+            &[
+                (address1, Some(account(1))),
+                (address2, Some(account(1))),
+                (address3, None),
+            ]
+            .into_iter()
+            .collect(),
+        );
+        apply_account_changes(
+            &mut state,
+            &[(address1, None), (address2, Some(account(2)))]
+                .into_iter()
+                .collect(),
+        );
+
+        assert_eq!(state, [(address2, account(2))].into_iter().collect());
+    }
+
+    #[test]
+    fn revert_changes() {
+        let address1: Address = hex!("a000000000000000000000000000000000000000").into();
+        let address2: Address = hex!("b000000000000000000000000000000000000000").into();
+        let address3: Address = hex!("c000000000000000000000000000000000000000").into();
+        let account = |nonce| Account {
+            nonce,
+            ..Default::default()
+        };
+
+        let original: HashMap<_, _> = [(address1, account(1)), (address2, account(1))]
+            .into_iter()
+            .collect();
+        let mut state = original.clone();
+
+        let changes = apply_account_changes(
+            &mut state,
+            &[
+                (address1, None),
+                (address2, Some(account(2))),
+                (address3, Some(account(3))),
+            ]
+            .into_iter()
+            .collect(),
+        );
+        // Change set records what was there before, as when written by the buffer
+        assert_eq!(
+            changes,
+            [
+                (address1, Some(account(1))),
+                (address2, Some(account(1))),
+                (address3, None),
+            ]
+            .into_iter()
+            .collect()
+        );
+
+        revert_account_changes(&mut state, &changes);
+        assert_eq!(state, original);
+    }
+
+    #[test]
+    fn merge_changes() {
+        let address1: Address = hex!("a000000000000000000000000000000000000000").into();
+        let address2: Address = hex!("b000000000000000000000000000000000000000").into();
+        let account = |nonce| {
+            Some(Account {
+                nonce,
+                ..Default::default()
+            })
+        };
+
+        let changes: AccountChanges = [(address1, None), (address2, account(1))]
+            .into_iter()
+            .collect();
+
+        let mut merged = changes.clone();
+        merge_account_changes(
+            &mut merged,
+            [(address2, account(1))].into_iter().collect(),
+            MergeStrategy::RejectConflicts,
+        )
+        .unwrap();
+        assert_eq!(merged, changes);
+
+        let conflicting: AccountChanges = [(address1, account(2))].into_iter().collect();
+        assert!(merge_account_changes(
+            &mut merged,
+            conflicting.clone(),
+            MergeStrategy::RejectConflicts
+        )
+        .is_err());
+        assert_eq!(merged, changes);
+
+        merge_account_changes(&mut merged, conflicting, MergeStrategy::LastWriterWins).unwrap();
+        assert_eq!(
+            merged,
+            [(address1, account(2)), (address2, account(1))]
+                .into_iter()
+                .collect::<AccountChanges>()
+        );
+
+        let storage = |entries: &[(Address, u64, u64)]| -> StorageChanges {
+            let mut changes = StorageChanges::new();
+            for &(address, location, value) in entries {
+                changes
+                    .entry(address)
+                    .or_default()
+                    .insert(location.as_u256(), value.as_u256());
+            }
+            changes
+        };
+
+        let mut merged = storage(&[(address1, 1, 10), (address1, 2, 20)]);
+        merge_storage_changes(
+            &mut merged,
+            storage(&[(address1, 3, 30), (address2, 1, 40)]),
+            MergeStrategy::RejectConflicts,
+        )
+        .unwrap();
+        assert_eq!(
+            merged,
+            storage(&[
+                (address1, 1, 10),
+                (address1, 2, 20),
+                (address1, 3, 30),
+                (address2, 1, 40)
+            ])
+        );
+
+        let conflicting = storage(&[(address2, 2, 50), (address1, 2, 21)]);
+        let before = merged.clone();
+        assert!(merge_storage_changes(
+            &mut merged,
+            conflicting.clone(),
+            MergeStrategy::RejectConflicts
+        )
+        .is_err());
+        assert_eq!(merged, before);
+
+        merge_storage_changes(&mut merged, conflicting, MergeStrategy::LastWriterWins).unwrap();
+        assert_eq!(
+            merged,
+            storage(&[
+                (address1, 1, 10),
+                (address1, 2, 21),
+                (address1, 3, 30),
+                (address2, 1, 40),
+                (address2, 2, 50)
+            ])
+        );
+    }
+
+    #[test]
+    fn merge_consecutive_transaction_changes() {
+        let address1: Address = hex!("a000000000000000000000000000000000000000").into();
+        let address2: Address = hex!("b000000000000000000000000000000000000000").into();
+        let account = |nonce| {
+            Some(Account {
+                nonce,
+                ..Default::default()
+            })
+        };
+
+        // The first transaction bumps the nonce of address1 from 5, the second one bumps it
+        // again and creates address2
+        let mut block_changes: AccountChanges = [(address1, account(5))].into_iter().collect();
+        merge_account_changes(
+            &mut block_changes,
+            [(address1, account(6)), (address2, None)]
+                .into_iter()
+                .collect(),
+            MergeStrategy::FirstWriterWins,
+        )
+        .unwrap();
+        assert_eq!(
+            block_changes,
+            [(address1, account(5)), (address2, None)]
+                .into_iter()
+                .collect::<AccountChanges>()
+        );
+
+        // Both transactions write location 1 of address1, the second one location 2 too
+        let mut block_storage = StorageChanges::new();
+        block_storage
+            .entry(address1)
+            .or_default()
+            .insert(1.as_u256(), 10.as_u256());
+        let mut second = StorageChanges::new();
+        second
+            .entry(address1)
+            .or_default()
+            .extend([(1.as_u256(), 11.as_u256()), (2.as_u256(), 20.as_u256())]);
+        merge_storage_changes(&mut block_storage, second, MergeStrategy::FirstWriterWins).unwrap();
+        assert_eq!(
+            block_storage[&address1],
+            [(1.as_u256(), 10.as_u256()), (2.as_u256(), 20.as_u256())]
+                .into_iter()
+                .collect::<BTreeMap<_, _>>()
+        );
+    }
+
+    #[test]
+    fn repeated_storage_writes() {
+        let db = new_mem_chaindata().unwrap();
+        let txn = db.begin_mutable().unwrap();
+
+        let address = hex!("be00000000000000000000000000000000000000").into();
+        let location = 0x13.as_u256();
+        let other = 0x14.as_u256();
+
+        let mut buffer = Buffer::new(&txn, None);
+        buffer.begin_block(BlockNumber(1));
+        for (initial, current) in [(0x01, 0x02), (0x02, 0x03), (0x03, 0x04)] {
+            buffer
+                .update_storage(address, location, initial.as_u256(), current.as_u256())
+                .unwrap();
+        }
+        buffer
+            .update_storage(address, other, U256::ZERO, 0x05.as_u256())
+            .unwrap();
+        // Erasing keeps the value from before the block too
+        buffer.erase_storage(address).unwrap();
+        buffer.write_to_db().unwrap();
+
+        let changes = accessors::state::storage::walk_changes(&txn, BlockNumber(1), BlockNumber(1))
+            .collect::<anyhow::Result<Vec<_>>>()
+            .unwrap();
+        assert_eq!(
+            changes,
+            vec![
+                (
+                    BlockNumber(1),
+                    (address, u256_to_h256(location)),
+                    0x01.as_u256()
+                ),
+                (BlockNumber(1), (address, u256_to_h256(other)), U256::ZERO),
+            ]
+        );
+    }
+
     #[test]
     fn historical_block() {
         let address = hex!("deadbeef00000000000000000000000000000000").into();
@@ -131,7 +131,7 @@ impl InMemoryState {
         self.canonical_hashes.get(block_number.0 as usize).copied()
     }
 
-    pub fn insert_block(&mut self, block: Block, hash: H256) {
+    pub fn insert_block(&mut self, block: Block, hash: H256) -> anyhow::Result<()> {
         let Block {
             header,
             transactions,
@@ -139,8 +139,17 @@ impl InMemoryState {
         } = block;
 
         let block_number = header.number.0 as usize;
-        let parent_hash = header.parent_hash;
-        let difficulty = header.difficulty;
+        let parent_td = if block_number == 0 {
+            U256::ZERO
+        } else {
+            self.difficulty
+                .get(block_number - 1)
+                .and_then(|difficulty_map| difficulty_map.get(&header.parent_hash))
+                .copied()
+                .unwrap_or_default()
+        };
+        // Fail before touching any of the maps, so that a rejected block leaves no trace
+        let d = add_difficulty(parent_td, header.difficulty)?;
 
         if self.headers.len() <= block_number {
             self.headers.resize_with(block_number + 1, Default::default);
@@ -162,17 +171,9 @@ impl InMemoryState {
             self.difficulty
                 .resize_with(block_number + 1, Default::default);
         }
+        self.difficulty[block_number].insert(hash, d);
 
-        let d = {
-            if block_number == 0 {
-                U256::ZERO
-            } else {
-                *self.difficulty[block_number - 1]
-                    .entry(parent_hash)
-                    .or_default()
-            }
-        } + difficulty;
-        self.difficulty[block_number].entry(hash).insert_entry(d);
+        Ok(())
     }
 
     pub fn read_body_with_senders(
@@ -579,4 +580,49 @@ mod tests {
             assert_eq!(state.state_root_hash(), H256(state_root))
         }
     }
+
+    #[test]
+    fn overflowing_block_is_not_inserted() {
+        let mut state = InMemoryState::default();
+        let genesis = H256::repeat_byte(0x01);
+        state
+            .insert_block(
+                Block {
+                    header: BlockHeader {
+                        difficulty: U256::MAX,
+                        ..Default::default()
+                    },
+                    transactions: vec![],
+                    ommers: vec![],
+                },
+                genesis,
+            )
+            .unwrap();
+
+        let hash = H256::repeat_byte(0x02);
+        assert!(state
+            .insert_block(
+                Block {
+                    header: BlockHeader {
+                        parent_hash: genesis,
+                        difficulty: U256::ONE,
+                        number: BlockNumber(1),
+                        ..Default::default()
+                    },
+                    transactions: vec![],
+                    ommers: vec![],
+                },
+                hash,
+            )
+            .is_err());
+
+        assert_eq!(state.headers.len(), 1);
+        assert_eq!(state.bodies.len(), 1);
+        assert_eq!(state.difficulty.len(), 1);
+        assert_eq!(state.total_difficulty(BlockNumber(1), hash), None);
+        assert_eq!(
+            state.total_difficulty(BlockNumber(0), genesis),
+            Some(U256::MAX)
+        );
+    }
 }
@@ -1,3 +1,4 @@
+use anyhow::{bail, Context};
 use bytes::{Bytes, BytesMut};
 use ethereum_types::*;
 use ethnum::U256;
@@ -200,6 +201,33 @@ pub mod duration_as_millis {
     }
 }
 
+/// Parses a hex address, `0x` prefix optional, validating its EIP-55 checksum if it is
+/// mixed-case.
+pub fn parse_address_checked(s: &str) -> anyhow::Result<Address> {
+    let digits = s.strip_prefix("0x").unwrap_or(s);
+
+    let mut address = Address::zero();
+    hex::decode_to_slice(digits, address.as_bytes_mut())
+        .with_context(|| format!("invalid address {:?}", s))?;
+
+    let lowercase = digits.to_ascii_lowercase();
+    if digits != lowercase && digits != digits.to_ascii_uppercase() {
+        let hash = crate::crypto::keccak256(&lowercase);
+        for (i, c) in digits.chars().enumerate() {
+            let nibble = if i % 2 == 0 {
+                hash[i / 2] >> 4
+            } else {
+                hash[i / 2] & 0x0f
+            };
+            if c.is_ascii_alphabetic() && c.is_ascii_uppercase() != (nibble >= 8) {
+                bail!("invalid checksum for address {}", s);
+            }
+        }
+    }
+
+    Ok(address)
+}
+
 pub fn version_string() -> String {
     format!(
         "akula/v{}-{}-{}-{}/{}/rustc{}",
@@ -248,6 +276,29 @@ mod tests {
         assert_eq!(repeatedly_padded, bytes!("000000b8"));
     }
 
+    #[test]
+    fn checked_address_parsing() {
+        let address = Address::from(hex!("5aaeb6053f3e94c9b9a09f33669435e7ef1beaed"));
+
+        for valid in [
+            "0x5aAeb6053F3E94C9b9A09f33669435E7Ef1BeAed",
+            "0x5aaeb6053f3e94c9b9a09f33669435e7ef1beaed",
+            "5aaeb6053f3e94c9b9a09f33669435e7ef1beaed",
+            "0x5AAEB6053F3E94C9B9A09F33669435E7EF1BEAED",
+        ] {
+            assert_eq!(parse_address_checked(valid).unwrap(), address, "{}", valid);
+        }
+
+        for invalid in [
+            "0x5aaeb6053F3E94C9b9A09f33669435E7Ef1BeAed",
+            "0x5aAeb6053F3E94C9b9A09f33669435E7Ef1BeAe",
+            "0x5aAeb6053F3E94C9b9A09f33669435E7Ef1BeAedaa",
+            "0xzaAeb6053F3E94C9b9A09f33669435E7Ef1BeAed",
+        ] {
+            assert!(parse_address_checked(invalid).is_err(), "{}", invalid);
+        }
+    }
+
     #[test]
     fn zeroless_view_test() {
         assert_eq!(